@@ -0,0 +1,316 @@
+use payments_engine::SyncPolicy;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+
+// usage: app [--config <path>] [--profiles <path>] [--strict] [--rejects <path>] [--event-log <path>]
+//            [--load-snapshot <path>] [--save-snapshot <path>]
+//            [--wal <path> [--fsync always|never|<records>]] [--store <path>]
+//            [--spill <path>] [--workers <n>] <input.csv>
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub input: OsString,
+    // TOML file with the engine settings, defaults are used if not given
+    pub config: Option<OsString>,
+    // CSV file with the credit limits of clients, added to the config's
+    pub profiles: Option<OsString>,
+    // stop at the first rejected row instead of carrying on
+    pub strict: bool,
+    // where rejected rows are written, stderr if not given
+    pub rejects: Option<OsString>,
+    // accepted transactions are appended here, an existing log is replayed
+    // first so processing carries on from its balances
+    pub event_log: Option<OsString>,
+    // state to start from instead of an empty engine
+    pub load_snapshot: Option<OsString>,
+    // where the final state is written, may be the loaded snapshot itself
+    pub save_snapshot: Option<OsString>,
+    // rows are logged here before they are applied, a log left behind by a
+    // crashed run is replayed and its rows skipped
+    pub wal: Option<OsString>,
+    pub fsync: SyncPolicy,
+    // SQLite database keeping the clients, every transaction is committed to
+    // it as soon as it's applied and a later run carries on from it
+    pub store: Option<OsString>,
+    // scratch file for the transactions past the config's spill_after,
+    // removed at the end of the run
+    pub spill: Option<OsString>,
+    // apply rows on this many threads, each owning a share of the clients
+    pub workers: Option<usize>,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Args, Box<dyn Error>> {
+        Args::parse(env::args_os().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Args, Box<dyn Error>> {
+        let mut args = args.into_iter();
+        let mut input = None;
+        let mut config = None;
+        let mut profiles = None;
+        let mut strict = false;
+        let mut rejects = None;
+        let mut event_log = None;
+        let mut load_snapshot = None;
+        let mut save_snapshot = None;
+        let mut wal = None;
+        let mut fsync = SyncPolicy::default();
+        let mut store = None;
+        let mut spill = None;
+        let mut workers = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--config") => config = Some(value_of("--config", args.next())?),
+                Some("--profiles") => profiles = Some(value_of("--profiles", args.next())?),
+                Some("--strict") => strict = true,
+                Some("--rejects") => rejects = Some(value_of("--rejects", args.next())?),
+                Some("--event-log") => event_log = Some(value_of("--event-log", args.next())?),
+                Some("--load-snapshot") => {
+                    load_snapshot = Some(value_of("--load-snapshot", args.next())?)
+                }
+                Some("--save-snapshot") => {
+                    save_snapshot = Some(value_of("--save-snapshot", args.next())?)
+                }
+                Some("--wal") => wal = Some(value_of("--wal", args.next())?),
+                Some("--fsync") => {
+                    fsync = value_of("--fsync", args.next())?
+                        .to_string_lossy()
+                        .parse()?
+                }
+                Some("--store") => store = Some(value_of("--store", args.next())?),
+                Some("--spill") => spill = Some(value_of("--spill", args.next())?),
+                Some("--workers") => {
+                    let value = value_of("--workers", args.next())?;
+                    match value.to_string_lossy().parse() {
+                        Ok(count) if count > 0 => workers = Some(count),
+                        _ => {
+                            return Err(From::from(format!(
+                                "--workers expects a positive number, but got {:?}",
+                                value
+                            )))
+                        }
+                    }
+                }
+                _ if input.is_none() => input = Some(arg),
+                _ => return Err(From::from(format!("unexpected argument {:?}", arg))),
+            }
+        }
+
+        // a log started on top of a snapshot would lack the snapshot's
+        // transactions, and replaying an existing one would apply them twice
+        if load_snapshot.is_some() && event_log.is_some() {
+            return Err(From::from(
+                "--load-snapshot can't be combined with --event-log",
+            ));
+        }
+        // the event log would get the recovered transactions a second time
+        if wal.is_some() && event_log.is_some() {
+            return Err(From::from("--wal can't be combined with --event-log"));
+        }
+        // the database already is the durable state
+        if store.is_some()
+            && (load_snapshot.is_some()
+                || save_snapshot.is_some()
+                || event_log.is_some()
+                || wal.is_some())
+        {
+            return Err(From::from(
+                "--store can't be combined with snapshots, --event-log or --wal",
+            ));
+        }
+        if spill.is_some() && (store.is_some() || save_snapshot.is_some()) {
+            return Err(From::from(
+                "--spill can't be combined with --store or --save-snapshot",
+            ));
+        }
+
+        // the workers' state is only merged for the final balances
+        if workers.is_some()
+            && (load_snapshot.is_some()
+                || save_snapshot.is_some()
+                || event_log.is_some()
+                || wal.is_some()
+                || store.is_some()
+                || spill.is_some())
+        {
+            return Err(From::from(
+                "--workers can't be combined with snapshots, --event-log, --wal, --store or --spill",
+            ));
+        }
+
+        Ok(Args {
+            input: input.ok_or("expected an input file, but got none")?,
+            config,
+            profiles,
+            strict,
+            rejects,
+            event_log,
+            load_snapshot,
+            save_snapshot,
+            wal,
+            fsync,
+            store,
+            spill,
+            workers,
+        })
+    }
+}
+
+fn value_of(flag: &str, value: Option<OsString>) -> Result<OsString, Box<dyn Error>> {
+    value.ok_or_else(|| From::from(format!("{} expects a value", flag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Box<dyn Error>> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parse_input_only() {
+        let args = parse(&["input.csv"]).expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_flags() {
+        let args = parse(&[
+            "--strict",
+            "input.csv",
+            "--rejects",
+            "rejects.csv",
+            "--config",
+            "engine.toml",
+            "--profiles",
+            "profiles.csv",
+            "--event-log",
+            "events.ndjson",
+        ])
+        .expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                config: Some(OsString::from("engine.toml")),
+                profiles: Some(OsString::from("profiles.csv")),
+                strict: true,
+                rejects: Some(OsString::from("rejects.csv")),
+                event_log: Some(OsString::from("events.ndjson")),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_snapshots() {
+        let args = parse(&[
+            "--load-snapshot",
+            "state.bin",
+            "--save-snapshot",
+            "state.bin",
+            "input.csv",
+        ])
+        .expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                load_snapshot: Some(OsString::from("state.bin")),
+                save_snapshot: Some(OsString::from("state.bin")),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_wal() {
+        let args = parse(&[
+            "--wal",
+            "input.wal",
+            "--fsync",
+            "100",
+            "--spill",
+            "input.spill",
+            "input.csv",
+        ])
+        .expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                wal: Some(OsString::from("input.wal")),
+                fsync: SyncPolicy::Every(100),
+                spill: Some(OsString::from("input.spill")),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_store() {
+        let args = parse(&["input.csv", "--store", "clients.db"]).expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                store: Some(OsString::from("clients.db")),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_workers() {
+        let args = parse(&["--workers", "4", "input.csv"]).expect("args error");
+
+        assert_eq!(
+            args,
+            Args {
+                input: OsString::from("input.csv"),
+                workers: Some(4),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reject_bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["input.csv", "--rejects"]).is_err());
+        assert!(parse(&["input.csv", "--event-log"]).is_err());
+        assert!(parse(&["input.csv", "--save-snapshot"]).is_err());
+        assert!(parse(&["input.csv", "--wal", "wal", "--fsync", "sometimes"]).is_err());
+        assert!(parse(&["input.csv", "--wal", "wal", "--event-log", "events"]).is_err());
+        assert!(parse(&[
+            "input.csv",
+            "--load-snapshot",
+            "state",
+            "--event-log",
+            "events"
+        ])
+        .is_err());
+        assert!(parse(&["input.csv", "--store", "db", "--wal", "wal"]).is_err());
+        assert!(parse(&["input.csv", "--store", "db", "--load-snapshot", "state"]).is_err());
+        assert!(parse(&["input.csv", "--spill", "spill", "--store", "db"]).is_err());
+        assert!(parse(&["input.csv", "--workers", "0"]).is_err());
+        assert!(parse(&["input.csv", "--workers", "many"]).is_err());
+        assert!(parse(&["input.csv", "--workers", "2", "--wal", "wal"]).is_err());
+        assert!(parse(&["--config", "input.csv"]).is_err());
+        assert!(parse(&["input.csv", "other.csv"]).is_err());
+    }
+}
@@ -2,22 +2,45 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
+mod args;
+mod shard;
+
+use args::Args;
 use payments_engine::*;
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
+use shard::Sharded;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::error::Error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
 use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
 
 #[derive(Debug, Deserialize)]
 struct InputRecord {
     r#type: TransactionType,
     client: ClientId,
     tx: TransactionId,
-    amount: Amount,
+    #[serde(default, deserialize_with = "exact_amount")]
+    amount: Option<Amount>,
+    // optional column, rows without it use the configured default currency
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+// reads the amount column from its text, see Amount::deserialize_text
+fn exact_amount<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    #[derive(Deserialize)]
+    struct Text(#[serde(deserialize_with = "Amount::deserialize_text")] Amount);
+
+    Ok(Option::<Text>::deserialize(deserializer)?.map(|Text(amount)| amount))
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,82 +51,488 @@ enum TransactionType {
     Withdrawal,
     #[serde(rename(deserialize = "dispute"))]
     Dispute,
+    #[serde(rename(deserialize = "resolve"))]
+    Resolve,
+    #[serde(rename(deserialize = "chargeback"))]
+    Chargeback,
+    #[serde(rename(deserialize = "authorize"))]
+    Authorize,
+    #[serde(rename(deserialize = "capture"))]
+    Capture,
+    #[serde(rename(deserialize = "void"))]
+    Void,
+    #[serde(rename(deserialize = "credit_limit"))]
+    CreditLimit,
+}
+
+// a row that was not applied, with its raw fields as read from the input
+#[derive(Debug, Serialize)]
+struct RejectRecord<'a> {
+    line: u64,
+    r#type: &'a str,
+    client: &'a str,
+    tx: &'a str,
+    amount: &'a str,
+    currency: &'a str,
+    reason: String,
+}
+
+// owner of an output row, the house account holds the fees collected
+#[derive(Debug)]
+enum Holder {
+    Client(ClientId),
+    House,
+}
+
+impl Serialize for Holder {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Holder::Client(client_id) => client_id.serialize(serializer),
+            Holder::House => serializer.serialize_str("house"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct OutputRecord {
-    client: ClientId,
+    client: Holder,
+    currency: Currency,
     available: Amount,
     held: Amount,
+    reserved: Amount,
+    // owed by the client for disputes held on credit
+    receivable: Amount,
     total: Amount,
+    credit_limit: Amount,
+    // available plus the credit limit
+    headroom: Amount,
+    // what rounding deposits and withdrawals cost the client
+    rounding: Amount,
     locked: bool,
 }
 
-fn process_csv(engine: &mut PaymentsEngine, csv_path: OsString) -> Result<(), Box<dyn Error>> {
-    let mut rdr = csv::ReaderBuilder::new()
-        //.has_headers(false)
-        .trim(csv::Trim::All)
-        .from_path(csv_path)?;
-
-    for result in rdr.deserialize() {
-        let record: InputRecord = result?;
-
-        let transaction = match record.r#type {
-            TransactionType::Deposit => {
-                let deposit = Deposit {
-                    transaction_id: record.tx,
-                    client_id: record.client,
-                    amount: record.amount,
-                    dispute_status: DisputeStatus::NotDisputed,
-                };
-                Transaction::Deposit(deposit)
+impl InputRecord {
+    // deposits, withdrawals, authorizations and credit limits must carry an
+    // amount, for disputes, resolves, chargebacks and captures it is optional
+    // and limits them to part of the target
+    fn into_transaction(self, default_currency: Currency) -> Result<Transaction, Box<dyn Error>> {
+        let currency = self.currency.unwrap_or(default_currency);
+        let transaction = match (self.r#type, self.amount) {
+            (TransactionType::Deposit, Some(amount)) => {
+                Transaction::Deposit(Deposit::new(self.tx, self.client, amount, currency))
             }
 
-            TransactionType::Withdrawal => {
-                let withdraw = Withdraw {
-                    transaction_id: record.tx,
-                    client_id: record.client,
-                    amount: record.amount,
-                };
-                Transaction::Withdraw(withdraw)
+            (TransactionType::Withdrawal, Some(amount)) => {
+                Transaction::Withdraw(Withdraw::new(self.tx, self.client, amount, currency))
             }
 
-            TransactionType::Dispute => {
-                let dispute = Dispute {
-                    client_id: record.client,
-                    target_transaction_id: record.tx,
+            (TransactionType::Dispute, amount) => Transaction::Dispute(Dispute {
+                client_id: self.client,
+                target_transaction_id: self.tx,
+                amount,
+                currency: self.currency,
+            }),
+
+            (TransactionType::Resolve, amount) => Transaction::Resolve(Resolve {
+                client_id: self.client,
+                target_transaction_id: self.tx,
+                amount,
+                currency: self.currency,
+            }),
+
+            (TransactionType::Chargeback, amount) => Transaction::Chargeback(Chargeback {
+                client_id: self.client,
+                target_transaction_id: self.tx,
+                amount,
+                currency: self.currency,
+            }),
+
+            (TransactionType::Authorize, Some(amount)) => {
+                Transaction::Authorize(Authorize::new(self.tx, self.client, amount, currency))
+            }
+
+            (TransactionType::Capture, amount) => Transaction::Capture(Capture {
+                client_id: self.client,
+                target_transaction_id: self.tx,
+                amount,
+                currency: self.currency,
+            }),
+
+            (TransactionType::CreditLimit, Some(credit_limit)) => {
+                Transaction::CreditLimit(CreditLimit {
+                    transaction_id: self.tx,
+                    client_id: self.client,
+                    credit_limit,
+                    currency,
+                })
+            }
+
+            (TransactionType::Void, _) => Transaction::Void(Void {
+                client_id: self.client,
+                target_transaction_id: self.tx,
+            }),
+
+            (
+                r#type @ (TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Authorize
+                | TransactionType::CreditLimit),
+                None,
+            ) => {
+                return Err(From::from(format!(
+                    "{:?} transaction {} is missing an amount",
+                    r#type, self.tx.0
+                )))
+            }
+        };
+
+        Ok(transaction)
+    }
+}
+
+fn csv_reader<R: io::Read>(input: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        //.has_headers(false)
+        .trim(csv::Trim::All)
+        // dispute, resolve and chargeback rows may omit the amount column
+        .flexible(true)
+        .from_reader(input)
+}
+
+fn parse_record(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    default_currency: Currency,
+) -> Result<Transaction, Box<dyn Error>> {
+    record
+        .deserialize::<InputRecord>(Some(headers))
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|record| record.into_transaction(default_currency))
+}
+
+// takes the fields by their header like parse_record, the input may order
+// its columns any way
+fn write_reject<W: io::Write>(
+    rejects: &mut csv::Writer<W>,
+    headers: &csv::StringRecord,
+    line: u64,
+    record: &csv::StringRecord,
+    reason: String,
+) -> Result<(), csv::Error> {
+    let field = |name| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .and_then(|column| record.get(column))
+            .unwrap_or_default()
+    };
+    rejects.serialize(RejectRecord {
+        line,
+        r#type: field("type"),
+        client: field("client"),
+        tx: field("tx"),
+        amount: field("amount"),
+        currency: field("currency"),
+        reason,
+    })
+}
+
+// Returns the number of rejected rows, in strict mode the first rejected row
+// aborts processing with an error instead. Accepted transactions go to events,
+// every transaction goes to the write-ahead log before being applied and the
+// rejects are flushed whenever the log is synced. Rows up to resume_after were
+// handled by a previous run and are skipped.
+fn process_csv<S: ClientStore, R: io::Read, W: io::Write, L: io::Write>(
+    engine: &mut PaymentsEngine<S>,
+    input: R,
+    strict: bool,
+    rejects: &mut csv::Writer<W>,
+    events: &mut EventLog<L>,
+    mut wal: Option<&mut WriteAheadLog>,
+    resume_after: u64,
+) -> Result<u64, Box<dyn Error>> {
+    let mut rdr = csv_reader(input);
+    let headers = rdr.headers()?.clone();
+    let default_currency = engine.config().default_currency;
+    let mut rejected = 0;
+
+    for result in rdr.records() {
+        let (line, record, outcome) = match result {
+            Ok(record) => {
+                let line = record.position().map_or(0, |position| position.line());
+                if line <= resume_after {
+                    continue;
+                }
+                let outcome = match parse_record(&record, &headers, default_currency) {
+                    Ok(transaction) => {
+                        if let Some(wal) = &mut wal {
+                            wal.append(line, &transaction)?;
+                        }
+                        match engine.recv_tx(transaction.clone()) {
+                            Ok(()) => Ok(transaction),
+                            // the store fell behind the engine, nothing after
+                            // this row can be applied
+                            Err(err @ payments_engine::Error::Storage(_)) => {
+                                return Err(From::from(format!("line {}: {}", line, err)));
+                            }
+                            Err(err) => {
+                                // the row stops a strict run, a recovery has
+                                // to stop at it again rather than skip it
+                                if let (true, Some(wal)) = (strict, &mut wal) {
+                                    wal.retract()?;
+                                }
+                                Err(Box::from(err))
+                            }
+                        }
+                    }
+                    Err(err) => Err(err),
                 };
-                Transaction::Dispute(dispute)
+                (line, record, outcome)
+            }
+            Err(err) => {
+                let line = err.position().map_or(0, |position| position.line());
+                if line <= resume_after {
+                    continue;
+                }
+                (line, csv::StringRecord::new(), Err(Box::from(err)))
             }
         };
 
-        engine.recv_tx(transaction)?;
+        match outcome {
+            // failing to log an applied transaction isn't a rejected row
+            Ok(transaction) => events.append(&transaction)?,
+            // what was accepted so far is logged all the same
+            Err(err) if strict => {
+                events.flush()?;
+                rejects.flush()?;
+                return Err(From::from(format!("line {}: {}", line, err)));
+            }
+            Err(err) => {
+                rejected += 1;
+                write_reject(rejects, &headers, line, &record, err.to_string())?;
+            }
+        }
+        // a recovery skips every row the log has, their rejects have to be
+        // written out by then
+        if let Some(wal) = wal.as_mut().filter(|wal| wal.sync_due()) {
+            rejects.flush()?;
+            wal.sync()?;
+        }
+    }
+
+    events.flush()?;
+    rejects.flush()?;
+    Ok(rejected)
+}
+
+fn load_config(args: &Args) -> Result<EngineConfig, Box<dyn Error>> {
+    let mut config: EngineConfig = match &args.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        None => EngineConfig::default(),
+    };
+    if let Some(path) = &args.profiles {
+        config
+            .credit_lines
+            .extend(read_profiles(File::open(path)?)?);
+    }
+    Ok(config)
+}
+
+// client profiles are CSV rows of client, currency and credit_limit, the
+// currency may be left empty for the default one
+fn read_profiles<R: io::Read>(input: R) -> Result<Vec<CreditLine>, Box<dyn Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let mut credit_lines = vec![];
+    for record in rdr.deserialize() {
+        credit_lines.push(record?);
     }
+    Ok(credit_lines)
+}
+
+type EventSink = EventLog<Box<dyn io::Write>>;
+
+// Builds the engine from a snapshot or by replaying an existing event log, and
+// opens the event log for appending. Args makes sure both aren't given.
+fn open_engine(
+    args: &Args,
+    config: EngineConfig,
+) -> Result<(PaymentsEngine, EventSink), Box<dyn Error>> {
+    let existing_log = args
+        .event_log
+        .as_ref()
+        .filter(|path| Path::new(path).exists());
+
+    let engine = match (&args.load_snapshot, existing_log) {
+        (Some(path), _) => {
+            PaymentsEngine::load_snapshot(config, BufReader::new(File::open(path)?))?
+        }
+        (None, Some(path)) => PaymentsEngine::replay(config, BufReader::new(File::open(path)?))?,
+        (None, None) => PaymentsEngine::with_config(config)?,
+    };
+
+    let events = match (&args.event_log, existing_log) {
+        (Some(_), Some(path)) => {
+            let file = OpenOptions::new().append(true).open(path)?;
+            EventLog::resume(Box::new(BufWriter::new(file)) as Box<dyn io::Write>)
+        }
+        (Some(path), None) => EventLog::create(
+            Box::new(BufWriter::new(File::create(path)?)) as _,
+            engine.config(),
+        )?,
+        (None, _) => EventLog::resume(Box::new(io::sink()) as _),
+    };
+
+    Ok((engine, events))
+}
 
+// Recovers from the write-ahead log a crashed run left behind, or starts a new
+// one. Returns the input line the recovered run got to.
+fn open_wal(
+    args: &Args,
+    engine: &mut PaymentsEngine,
+) -> Result<Option<(WriteAheadLog, u64)>, Box<dyn Error>> {
+    let path = match &args.wal {
+        Some(path) => Path::new(path),
+        None => return Ok(None),
+    };
+    let input = InputFile::read(Path::new(&args.input))?;
+    if !path.exists() {
+        let wal = WriteAheadLog::create(path, engine, &input, args.fsync)?;
+        return Ok(Some((wal, 0)));
+    }
+
+    let (wal, recovery) = WriteAheadLog::recover(path, engine, args.fsync)?;
+    if recovery.input.path != input.path {
+        return Err(From::from(format!(
+            "write-ahead log {:?} belongs to input {:?}",
+            path, recovery.input.path
+        )));
+    }
+    if recovery.input != input {
+        return Err(From::from(format!(
+            "input {:?} changed since write-ahead log {:?} was started",
+            input.path, path
+        )));
+    }
+    let last_line = recovery.last_line.unwrap_or(0);
+    eprintln!(
+        "recovered {} transactions from {:?}, resuming after input line {}",
+        recovery.replayed, path, last_line
+    );
+    Ok(Some((wal, last_line)))
+}
+
+// writes next to the target and renames, so a crash never leaves a
+// truncated snapshot behind
+fn save_snapshot(engine: &PaymentsEngine, path: &OsStr) -> Result<(), Box<dyn Error>> {
+    let mut partial = path.to_os_string();
+    partial.push(".partial");
+    engine.save_snapshot(BufWriter::new(File::create(&partial)?))?;
+    fs::rename(&partial, path)?;
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut engine = PaymentsEngine {
-        client_list: HashMap::new(),
+// A resumed run appends to the rows the crashed one rejected, without
+// repeating the header.
+fn open_rejects(
+    args: &Args,
+    resuming: bool,
+) -> Result<csv::Writer<Box<dyn io::Write>>, Box<dyn Error>> {
+    let (rejects, has_rows): (Box<dyn io::Write>, bool) = match &args.rejects {
+        Some(path) if resuming => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let has_rows = file.metadata()?.len() > 0;
+            (Box::new(file), has_rows)
+        }
+        Some(path) => (Box::new(File::create(path)?), false),
+        None => (Box::new(io::stderr()), false),
     };
+    Ok(csv::WriterBuilder::new()
+        .has_headers(!has_rows)
+        .from_writer(rejects))
+}
 
-    let csv_path = get_first_arg()?;
+// Runs the input through the engine, with rejected rows going to the rejects
+// file or stderr, and checks the result against the ledger.
+fn process_input<S: ClientStore>(
+    args: &Args,
+    engine: &mut PaymentsEngine<S>,
+    events: &mut EventSink,
+    wal: Option<&mut WriteAheadLog>,
+    resume_after: u64,
+) -> Result<(), Box<dyn Error>> {
+    let mut rejects = open_rejects(args, resume_after > 0)?;
+    let rejected = process_csv(
+        engine,
+        File::open(&args.input)?,
+        args.strict,
+        &mut rejects,
+        events,
+        wal,
+        resume_after,
+    )?;
+    if rejected > 0 {
+        eprintln!("{} rows rejected", rejected);
+    }
 
-    let mut wtr = csv::WriterBuilder::new().from_writer(io::stdout());
+    // refuse to report balances the ledger can't account for
+    engine.load_clients()?;
+    engine.reconcile()?;
+    Ok(())
+}
+
+// Writes the clients of all the engines by id, then what the house collected
+// across them. The engines must not share clients.
+fn write_balances<S: ClientStore, W: io::Write>(
+    engines: &[PaymentsEngine<S>],
+    output: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::WriterBuilder::new().from_writer(output);
 
-    match process_csv(&mut engine, csv_path) {
-        Err(err) => eprintln!("{:?}", err),
-        Ok(()) => (),
+    let mut clients: Vec<_> = engines
+        .iter()
+        .flat_map(|engine| engine.clients().map(move |client| (engine, client)))
+        .collect();
+    clients.sort_by_key(|(_, client)| client.client_id);
+    let mut house = BTreeMap::new();
+    for (currency, collected) in engines.iter().flat_map(|engine| engine.house()) {
+        let total = house.entry(*currency).or_insert(Amount::ZERO);
+        *total = total.checked_add(*collected)?;
     }
 
-    for (id, client) in engine.client_list.iter() {
+    for (engine, client) in clients {
+        for (currency, balance) in client.balances.iter() {
+            let credit_limit = engine.credit_limit(client.client_id, *currency);
+            wtr.serialize(OutputRecord {
+                client: Holder::Client(client.client_id),
+                currency: *currency,
+                available: balance.available,
+                held: balance.held,
+                reserved: balance.reserved,
+                receivable: balance.receivable,
+                total: balance.total()?,
+                credit_limit,
+                headroom: balance.headroom(credit_limit)?,
+                rounding: client.rounding_residue(*currency),
+                locked: client.locked,
+            })?;
+        }
+    }
+    for (currency, collected) in house {
         wtr.serialize(OutputRecord {
-            client: *id,
-            available: client.available,
-            held: client.held,
-            total: client.available.checked_add(client.held),
-            locked: client.locked,
+            client: Holder::House,
+            currency,
+            available: collected,
+            held: Amount::ZERO,
+            reserved: Amount::ZERO,
+            receivable: Amount::ZERO,
+            total: collected,
+            credit_limit: Amount::ZERO,
+            headroom: collected,
+            rounding: Amount::ZERO,
+            locked: false,
         })?;
     }
 
@@ -111,9 +540,593 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(1) {
-        None => Err(From::from("expected 1 argument, but got none")),
-        Some(file_path) => Ok(file_path),
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_env()?;
+    let config = load_config(&args)?;
+
+    if let Some(workers) = args.workers {
+        let mut rejects = open_rejects(&args, false)?;
+        let input = File::open(&args.input)?;
+        match shard::process_csv(&config, input, workers, args.strict, &mut rejects)? {
+            Sharded::Done { engines, rejected } => {
+                if rejected > 0 {
+                    eprintln!("{} rows rejected", rejected);
+                }
+                for engine in &engines {
+                    engine.reconcile()?;
+                }
+                return write_balances(&engines, io::stdout());
+            }
+            Sharded::Unshardable(reason) => {
+                eprintln!("can't split the input by client, {}", reason);
+                eprintln!("processing it on a single thread instead");
+            }
+        }
+    }
+
+    if let Some(path) = &args.store {
+        let store = SqliteStore::open(Path::new(path))?;
+        let mut engine = PaymentsEngine::with_store(config, store)?;
+        let mut events = EventLog::resume(Box::new(io::sink()) as Box<dyn io::Write>);
+        process_input(&args, &mut engine, &mut events, None, 0)?;
+        return write_balances(&[engine], io::stdout());
+    }
+
+    let (mut engine, mut events) = open_engine(&args, config)?;
+    if let Some(path) = &args.spill {
+        engine.spill_history(SpillFile::create(Path::new(path))?);
+    }
+    let (mut wal, resume_after) = match open_wal(&args, &mut engine)? {
+        Some((wal, resume_after)) => (Some(wal), resume_after),
+        None => (None, 0),
+    };
+
+    let processed = process_input(&args, &mut engine, &mut events, wal.as_mut(), resume_after);
+
+    // saved even when processing stopped early, the event log already has
+    // the transactions that were applied
+    if let Some(path) = &args.save_snapshot {
+        save_snapshot(&engine, path)?;
+    }
+    processed?;
+    // the batch is done and its state saved, nothing is left to recover
+    if let (Some(wal), Some(path)) = (wal, &args.wal) {
+        wal.remove(Path::new(path))?;
+    }
+    if let Some(path) = &args.spill {
+        fs::remove_file(path)?;
+    }
+
+    write_balances(&[engine], io::stdout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn parse(csv: &str) -> Vec<Result<Transaction, Box<dyn Error>>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(csv.as_bytes());
+        rdr.deserialize::<InputRecord>()
+            .map(|record| {
+                record
+                    .expect("malformed record")
+                    .into_transaction(Currency::XXX)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_all_transaction_types() {
+        let transactions = parse(
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            withdrawal, 1, 2, 0.5
+            dispute, 1, 1
+            resolve, 1, 1,
+            chargeback, 1, 1",
+        );
+
+        assert!(matches!(transactions[0], Ok(Transaction::Deposit(_))));
+        assert!(matches!(transactions[1], Ok(Transaction::Withdraw(_))));
+        assert!(matches!(transactions[2], Ok(Transaction::Dispute(_))));
+        assert!(matches!(transactions[3], Ok(Transaction::Resolve(_))));
+        assert!(matches!(transactions[4], Ok(Transaction::Chargeback(_))));
+    }
+
+    #[test]
+    fn reject_missing_amount() {
+        let transactions = parse(
+            "type, client, tx, amount
+            deposit, 1, 1
+            withdrawal, 1, 2,",
+        );
+
+        assert!(transactions.iter().all(|transaction| transaction.is_err()));
+    }
+
+    #[test]
+    fn parse_partial_dispute() {
+        let transactions = parse(
+            "type, client, tx, amount
+            dispute, 1, 1, 1.0
+            resolve, 1, 1, 0.5
+            chargeback, 1, 1",
+        );
+
+        assert!(matches!(
+            transactions[0],
+            Ok(Transaction::Dispute(Dispute {
+                amount: Some(_),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[1],
+            Ok(Transaction::Resolve(Resolve {
+                amount: Some(_),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[2],
+            Ok(Transaction::Chargeback(Chargeback { amount: None, .. }))
+        ));
+    }
+
+    #[test]
+    fn parse_currency_column() {
+        let transactions = parse(
+            "type, client, tx, amount, currency
+            deposit, 1, 1, 1.0, EUR
+            withdrawal, 1, 2, 0.5
+            dispute, 1, 1, , usd",
+        );
+
+        assert!(matches!(
+            transactions[0],
+            Ok(Transaction::Deposit(Deposit {
+                currency: Currency::EUR,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[1],
+            Ok(Transaction::Withdraw(Withdraw {
+                currency: Currency::XXX,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[2],
+            Ok(Transaction::Dispute(Dispute {
+                amount: None,
+                currency: Some(Currency::USD),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn parse_amounts_exactly() {
+        let transactions = parse(
+            "type, client, tx, amount
+            deposit, 1, 1, 0.1234567890123456789",
+        );
+
+        assert!(matches!(
+            &transactions[0],
+            Ok(Transaction::Deposit(deposit))
+                if deposit.amount == Amount("0.1234567890123456789".parse().expect("decimal error"))
+        ));
+    }
+
+    #[test]
+    fn parse_authorizations() {
+        let transactions = parse(
+            "type, client, tx, amount
+            authorize, 1, 1, 2.5
+            capture, 1, 1, 1.0
+            capture, 1, 1,
+            void, 1, 1,
+            authorize, 1, 2,",
+        );
+
+        assert!(matches!(
+            transactions[0],
+            Ok(Transaction::Authorize(Authorize {
+                transaction_id: TransactionId(1),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[1],
+            Ok(Transaction::Capture(Capture {
+                amount: Some(_),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            transactions[2],
+            Ok(Transaction::Capture(Capture { amount: None, .. }))
+        ));
+        assert!(matches!(transactions[3], Ok(Transaction::Void(_))));
+        assert!(transactions[4].is_err());
+    }
+
+    #[test]
+    fn parse_profiles() {
+        let credit_lines = read_profiles(
+            "client, currency, credit_limit
+            1, EUR, 500
+            2, , 100.5"
+                .as_bytes(),
+        )
+        .expect("profiles error");
+
+        assert_eq!(
+            credit_lines,
+            vec![
+                CreditLine {
+                    client: ClientId(1),
+                    currency: Some(Currency::EUR),
+                    credit_limit: Amount(500.into()),
+                },
+                CreditLine {
+                    client: ClientId(2),
+                    currency: None,
+                    credit_limit: Amount("100.5".parse().expect("decimal error")),
+                },
+            ]
+        );
+    }
+
+    fn process(csv: &str, strict: bool) -> (Result<u64, String>, String) {
+        let mut engine = PaymentsEngine::new();
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let mut events = EventLog::resume(io::sink());
+        let result = process_csv(
+            &mut engine,
+            csv.as_bytes(),
+            strict,
+            &mut rejects,
+            &mut events,
+            None,
+            0,
+        )
+        .map_err(|err| err.to_string());
+        let rejects = String::from_utf8(rejects.into_inner().expect("flush error"))
+            .expect("rejects aren't utf-8");
+        (result, rejects)
+    }
+
+    const INPUT_WITH_BAD_ROWS: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+refund, 1, 3, 1.0
+deposit, 1, 4
+deposit, 1, 5, 2.0
+";
+
+    #[test]
+    fn lenient_mode_writes_rejects() {
+        let (result, rejects) = process(INPUT_WITH_BAD_ROWS, false);
+
+        assert_eq!(result, Ok(3));
+
+        let rows: Vec<&str> = rejects.lines().collect();
+        assert_eq!(rows[0], "line,type,client,tx,amount,currency,reason");
+        assert_eq!(
+            rows[1],
+            "3,withdrawal,1,2,5.0,,withdraw amount is bigger than available amount"
+        );
+        assert!(rows[2].starts_with("4,refund,1,3,1.0,"));
+        assert_eq!(
+            rows[3],
+            "5,deposit,1,4,,,Deposit transaction 4 is missing an amount"
+        );
+        assert_eq!(rows.len(), 4);
+    }
+
+    #[test]
+    fn resume_skips_handled_rows() {
+        let mut engine = PaymentsEngine::new();
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let mut events = EventLog::resume(vec![]);
+
+        let rejected = process_csv(
+            &mut engine,
+            INPUT_WITH_BAD_ROWS.as_bytes(),
+            false,
+            &mut rejects,
+            &mut events,
+            None,
+            3,
+        )
+        .expect("process error");
+
+        assert_eq!(rejected, 2);
+        let client = engine.client(ClientId(1)).expect("missing client");
+        assert_eq!(client.balance(Currency::XXX).available, Amount(2.into()));
+        assert_eq!(
+            events.into_inner().iter().filter(|&&b| b == b'\n').count(),
+            1
+        );
+    }
+
+    // balances and rejects of the input run sequentially, then split over
+    // workers
+    fn process_both(csv: &str, workers: usize) -> [(String, String); 2] {
+        let mut engine = PaymentsEngine::new();
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let mut events = EventLog::resume(io::sink());
+        process_csv(
+            &mut engine,
+            csv.as_bytes(),
+            false,
+            &mut rejects,
+            &mut events,
+            None,
+            0,
+        )
+        .expect("process error");
+        let mut balances = vec![];
+        write_balances(&[engine], &mut balances).expect("write error");
+        let sequential = (
+            String::from_utf8(balances).expect("balances aren't utf-8"),
+            String::from_utf8(rejects.into_inner().expect("flush error"))
+                .expect("rejects aren't utf-8"),
+        );
+
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let engines = match shard::process_csv(
+            &EngineConfig::default(),
+            csv.as_bytes(),
+            workers,
+            false,
+            &mut rejects,
+        )
+        .expect("process error")
+        {
+            Sharded::Done { engines, .. } => engines,
+            Sharded::Unshardable(reason) => panic!("unshardable input: {}", reason),
+        };
+        let mut balances = vec![];
+        write_balances(&engines, &mut balances).expect("write error");
+        let sharded = (
+            String::from_utf8(balances).expect("balances aren't utf-8"),
+            String::from_utf8(rejects.into_inner().expect("flush error"))
+                .expect("rejects aren't utf-8"),
+        );
+
+        [sequential, sharded]
+    }
+
+    #[test]
+    fn sharded_run_matches_sequential() {
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0, EUR
+deposit, 3, 3, 7.5
+withdrawal, 2, 4, 9.0, EUR
+dispute, 1, 1
+deposit, 4, 5, 1.0
+withdrawal, 1, 6, 1.0
+chargeback, 1, 1
+refund, 3, 7, 1.0
+deposit, 3, 8
+dispute, 3, 3, 2.5
+resolve, 3, 3
+withdrawal, 3, 9, 2.0
+credit_limit, 4, 10, 3.0
+withdrawal, 4, 11, 3.5
+deposit, 2, 2, 5.0, EUR
+";
+        let [sequential, sharded] = process_both(input, 3);
+
+        assert_eq!(sharded, sequential);
+        // a header then four clients, a header then five rejects
+        assert_eq!(sequential.0.lines().count(), 5);
+        assert_eq!(sequential.1.lines().count(), 6);
+    }
+
+    #[test]
+    fn rejects_keep_their_columns_in_any_order() {
+        let input = "currency, amount, tx, client, type
+EUR, 10.0, 1, 1, deposit
+EUR, 15.0, 2, 1, withdrawal
+";
+        let [sequential, sharded] = process_both(input, 2);
+
+        assert_eq!(sharded, sequential);
+        let rows: Vec<&str> = sequential.1.lines().collect();
+        assert_eq!(
+            rows,
+            [
+                "line,type,client,tx,amount,currency,reason",
+                "3,withdrawal,1,2,15.0,EUR,withdraw amount is bigger than available amount",
+            ]
+        );
+    }
+
+    #[test]
+    fn sharded_run_gives_up_on_shared_ids() {
+        let shard = |csv: &str| {
+            let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+            let sharded = shard::process_csv(
+                &EngineConfig::default(),
+                csv.as_bytes(),
+                2,
+                false,
+                &mut rejects,
+            )
+            .expect("process error");
+            matches!(sharded, Sharded::Unshardable(_))
+        };
+
+        // clients 1 and 3 share a worker, 2 doesn't
+        assert!(!shard(
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 3, 1, 1.0
+            dispute, 3, 1"
+        ));
+        assert!(shard(
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 1, 1.0"
+        ));
+        assert!(shard(
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            dispute, 2, 1"
+        ));
+        assert!(shard(
+            "type, client, tx, amount
+            authorize, 1, 1, 1.0"
+        ));
+    }
+
+    #[test]
+    fn sharded_strict_mode_reports_first_reject() {
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let result = shard::process_csv(
+            &EngineConfig::default(),
+            INPUT_WITH_BAD_ROWS.as_bytes(),
+            2,
+            true,
+            &mut rejects,
+        );
+
+        assert_eq!(
+            result.map(|_| ()).map_err(|err| err.to_string()),
+            Err(String::from(
+                "line 3: withdraw amount is bigger than available amount"
+            ))
+        );
+    }
+
+    #[test]
+    fn strict_stop_is_left_out_of_the_wal() {
+        let path = env::temp_dir().join(format!("app_{}_strict.wal", std::process::id()));
+        let input = InputFile {
+            path: String::from("input.csv"),
+            len: INPUT_WITH_BAD_ROWS.len() as u64,
+            hash: 0,
+        };
+        let mut engine = PaymentsEngine::new();
+        let mut wal =
+            WriteAheadLog::create(&path, &engine, &input, SyncPolicy::Always).expect("wal error");
+        let mut rejects = csv::WriterBuilder::new().from_writer(vec![]);
+        let mut events = EventLog::resume(io::sink());
+
+        let result = process_csv(
+            &mut engine,
+            INPUT_WITH_BAD_ROWS.as_bytes(),
+            true,
+            &mut rejects,
+            &mut events,
+            Some(&mut wal),
+            0,
+        );
+        assert!(result.is_err());
+
+        // a recovery stops at the rejected row again
+        let (_, recovery) =
+            WriteAheadLog::recover(&path, &mut PaymentsEngine::new(), SyncPolicy::Always)
+                .expect("wal error");
+        assert_eq!(recovery.last_line, Some(2));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    // hands out the input a row at a time, noting how much of the rejects was
+    // written out before each row
+    struct RowByRow {
+        rows: Vec<String>,
+        rejects: Rc<RefCell<Vec<u8>>>,
+        written: Vec<usize>,
+    }
+
+    impl io::Read for RowByRow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.rows.is_empty() {
+                return Ok(0);
+            }
+            self.written.push(self.rejects.borrow().len());
+            let row = self.rows.remove(0);
+            buf[..row.len()].copy_from_slice(row.as_bytes());
+            Ok(row.len())
+        }
+    }
+
+    struct SharedRejects(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedRejects {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejects_flushed_with_the_wal() {
+        let path = env::temp_dir().join(format!("app_{}_rejects.wal", std::process::id()));
+        let input = InputFile {
+            path: String::from("input.csv"),
+            len: INPUT_WITH_BAD_ROWS.len() as u64,
+            hash: 0,
+        };
+        let mut engine = PaymentsEngine::new();
+        let mut wal =
+            WriteAheadLog::create(&path, &engine, &input, SyncPolicy::Always).expect("wal error");
+        let shared = Rc::new(RefCell::new(vec![]));
+        let mut rejects = csv::WriterBuilder::new().from_writer(SharedRejects(Rc::clone(&shared)));
+        let mut events = EventLog::resume(io::sink());
+        let mut rows = RowByRow {
+            rows: INPUT_WITH_BAD_ROWS
+                .split_inclusive('\n')
+                .map(String::from)
+                .collect(),
+            rejects: Rc::clone(&shared),
+            written: vec![],
+        };
+
+        let result = process_csv(
+            &mut engine,
+            &mut rows,
+            false,
+            &mut rejects,
+            &mut events,
+            Some(&mut wal),
+            0,
+        );
+        assert_eq!(result.ok(), Some(3));
+
+        // the reject of the third line is out before the fourth is read
+        let reject = "line,type,client,tx,amount,currency,reason\n\
+            3,withdrawal,1,2,5.0,,withdraw amount is bigger than available amount\n";
+        assert_eq!(rows.written[..3], [0, 0, 0]);
+        assert_eq!(rows.written[3], reject.len());
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn strict_mode_stops_at_first_reject() {
+        let (result, rejects) = process(INPUT_WITH_BAD_ROWS, true);
+
+        assert_eq!(
+            result,
+            Err(String::from(
+                "line 3: withdraw amount is bigger than available amount"
+            ))
+        );
+        assert!(rejects.is_empty());
     }
 }
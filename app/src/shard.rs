@@ -0,0 +1,216 @@
+use crate::{csv_reader, parse_record, write_reject, TransactionType};
+use payments_engine::{EngineConfig, PaymentsEngine, TransactionId, TransactionIds};
+use serde::de::value::Error as ValueError;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::error::Error;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+// rows are handed to the workers in batches, a few batches may queue up per
+// worker before parsing waits for it
+const BATCH_ROWS: usize = 1024;
+const QUEUED_BATCHES: usize = 16;
+
+type Row = (u64, csv::StringRecord);
+
+// a rejected row with its line, raw fields and reason
+type Reject = (u64, csv::StringRecord, String);
+
+#[derive(Debug)]
+pub enum Sharded {
+    // one engine per worker, each holding the clients of its shard
+    Done {
+        engines: Vec<PaymentsEngine>,
+        rejected: u64,
+    },
+    // splitting the input by client would change the outcome, it has to be
+    // processed sequentially
+    Unshardable(String),
+}
+
+// Reads the input on the calling thread and hands each row to the worker
+// owning its client, client id modulo workers, which parses and applies it,
+// so rows of the same client keep their order. That only matches the
+// sequential run while shards don't interact: transaction ids are unique
+// across clients, so a row reusing or targeting an id already used in another
+// shard gives up, as do authorizations, whose expiry counts the transactions
+// of every client, and a dispute window, for the same reason. Rejects are
+// written in line order once all the rows are in, the other outputs are the
+// caller's.
+pub fn process_csv<R: io::Read, W: io::Write>(
+    config: &EngineConfig,
+    input: R,
+    workers: usize,
+    strict: bool,
+    rejects: &mut csv::Writer<W>,
+) -> Result<Sharded, Box<dyn Error>> {
+    if config.dispute_window.is_some() {
+        return Ok(Sharded::Unshardable(String::from(
+            "the dispute window counts the transactions of every client",
+        )));
+    }
+    let engines = (0..workers)
+        .map(|_| PaymentsEngine::with_config(config.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rdr = csv_reader(input);
+    let headers = rdr.headers()?.clone();
+    let column = |name| headers.iter().position(|header| header == name);
+    let (type_column, client_column, tx_column) =
+        match (column("type"), column("client"), column("tx")) {
+            (Some(r#type), Some(client), Some(tx)) => (r#type, client, tx),
+            _ => {
+                return Ok(Sharded::Unshardable(String::from(
+                    "the type, client or tx column is missing",
+                )))
+            }
+        };
+    let default_currency = config.default_currency;
+    // ids of the new transactions each shard has seen, applied or not
+    let mut claimed = vec![TransactionIds::default(); workers];
+    let mut read_rejects: Vec<Reject> = vec![];
+
+    let (unshardable, results) = thread::scope(|scope| {
+        let mut senders = vec![];
+        let mut handles = vec![];
+        for mut engine in engines {
+            let (sender, receiver) = mpsc::sync_channel::<Vec<Row>>(QUEUED_BATCHES);
+            senders.push(sender);
+            let headers = &headers;
+            handles.push(scope.spawn(move || {
+                let mut rejects: Vec<Reject> = vec![];
+                for batch in receiver {
+                    for (line, record) in batch {
+                        let outcome = parse_record(&record, headers, default_currency)
+                            .and_then(|transaction| Ok(engine.recv_tx(transaction)?));
+                        if let Err(err) = outcome {
+                            rejects.push((line, record, err.to_string()));
+                        }
+                    }
+                }
+                (engine, rejects)
+            }));
+        }
+
+        let mut batches: Vec<Vec<Row>> = vec![vec![]; workers];
+        let mut unshardable = None;
+        for result in rdr.records() {
+            let record = match result {
+                Ok(record) => record,
+                Err(err) => {
+                    let line = err.position().map_or(0, |position| position.line());
+                    read_rejects.push((line, csv::StringRecord::new(), err.to_string()));
+                    continue;
+                }
+            };
+            let line = record.position().map_or(0, |position| position.line());
+
+            // a row whose client doesn't parse is rejected by any worker
+            let client = record
+                .get(client_column)
+                .and_then(|client| client.parse().ok());
+            let shard = client.map_or(0, |client: u16| usize::from(client) % workers);
+            let r#type = record.get(type_column).and_then(|r#type| {
+                TransactionType::deserialize(IntoDeserializer::<ValueError>::into_deserializer(
+                    r#type,
+                ))
+                .ok()
+            });
+            let transaction_id = record.get(tx_column).and_then(|tx| tx.parse().ok());
+            if let (Some(_), Some(r#type), Some(transaction_id)) = (client, r#type, transaction_id)
+            {
+                let claimed = claim(&mut claimed, shard, r#type, TransactionId(transaction_id));
+                if let Err(reason) = claimed {
+                    unshardable = Some(format!("line {}: {}", line, reason));
+                    break;
+                }
+            }
+            batches[shard].push((line, record));
+            if batches[shard].len() == BATCH_ROWS {
+                // a worker only hangs up by panicking, which join reports
+                let _ = senders[shard].send(std::mem::take(&mut batches[shard]));
+            }
+        }
+        for (sender, batch) in senders.into_iter().zip(batches) {
+            if unshardable.is_none() {
+                let _ = sender.send(batch);
+            }
+        }
+
+        let results = handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .collect::<Result<Vec<_>, _>>();
+        (unshardable, results)
+    });
+
+    let results = results.map_err(|_| "a worker thread panicked")?;
+    if let Some(reason) = unshardable {
+        return Ok(Sharded::Unshardable(reason));
+    }
+
+    let mut engines = Vec::with_capacity(workers);
+    let mut all_rejects = read_rejects;
+    for (engine, rejects) in results {
+        engines.push(engine);
+        all_rejects.extend(rejects);
+    }
+    all_rejects.sort_by_key(|(line, _, _)| *line);
+
+    if strict {
+        if let Some((line, _, reason)) = all_rejects.first() {
+            return Err(From::from(format!("line {}: {}", line, reason)));
+        }
+    }
+    for (line, record, reason) in &all_rejects {
+        write_reject(rejects, &headers, *line, record, reason.clone())?;
+    }
+    rejects.flush()?;
+
+    Ok(Sharded::Done {
+        engines,
+        rejected: all_rejects.len() as u64,
+    })
+}
+
+// records the id a row introduces against its shard, failing when the row
+// depends on what another shard has seen
+fn claim(
+    claimed: &mut [TransactionIds],
+    shard: usize,
+    r#type: TransactionType,
+    transaction_id: TransactionId,
+) -> Result<(), String> {
+    let used_elsewhere = claimed
+        .iter()
+        .enumerate()
+        .any(|(other, ids)| other != shard && ids.contains(transaction_id));
+    match r#type {
+        TransactionType::Authorize | TransactionType::Capture | TransactionType::Void => Err(
+            String::from("authorizations expire by the transactions of every client"),
+        ),
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+            if used_elsewhere =>
+        {
+            Err(format!(
+                "transaction {} belongs to another shard",
+                transaction_id.0
+            ))
+        }
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::CreditLimit
+            if used_elsewhere =>
+        {
+            Err(format!(
+                "transaction id {} is used in more than one shard",
+                transaction_id.0
+            ))
+        }
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::CreditLimit => {
+            claimed[shard].insert(transaction_id);
+            Ok(())
+        }
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => Ok(()),
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{Amount, ClientId, Currency, Error, TransactionId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthorizationStatus {
+    Open,
+    Captured,
+    Voided,
+    Expired,
+}
+
+impl fmt::Display for AuthorizationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthorizationStatus::Open => write!(f, "open"),
+            AuthorizationStatus::Captured => write!(f, "captured"),
+            AuthorizationStatus::Voided => write!(f, "voided"),
+            AuthorizationStatus::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+// Moves funds from available to reserved until they are captured, voided or
+// the authorization expires. Only an open authorization can be settled.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Authorize {
+    pub transaction_id: TransactionId,
+    pub client_id: ClientId,
+    pub amount: Amount,
+    pub currency: Currency,
+}
+
+impl Authorize {
+    pub fn new(
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        currency: Currency,
+    ) -> Authorize {
+        Authorize {
+            transaction_id,
+            client_id,
+            amount,
+            currency,
+        }
+    }
+}
+
+// what a client's history keeps of an accepted authorization
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Authorization {
+    pub amount: Amount,
+    pub currency: Currency,
+    pub status: AuthorizationStatus,
+    // part of the amount taken by the capture, the rest went back to available
+    pub captured: Amount,
+    // engine sequence from which the authorization counts as expired
+    pub expires_at: u64,
+}
+
+impl Authorization {
+    pub(crate) fn check_open(&self) -> Result<(), Error> {
+        match self.status {
+            AuthorizationStatus::Open => Ok(()),
+            status => Err(Error::AuthorizationClosed(status)),
+        }
+    }
+}
+
+// takes the whole authorized amount if none is given, a partial capture
+// releases the rest
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capture {
+    pub client_id: ClientId,
+    pub target_transaction_id: TransactionId,
+    pub amount: Option<Amount>,
+    // checked against the target's currency if given
+    pub currency: Option<Currency>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Void {
+    pub client_id: ClientId,
+    pub target_transaction_id: TransactionId,
+}
@@ -0,0 +1,471 @@
+use crate::{Amount, ClientId, Currency, FeeKind, FeeSchedule};
+use rust_decimal::Decimal;
+use rust_decimal::RoundingStrategy;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const MIN_DEPOSIT: Decimal = dec!(0.0001);
+const MAX_DEPOSIT: Decimal = dec!(50000);
+const MIN_WITHDRAW: Decimal = dec!(0.0001);
+const MAX_WITHDRAW: Decimal = dec!(50000);
+// limits of the currencies we operate in
+const CURRENCY_MIN: Decimal = dec!(0.01);
+const CURRENCY_MAX: Decimal = dec!(50000);
+const DECIMAL_POINTS: u32 = 4;
+const AUTHORIZATION_LIFETIME: u64 = 10000;
+// keeps the sequence an authorization expires at far from overflowing
+const MAX_AUTHORIZATION_LIFETIME: u64 = u32::MAX as u64;
+
+// rust_decimal supports at most 28 decimal points
+const MAX_DECIMAL_POINTS: u32 = 28;
+
+// Settings a PaymentsEngine is built with. Every field has a default so a
+// config file only needs to list the ones it changes, amounts are best given
+// as strings to avoid going through floating point:
+//
+//     min_deposit = "0.01"
+//     max_deposit = "10000"
+//     decimal_points = 2
+//     rounding_strategy = "midpoint_away_from_zero"
+//
+//     [[limits]]
+//     currency = "JPY"
+//     min_deposit = "1"
+//     max_deposit = "5000000"
+//     min_withdraw = "1"
+//     max_withdraw = "5000000"
+//
+//     [[credit_lines]]
+//     client = 7
+//     credit_limit = "500"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    // limits of the default currency
+    pub min_deposit: Amount,
+    pub max_deposit: Amount,
+    pub min_withdraw: Amount,
+    pub max_withdraw: Amount,
+    // limits of the other currencies, deposits and withdrawals in a currency
+    // without any are rejected, listing limits replaces the default ones
+    pub limits: Vec<CurrencyLimits>,
+    // precision of amounts in a currency without minor units, like XXX
+    pub decimal_points: u32,
+    pub rounding_strategy: Rounding,
+    // reject transaction amounts with more decimal points than their currency
+    // allows instead of rounding them
+    pub reject_excess_precision: bool,
+    // currency of input rows that don't specify one
+    pub default_currency: Currency,
+    // whether a locked client can still open new disputes, resolves and
+    // chargebacks of already open disputes are always accepted
+    pub dispute_locked_clients: bool,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    // what to do when a client no longer has the funds a dispute, or the fee
+    // of a dispute or chargeback, would take
+    pub dispute_shortfall: ShortfallPolicy,
+    // whether a resolved dispute can be opened again
+    pub redispute_resolved: bool,
+    // number of transactions the engine accepts before an authorization that
+    // was neither captured nor voided expires and its funds are released
+    pub authorization_lifetime: u64,
+    // number of transactions the engine accepts before a deposit, withdrawal
+    // or transfer can no longer be disputed and is dropped from the history,
+    // kept forever if not given
+    pub dispute_window: Option<u64>,
+    // number of transactions the engine accepts before a transaction that can
+    // still be disputed, captured or voided is moved from memory to the spill
+    // file, only used when the engine was given one
+    pub spill_after: Option<u64>,
+    // approved overdrafts, a credit limit set by an admin transaction
+    // replaces the one given here
+    pub credit_lines: Vec<CreditLine>,
+    // charged to clients and credited to the house account
+    pub fees: FeeSchedule,
+}
+
+// deposit and withdraw limits in a currency's own units
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CurrencyLimits {
+    pub currency: Currency,
+    pub min_deposit: Amount,
+    pub max_deposit: Amount,
+    pub min_withdraw: Amount,
+    pub max_withdraw: Amount,
+}
+
+impl CurrencyLimits {
+    fn new(currency: Currency, min: Decimal, max: Decimal) -> CurrencyLimits {
+        CurrencyLimits {
+            currency,
+            min_deposit: Amount(min),
+            max_deposit: Amount(max),
+            min_withdraw: Amount(min),
+            max_withdraw: Amount(max),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_deposit.0 <= Decimal::ZERO || self.min_withdraw.0 <= Decimal::ZERO {
+            Err(ConfigError::NonPositiveMinimum)
+        } else if self.min_deposit > self.max_deposit {
+            Err(ConfigError::MinDepositMoreThanMax)
+        } else if self.min_withdraw > self.max_withdraw {
+            Err(ConfigError::MinWithdrawMoreThanMax)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// lets a client's available funds in a currency go down to -credit_limit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreditLine {
+    pub client: ClientId,
+    // the default currency if not given
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    pub credit_limit: Amount,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            min_deposit: Amount(MIN_DEPOSIT),
+            max_deposit: Amount(MAX_DEPOSIT),
+            min_withdraw: Amount(MIN_WITHDRAW),
+            max_withdraw: Amount(MAX_WITHDRAW),
+            limits: [Currency::EUR, Currency::GBP, Currency::USD]
+                .into_iter()
+                .map(|currency| CurrencyLimits::new(currency, CURRENCY_MIN, CURRENCY_MAX))
+                .collect(),
+            decimal_points: DECIMAL_POINTS,
+            rounding_strategy: Rounding::MidpointNearestEven,
+            reject_excess_precision: false,
+            default_currency: Currency::XXX,
+            dispute_locked_clients: true,
+            withdrawal_disputes: WithdrawalDisputePolicy::Reject,
+            dispute_shortfall: ShortfallPolicy::AllowNegative,
+            redispute_resolved: false,
+            authorization_lifetime: AUTHORIZATION_LIFETIME,
+            dispute_window: None,
+            spill_after: None,
+            credit_lines: vec![],
+            fees: FeeSchedule::default(),
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.default_limits().validate()?;
+        for (i, limits) in self.limits.iter().enumerate() {
+            limits.validate()?;
+            if self.limits[..i]
+                .iter()
+                .any(|other| other.currency == limits.currency)
+            {
+                return Err(ConfigError::DuplicateLimits(limits.currency));
+            }
+        }
+        if self.decimal_points == 0 || self.decimal_points > MAX_DECIMAL_POINTS {
+            Err(ConfigError::InvalidDecimalPoints)
+        } else if self.authorization_lifetime == 0
+            || self.authorization_lifetime > MAX_AUTHORIZATION_LIFETIME
+        {
+            Err(ConfigError::InvalidAuthorizationLifetime)
+        } else if self.dispute_window == Some(0) || self.spill_after == Some(0) {
+            Err(ConfigError::ZeroHistoryWindow)
+        } else if self
+            .credit_lines
+            .iter()
+            .any(|line| line.credit_limit < Amount::ZERO)
+        {
+            Err(ConfigError::NegativeCreditLimit)
+        } else if ![
+            &self.fees.withdrawal,
+            &self.fees.dispute,
+            &self.fees.chargeback,
+        ]
+        .iter()
+        .all(|fee| fee.is_valid())
+        {
+            Err(ConfigError::InvalidFee)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn default_limits(&self) -> CurrencyLimits {
+        CurrencyLimits {
+            currency: self.default_currency,
+            min_deposit: self.min_deposit,
+            max_deposit: self.max_deposit,
+            min_withdraw: self.min_withdraw,
+            max_withdraw: self.max_withdraw,
+        }
+    }
+
+    // limits listed for a currency, or the top level ones for the default
+    // currency
+    pub(crate) fn limits(&self, currency: Currency) -> Result<CurrencyLimits, crate::Error> {
+        match self
+            .limits
+            .iter()
+            .find(|limits| limits.currency == currency)
+        {
+            Some(limits) => Ok(*limits),
+            None if currency == self.default_currency => Ok(self.default_limits()),
+            None => Err(crate::Error::NoCurrencyLimits),
+        }
+    }
+
+    // currencies are rounded to their minor units, amounts without a
+    // currency to the configured decimal points
+    pub(crate) fn round(&self, amount: Amount, currency: Currency) -> Amount {
+        let decimal_points = currency.minor_units().unwrap_or(self.decimal_points);
+        Amount(
+            amount
+                .0
+                .round_dp_with_strategy(decimal_points, self.rounding_strategy.into()),
+        )
+    }
+
+    // rounds an amount given by a transaction, or rejects it in strict mode
+    pub(crate) fn round_input(
+        &self,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<Amount, crate::Error> {
+        let rounded = self.round(amount, currency);
+        if self.reject_excess_precision && rounded != amount {
+            Err(crate::Error::ExcessPrecision)
+        } else {
+            Ok(rounded)
+        }
+    }
+
+    // fee charged on a transaction amount, rounded like the amount
+    pub(crate) fn fee(
+        &self,
+        kind: FeeKind,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<Amount, crate::Error> {
+        Ok(self.round(self.fees.fee(kind).charge(amount)?, currency))
+    }
+}
+
+// Whether withdrawals can be disputed. A disputed withdrawal is credited back
+// to held, a resolve drops that credit and a chargeback releases it to
+// available, refunding the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalDisputePolicy {
+    Reject,
+    Allow,
+}
+
+// How a dispute is handled when the disputed funds were already spent, e.g.
+// a deposit that was withdrawn before being disputed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortfallPolicy {
+    // the whole amount is held and available goes negative
+    AllowNegative,
+    // the dispute is rejected
+    Reject,
+    // available goes down to zero at most, the rest is held on credit and
+    // recorded as a receivable the client pays back from later deposits
+    HoldAvailable,
+}
+
+// serde friendly mirror of rust_decimal::RoundingStrategy
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    MidpointNearestEven,
+    MidpointAwayFromZero,
+    MidpointTowardZero,
+    ToZero,
+    AwayFromZero,
+    ToNegativeInfinity,
+    ToPositiveInfinity,
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(rounding: Rounding) -> Self {
+        match rounding {
+            Rounding::MidpointNearestEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::MidpointAwayFromZero => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::MidpointTowardZero => RoundingStrategy::MidpointTowardZero,
+            Rounding::ToZero => RoundingStrategy::ToZero,
+            Rounding::AwayFromZero => RoundingStrategy::AwayFromZero,
+            Rounding::ToNegativeInfinity => RoundingStrategy::ToNegativeInfinity,
+            Rounding::ToPositiveInfinity => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("minimum deposit and withdraw amounts must be positive")]
+    NonPositiveMinimum,
+
+    #[error("minimum deposit amount is bigger than maximum")]
+    MinDepositMoreThanMax,
+
+    #[error("minimum withdraw amount is bigger than maximum")]
+    MinWithdrawMoreThanMax,
+
+    #[error("limits of {0} are given more than once")]
+    DuplicateLimits(Currency),
+
+    #[error("decimal points must be between 1 and 28")]
+    InvalidDecimalPoints,
+
+    #[error("authorization lifetime must be between 1 and 4294967295 transactions")]
+    InvalidAuthorizationLifetime,
+
+    #[error("dispute window and spill age must be at least one transaction")]
+    ZeroHistoryWindow,
+
+    #[error("credit limits can't be negative")]
+    NegativeCreditLimit,
+
+    #[error("fees can't be negative and their minimum can't be bigger than maximum")]
+    InvalidFee,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fee;
+    use test_case::test_case;
+
+    #[test]
+    fn round_to_currency_minor_units() {
+        let config = EngineConfig::default();
+
+        assert_eq!(
+            config.round(Amount(dec!(1.2345)), Currency::EUR),
+            Amount(dec!(1.23))
+        );
+        assert_eq!(
+            config.round(Amount(dec!(1.23456)), Currency::XXX),
+            Amount(dec!(1.2346))
+        );
+    }
+
+    #[test]
+    fn reject_excess_precision() {
+        let config = EngineConfig {
+            reject_excess_precision: true,
+            ..EngineConfig::default()
+        };
+
+        assert_eq!(
+            config.round_input(Amount(dec!(1.20)), Currency::EUR),
+            Ok(Amount(dec!(1.2)))
+        );
+        assert_eq!(
+            config.round_input(Amount(dec!(1.205)), Currency::EUR),
+            Err(crate::Error::ExcessPrecision)
+        );
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(EngineConfig::default().validate(), Ok(()));
+    }
+
+    #[test_case(EngineConfig {
+        min_deposit: Amount(Decimal::ZERO),
+        ..EngineConfig::default()
+    }, ConfigError::NonPositiveMinimum; "zero minimum deposit")]
+    #[test_case(EngineConfig {
+        min_withdraw: Amount(Decimal::NEGATIVE_ONE),
+        ..EngineConfig::default()
+    }, ConfigError::NonPositiveMinimum; "negative minimum withdraw")]
+    #[test_case(EngineConfig {
+        min_deposit: Amount(Decimal::TEN),
+        max_deposit: Amount(Decimal::ONE),
+        ..EngineConfig::default()
+    }, ConfigError::MinDepositMoreThanMax; "deposit minimum above maximum")]
+    #[test_case(EngineConfig {
+        min_withdraw: Amount(Decimal::TEN),
+        max_withdraw: Amount(Decimal::ONE),
+        ..EngineConfig::default()
+    }, ConfigError::MinWithdrawMoreThanMax; "withdraw minimum above maximum")]
+    #[test_case(EngineConfig {
+        limits: vec![CurrencyLimits {
+            max_deposit: Amount(dec!(0.001)),
+            ..CurrencyLimits::new(Currency::EUR, dec!(0.01), dec!(100))
+        }],
+        ..EngineConfig::default()
+    }, ConfigError::MinDepositMoreThanMax; "currency deposit minimum above maximum")]
+    #[test_case(EngineConfig {
+        limits: vec![
+            CurrencyLimits::new(Currency::EUR, dec!(0.01), dec!(100)),
+            CurrencyLimits::new(Currency::EUR, dec!(1), dec!(10)),
+        ],
+        ..EngineConfig::default()
+    }, ConfigError::DuplicateLimits(Currency::EUR); "currency limits given twice")]
+    #[test_case(EngineConfig {
+        decimal_points: 0,
+        ..EngineConfig::default()
+    }, ConfigError::InvalidDecimalPoints; "zero precision")]
+    #[test_case(EngineConfig {
+        decimal_points: 29,
+        ..EngineConfig::default()
+    }, ConfigError::InvalidDecimalPoints; "precision beyond decimal")]
+    #[test_case(EngineConfig {
+        authorization_lifetime: 0,
+        ..EngineConfig::default()
+    }, ConfigError::InvalidAuthorizationLifetime; "authorizations expiring at once")]
+    #[test_case(EngineConfig {
+        authorization_lifetime: u64::MAX,
+        ..EngineConfig::default()
+    }, ConfigError::InvalidAuthorizationLifetime; "authorizations expiring past the last sequence")]
+    #[test_case(EngineConfig {
+        dispute_window: Some(0),
+        ..EngineConfig::default()
+    }, ConfigError::ZeroHistoryWindow; "empty dispute window")]
+    #[test_case(EngineConfig {
+        credit_lines: vec![CreditLine {
+            client: ClientId(1),
+            currency: None,
+            credit_limit: Amount(Decimal::NEGATIVE_ONE),
+        }],
+        ..EngineConfig::default()
+    }, ConfigError::NegativeCreditLimit; "negative credit limit")]
+    #[test_case(EngineConfig {
+        fees: FeeSchedule {
+            dispute: Fee {
+                flat: Amount(Decimal::NEGATIVE_ONE),
+                ..Fee::default()
+            },
+            ..FeeSchedule::default()
+        },
+        ..EngineConfig::default()
+    }, ConfigError::InvalidFee; "negative fee")]
+    fn invalid_config(config: EngineConfig, error: ConfigError) {
+        assert_eq!(config.validate(), Err(error));
+    }
+
+    #[test_case(Rounding::MidpointNearestEven, dec!(0.12), dec!(0.125); "midpoint to even")]
+    #[test_case(Rounding::MidpointAwayFromZero, dec!(0.13), dec!(0.125); "midpoint away from zero")]
+    #[test_case(Rounding::ToZero, dec!(0.12), dec!(0.129); "towards zero")]
+    fn round_amount(rounding_strategy: Rounding, rounded: Decimal, amount: Decimal) {
+        let config = EngineConfig {
+            decimal_points: 2,
+            rounding_strategy,
+            ..EngineConfig::default()
+        };
+
+        assert_eq!(config.round(Amount(amount), Currency::XXX), Amount(rounded));
+    }
+}
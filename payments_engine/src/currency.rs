@@ -0,0 +1,117 @@
+use crate::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// ISO 4217 codes the engine knows about and their minor units. XXX is the
+// code for transactions where no currency is involved, it has no minor units
+// so amounts in it use the precision from EngineConfig.
+const CURRENCIES: &[(&str, Option<u32>)] = &[
+    ("AUD", Some(2)),
+    ("BHD", Some(3)),
+    ("CAD", Some(2)),
+    ("CHF", Some(2)),
+    ("CZK", Some(2)),
+    ("DKK", Some(2)),
+    ("EUR", Some(2)),
+    ("GBP", Some(2)),
+    ("JPY", Some(0)),
+    ("KWD", Some(3)),
+    ("NOK", Some(2)),
+    ("PLN", Some(2)),
+    ("SEK", Some(2)),
+    ("USD", Some(2)),
+    ("XXX", None),
+];
+
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Copy, Clone)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const GBP: Currency = Currency(*b"GBP");
+    pub const JPY: Currency = Currency(*b"JPY");
+    pub const USD: Currency = Currency(*b"USD");
+    pub const XXX: Currency = Currency(*b"XXX");
+
+    pub fn code(&self) -> &str {
+        // only ever built from the ASCII codes in CURRENCIES
+        std::str::from_utf8(&self.0).unwrap_or("XXX")
+    }
+
+    pub fn minor_units(&self) -> Option<u32> {
+        CURRENCIES
+            .iter()
+            .find(|(code, _)| code.as_bytes() == self.0)
+            .and_then(|(_, minor_units)| *minor_units)
+    }
+
+    // a transaction referring to an earlier one may give its currency, which
+    // then has to match
+    pub(crate) fn check(self, given: Option<Currency>) -> Result<(), Error> {
+        match given {
+            Some(currency) if currency != self => Err(Error::CurrencyMismatch),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = Error;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let code = code.to_ascii_uppercase();
+        CURRENCIES
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(known, _)| {
+                let mut bytes = [0; 3];
+                bytes.copy_from_slice(known.as_bytes());
+                Currency(bytes)
+            })
+            .ok_or(Error::UnknownCurrency)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("EUR", Currency::EUR, Some(2); "euro")]
+    #[test_case("gbp", Currency::GBP, Some(2); "lower case pound")]
+    #[test_case("JPY", Currency(*b"JPY"), Some(0); "yen has no minor units")]
+    #[test_case("XXX", Currency::XXX, None; "no currency")]
+    fn parse_currency(code: &str, currency: Currency, minor_units: Option<u32>) {
+        let parsed: Currency = code.parse().expect("currency error");
+
+        assert_eq!(parsed, currency);
+        assert_eq!(parsed.minor_units(), minor_units);
+    }
+
+    #[test_case("EU"; "too short")]
+    #[test_case("ABC"; "not in the table")]
+    #[test_case(""; "empty")]
+    fn parse_unknown_currency(code: &str) {
+        assert_eq!(code.parse::<Currency>(), Err(Error::UnknownCurrency));
+    }
+}
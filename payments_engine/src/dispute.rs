@@ -0,0 +1,309 @@
+use crate::{Amount, Currency, EngineConfig, Error, Transaction};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeStatus {
+    NotDisputed,
+    Disputed,
+    Resolved,
+    Chargebacked,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeAction {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl DisputeStatus {
+    // The status a deposit or withdrawal moves to when fully settled by the
+    // given action. Partial disputes can be added on top of an open one. Once
+    // nothing is left under dispute the status is that of the last
+    // settlement, and the transaction can only be disputed again for what is
+    // left disputable, see Disputable::check.
+    //
+    //   status \ action | dispute     | resolve     | chargeback
+    //   ----------------+-------------+-------------+----------------
+    //   NotDisputed     | Disputed    | error       | error
+    //   Disputed        | Disputed    | Resolved(+) | Chargebacked(+)
+    //   Resolved        | Disputed(*) | error       | error
+    //   Chargebacked    | Disputed(*) | error       | error
+    //
+    //   (+) a partial resolve or chargeback stays Disputed
+    //   (*) if part of the amount is left disputable
+    //
+    // Every step is recorded as a DisputeEvent with the amount it moved and
+    // the parts of the whole amount it left.
+    pub fn transition(
+        self,
+        action: DisputeAction,
+        disputable: bool,
+    ) -> Result<DisputeStatus, Error> {
+        match (self, action) {
+            (DisputeStatus::NotDisputed | DisputeStatus::Disputed, DisputeAction::Dispute) => {
+                Ok(DisputeStatus::Disputed)
+            }
+            (DisputeStatus::Resolved | DisputeStatus::Chargebacked, DisputeAction::Dispute)
+                if disputable =>
+            {
+                Ok(DisputeStatus::Disputed)
+            }
+            (DisputeStatus::Disputed, DisputeAction::Resolve) => Ok(DisputeStatus::Resolved),
+            (DisputeStatus::Disputed, DisputeAction::Chargeback) => Ok(DisputeStatus::Chargebacked),
+            (status, action) => Err(Error::InvalidDisputeTransition { status, action }),
+        }
+    }
+}
+
+impl fmt::Display for DisputeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisputeStatus::NotDisputed => write!(f, "not disputed"),
+            DisputeStatus::Disputed => write!(f, "disputed"),
+            DisputeStatus::Resolved => write!(f, "resolved"),
+            DisputeStatus::Chargebacked => write!(f, "charged back"),
+        }
+    }
+}
+
+impl fmt::Display for DisputeAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisputeAction::Dispute => write!(f, "dispute"),
+            DisputeAction::Resolve => write!(f, "resolve"),
+            DisputeAction::Chargeback => write!(f, "charge back"),
+        }
+    }
+}
+
+// One step in the dispute history of a deposit or withdrawal. The sequence is
+// the position of the triggering transaction among all the transactions the
+// engine accepted, so events can be ordered across clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisputeEvent {
+    pub sequence: u64,
+    pub from: DisputeStatus,
+    pub to: DisputeStatus,
+    // what the event moved into dispute, or out of it
+    pub amount: Amount,
+    // how the whole amount was split after the event, missing from events
+    // of version 1 snapshots
+    #[serde(default)]
+    pub parts: DisputeParts,
+    pub transaction: Transaction,
+}
+
+// What disputes work on, shared by deposits, withdrawals and transfers. The
+// disputed and charged back parts are only allocated once the transaction is
+// disputed, which most never are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Disputable {
+    pub amount: Amount,
+    pub currency: Currency,
+    pub status: DisputeStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disputes: Option<Box<DisputeParts>>,
+}
+
+// Whatever of the amount isn't in one of these was never disputed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisputeParts {
+    // part of the amount currently held by open disputes
+    pub disputed: Amount,
+    // part of the amount already taken back by chargebacks
+    pub charged_back: Amount,
+    // part of the amount whose disputes were resolved
+    #[serde(default)]
+    pub resolved: Amount,
+}
+
+impl Disputable {
+    pub fn new(amount: Amount, currency: Currency) -> Disputable {
+        Disputable {
+            amount,
+            currency,
+            status: DisputeStatus::NotDisputed,
+            disputes: None,
+        }
+    }
+
+    pub(crate) fn with_amounts(
+        amount: Amount,
+        currency: Currency,
+        status: DisputeStatus,
+        disputed: Amount,
+        charged_back: Amount,
+        resolved: Amount,
+    ) -> Disputable {
+        let disputes = DisputeParts {
+            disputed,
+            charged_back,
+            resolved,
+        };
+        let disputes = (disputes != DisputeParts::default()).then(|| Box::new(disputes));
+        Disputable {
+            amount,
+            currency,
+            status,
+            disputes,
+        }
+    }
+
+    pub fn parts(&self) -> DisputeParts {
+        self.disputes.as_deref().copied().unwrap_or_default()
+    }
+
+    pub fn disputed(&self) -> Amount {
+        self.disputes
+            .as_ref()
+            .map_or(Amount::ZERO, |disputes| disputes.disputed)
+    }
+
+    pub fn charged_back(&self) -> Amount {
+        self.disputes
+            .as_ref()
+            .map_or(Amount::ZERO, |disputes| disputes.charged_back)
+    }
+
+    pub fn resolved(&self) -> Amount {
+        self.disputes
+            .as_ref()
+            .map_or(Amount::ZERO, |disputes| disputes.resolved)
+    }
+
+    // under an open dispute
+    pub fn is_open(&self) -> bool {
+        self.disputed() > Amount::ZERO
+    }
+
+    // the part of the amount that was never disputed
+    pub fn undisputed(&self) -> Result<Amount, Error> {
+        self.amount
+            .checked_subtract(self.disputed())?
+            .checked_subtract(self.charged_back())?
+            .checked_subtract(self.resolved())
+    }
+
+    // what a new dispute can cover, resolved parts only if they can be
+    // disputed again
+    fn disputable(&self, redispute_resolved: bool) -> Result<Amount, Error> {
+        if redispute_resolved {
+            self.undisputed()?.checked_add(self.resolved())
+        } else {
+            self.undisputed()
+        }
+    }
+
+    // Checks an action against the transaction, returning the status it
+    // moves to when it settles the whole dispute and the amount it covers,
+    // rounded to the currency. A transaction is only final once no part of
+    // its amount is left to dispute.
+    pub(crate) fn check(
+        &self,
+        action: DisputeAction,
+        amount: Option<Amount>,
+        currency: Option<Currency>,
+        config: &EngineConfig,
+    ) -> Result<(DisputeStatus, Amount), Error> {
+        self.currency.check(currency)?;
+        let disputable = self.disputable(config.redispute_resolved)?;
+        let to = self.status.transition(action, disputable > Amount::ZERO)?;
+        let amount = match action {
+            DisputeAction::Dispute => {
+                Amount::check_and_round_dispute(amount, disputable, self.currency, config)?
+            }
+            DisputeAction::Resolve | DisputeAction::Chargeback => {
+                Amount::check_and_round_settlement(amount, self.disputed(), self.currency, config)?
+            }
+        };
+        Ok((to, amount))
+    }
+
+    // Moves an amount checked by check() in or out of dispute. A partial
+    // resolve or chargeback leaves the rest under dispute, and a dispute
+    // takes what was never disputed before resolved parts.
+    pub(crate) fn apply(
+        &mut self,
+        action: DisputeAction,
+        to: DisputeStatus,
+        amount: Amount,
+    ) -> Result<(), Error> {
+        let undisputed = self.undisputed()?;
+        let mut disputes = self.parts();
+        match action {
+            DisputeAction::Dispute => {
+                let redisputed = amount.checked_subtract(undisputed)?.max(Amount::ZERO);
+                disputes.resolved = disputes.resolved.checked_subtract(redisputed)?;
+                disputes.disputed = disputes.disputed.checked_add(amount)?;
+            }
+            DisputeAction::Resolve => {
+                disputes.disputed = disputes.disputed.checked_subtract(amount)?;
+                disputes.resolved = disputes.resolved.checked_add(amount)?;
+            }
+            DisputeAction::Chargeback => {
+                disputes.disputed = disputes.disputed.checked_subtract(amount)?;
+                disputes.charged_back = disputes.charged_back.checked_add(amount)?;
+            }
+        }
+        self.status = if action == DisputeAction::Dispute || disputes.disputed == Amount::ZERO {
+            to
+        } else {
+            DisputeStatus::Disputed
+        };
+        // fully resolved disputes leave nothing to keep
+        self.disputes = (disputes != DisputeParts::default()).then(|| Box::new(disputes));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(DisputeStatus::NotDisputed, DisputeAction::Dispute, DisputeStatus::Disputed; "dispute")]
+    #[test_case(DisputeStatus::Disputed, DisputeAction::Dispute, DisputeStatus::Disputed; "partial dispute")]
+    #[test_case(DisputeStatus::Disputed, DisputeAction::Resolve, DisputeStatus::Resolved; "resolve")]
+    #[test_case(DisputeStatus::Disputed, DisputeAction::Chargeback, DisputeStatus::Chargebacked; "chargeback")]
+    fn legal_transition(status: DisputeStatus, action: DisputeAction, next: DisputeStatus) {
+        assert_eq!(status.transition(action, false), Ok(next));
+    }
+
+    #[test_case(DisputeStatus::NotDisputed, DisputeAction::Resolve; "resolve not disputed")]
+    #[test_case(DisputeStatus::NotDisputed, DisputeAction::Chargeback; "chargeback not disputed")]
+    #[test_case(DisputeStatus::Resolved, DisputeAction::Dispute; "dispute resolved")]
+    #[test_case(DisputeStatus::Resolved, DisputeAction::Resolve; "resolve resolved")]
+    #[test_case(DisputeStatus::Resolved, DisputeAction::Chargeback; "chargeback resolved")]
+    #[test_case(DisputeStatus::Chargebacked, DisputeAction::Dispute; "dispute charged back")]
+    #[test_case(DisputeStatus::Chargebacked, DisputeAction::Resolve; "resolve charged back")]
+    #[test_case(DisputeStatus::Chargebacked, DisputeAction::Chargeback; "chargeback charged back")]
+    fn illegal_transition(status: DisputeStatus, action: DisputeAction) {
+        assert_eq!(
+            status.transition(action, false),
+            Err(Error::InvalidDisputeTransition { status, action })
+        );
+    }
+
+    #[test_case(DisputeStatus::Resolved ; "resolved")]
+    #[test_case(DisputeStatus::Chargebacked ; "charged back")]
+    fn dispute_what_is_left(status: DisputeStatus) {
+        assert_eq!(
+            status.transition(DisputeAction::Dispute, true),
+            Ok(DisputeStatus::Disputed)
+        );
+    }
+
+    #[test]
+    fn transition_error_message() {
+        let error = DisputeStatus::NotDisputed
+            .transition(DisputeAction::Resolve, false)
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "can't resolve a transaction that is not disputed"
+        );
+    }
+}
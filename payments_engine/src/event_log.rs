@@ -0,0 +1,286 @@
+use crate::{ConfigError, EngineConfig, PaymentsEngine, Transaction};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use thiserror::Error;
+
+// Newline delimited JSON: a header line with the format version and the
+// config the log is written with, then one accepted transaction per line in
+// the order they were applied. Bump the version whenever the serialized shape
+// of Transaction changes.
+pub const EVENT_LOG_VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    // missing from version 1 logs, which are replayed unchecked
+    #[serde(default)]
+    config: Option<EngineConfig>,
+}
+
+#[derive(Error, Debug)]
+pub enum EventLogError {
+    #[error("event log i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("malformed event on line {line}: {source}")]
+    Malformed {
+        line: u64,
+        source: serde_json::Error,
+    },
+
+    #[error("event log has no header")]
+    MissingHeader,
+
+    #[error("event log version {0} isn't supported")]
+    UnsupportedVersion(u32),
+
+    #[error("event log was written with a different config")]
+    ConfigMismatch,
+
+    #[error("event on line {line} was rejected on replay: {source}")]
+    Rejected { line: u64, source: crate::Error },
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+pub struct EventLog<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> EventLog<W> {
+    // starts a new log of an engine with the given config, writing its header
+    pub fn create(mut writer: W, config: &EngineConfig) -> Result<EventLog<W>, EventLogError> {
+        serde_json::to_writer(
+            &mut writer,
+            &Header {
+                version: EVENT_LOG_VERSION,
+                config: Some(config.clone()),
+            },
+        )
+        .map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(EventLog { writer })
+    }
+
+    // continues a log that already has a header, e.g. a file opened for
+    // appending after a replay
+    pub fn resume(writer: W) -> EventLog<W> {
+        EventLog { writer }
+    }
+
+    pub fn append(&mut self, transaction: &Transaction) -> Result<(), EventLogError> {
+        serde_json::to_writer(&mut self.writer, transaction).map_err(io::Error::from)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), EventLogError> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// reads the transactions of a log back, checking its header against the
+// config they are going to be applied with first
+pub fn read_events<R: BufRead>(
+    reader: R,
+    config: &EngineConfig,
+) -> Result<impl Iterator<Item = Result<(u64, Transaction), EventLogError>>, EventLogError> {
+    let mut lines = reader.lines().zip(1..);
+    let header = match lines.next() {
+        Some((line, number)) => {
+            serde_json::from_str::<Header>(&line?).map_err(|source| EventLogError::Malformed {
+                line: number,
+                source,
+            })?
+        }
+        None => return Err(EventLogError::MissingHeader),
+    };
+    if !(1..=EVENT_LOG_VERSION).contains(&header.version) {
+        return Err(EventLogError::UnsupportedVersion(header.version));
+    }
+    if header.config.is_some_and(|logged| logged != *config) {
+        return Err(EventLogError::ConfigMismatch);
+    }
+
+    Ok(lines
+        .filter(|(line, _)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(line, number)| {
+            let transaction =
+                serde_json::from_str(&line?).map_err(|source| EventLogError::Malformed {
+                    line: number,
+                    source,
+                })?;
+            Ok((number, transaction))
+        }))
+}
+
+impl PaymentsEngine {
+    // Rebuilds an engine by applying every transaction of an event log. The
+    // config has to be the one the log was written with, otherwise events
+    // may be rejected or rounded differently.
+    pub fn replay<R: BufRead>(
+        config: EngineConfig,
+        reader: R,
+    ) -> Result<PaymentsEngine, EventLogError> {
+        let events = read_events(reader, &config)?;
+        let mut engine = PaymentsEngine::with_config(config)?;
+        for event in events {
+            let (line, transaction) = event?;
+            engine
+                .recv_tx(transaction)
+                .map_err(|source| EventLogError::Rejected { line, source })?;
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Amount, ClientId, Currency, Deposit, Dispute, Error, TransactionId, Transfer, Withdraw,
+    };
+    use rust_decimal::Decimal;
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::EUR,
+            )),
+            Transaction::Deposit(Deposit::new(
+                TransactionId(2),
+                ClientId(2),
+                Amount(Decimal::TEN),
+                Currency::EUR,
+            )),
+            Transaction::Withdraw(Withdraw::new(
+                TransactionId(3),
+                ClientId(1),
+                Amount(Decimal::new(125, 1)),
+                Currency::EUR,
+            )),
+            Transaction::Transfer(Transfer::new(
+                TransactionId(4),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::TEN),
+                Currency::EUR,
+            )),
+            Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::ONE)),
+                currency: None,
+            }),
+        ]
+    }
+
+    #[test]
+    fn replay_rebuilds_identical_state() {
+        let mut engine = PaymentsEngine::new();
+        let mut log = EventLog::create(vec![], &EngineConfig::default()).expect("log error");
+        for transaction in transactions() {
+            engine
+                .recv_tx(transaction.clone())
+                .expect("transaction error");
+            log.append(&transaction).expect("log error");
+        }
+
+        let replayed = PaymentsEngine::replay(EngineConfig::default(), &log.into_inner()[..])
+            .expect("replay error");
+
+        assert_eq!(replayed.client_list, engine.client_list);
+        assert_eq!(replayed.ledger(), engine.ledger());
+    }
+
+    #[test]
+    fn replay_resumed_log() {
+        let mut log = EventLog::create(vec![], &EngineConfig::default()).expect("log error");
+        for transaction in &transactions()[..3] {
+            log.append(transaction).expect("log error");
+        }
+        let mut log = EventLog::resume(log.into_inner());
+        for transaction in &transactions()[3..] {
+            log.append(transaction).expect("log error");
+        }
+
+        let replayed = PaymentsEngine::replay(EngineConfig::default(), &log.into_inner()[..])
+            .expect("replay error");
+
+        assert_eq!(
+            replayed.client_list[&ClientId(2)]
+                .balance(Currency::EUR)
+                .available,
+            Amount(Decimal::from(20))
+        );
+    }
+
+    #[test]
+    fn reject_other_config() {
+        let mut log = EventLog::create(vec![], &EngineConfig::default()).expect("log error");
+        log.append(&transactions()[0]).expect("log error");
+        let config = EngineConfig {
+            decimal_points: 2,
+            ..EngineConfig::default()
+        };
+
+        let result = PaymentsEngine::replay(config, &log.into_inner()[..]);
+
+        assert!(matches!(result, Err(EventLogError::ConfigMismatch)));
+    }
+
+    #[test]
+    fn replay_version_1_log() {
+        let deposit = serde_json::to_string(&transactions()[0]).expect("json error");
+        let log = format!("{{\"version\":1}}\n{deposit}\n");
+
+        let replayed =
+            PaymentsEngine::replay(EngineConfig::default(), log.as_bytes()).expect("replay error");
+
+        assert_eq!(replayed.client_list.len(), 1);
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let result = PaymentsEngine::replay(EngineConfig::default(), &b"{\"version\":99}\n"[..]);
+
+        assert!(matches!(result, Err(EventLogError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn reject_empty_log() {
+        let result = PaymentsEngine::replay(EngineConfig::default(), &b""[..]);
+
+        assert!(matches!(result, Err(EventLogError::MissingHeader)));
+    }
+
+    #[test]
+    fn report_line_of_rejected_event() {
+        let mut log = EventLog::create(vec![], &EngineConfig::default()).expect("log error");
+        let withdraw = Transaction::Withdraw(Withdraw::new(
+            TransactionId(1),
+            ClientId(1),
+            Amount(Decimal::ONE),
+            Currency::EUR,
+        ));
+        log.append(&withdraw).expect("log error");
+
+        let result = PaymentsEngine::replay(EngineConfig::default(), &log.into_inner()[..]);
+
+        assert!(matches!(
+            result,
+            Err(EventLogError::Rejected {
+                line: 2,
+                source: Error::NonExistingClient
+            })
+        ));
+    }
+}
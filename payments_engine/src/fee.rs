@@ -0,0 +1,126 @@
+use crate::{Amount, ClientId, Currency, Error, TransactionId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+// A flat part plus a percentage of the transaction amount, kept between min
+// and max. The default charges nothing. In a config file:
+//
+//     [fees.withdrawal]
+//     flat = "0.5"
+//     percentage = "1.5"
+//     min = "1"
+//     max = "25"
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fee {
+    pub flat: Amount,
+    pub percentage: Decimal,
+    pub min: Amount,
+    pub max: Option<Amount>,
+}
+
+impl Fee {
+    pub(crate) fn is_valid(&self) -> bool {
+        self.flat >= Amount::ZERO
+            && self.percentage >= Decimal::ZERO
+            && self.min >= Amount::ZERO
+            && self.max.is_none_or(|max| max >= self.min)
+    }
+
+    // not rounded yet, that depends on the currency
+    pub(crate) fn charge(&self, amount: Amount) -> Result<Amount, Error> {
+        let percentage = amount
+            .0
+            .checked_mul(self.percentage)
+            .and_then(|fee| fee.checked_div(Decimal::ONE_HUNDRED))
+            .ok_or(Error::AmountOverflow)?;
+        let fee = self.flat.checked_add(Amount(percentage))?.max(self.min);
+        Ok(match self.max {
+            Some(max) => fee.min(max),
+            None => fee,
+        })
+    }
+}
+
+// fees by the kind of transaction they are charged on
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub withdrawal: Fee,
+    pub dispute: Fee,
+    pub chargeback: Fee,
+}
+
+impl FeeSchedule {
+    pub(crate) fn fee(&self, kind: FeeKind) -> &Fee {
+        match kind {
+            FeeKind::Withdrawal => &self.withdrawal,
+            FeeKind::Dispute => &self.dispute,
+            FeeKind::Chargeback => &self.chargeback,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeKind {
+    Withdrawal,
+    Dispute,
+    Chargeback,
+}
+
+// a fee taken from a client's available funds into the house account, kept
+// in the client's history so statements can list it
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeLine {
+    pub sequence: u64,
+    pub client_id: ClientId,
+    pub kind: FeeKind,
+    // the withdrawal, or the disputed transaction
+    pub transaction_id: TransactionId,
+    pub currency: Currency,
+    pub amount: Amount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use test_case::test_case;
+
+    fn fee() -> Fee {
+        Fee {
+            flat: Amount(dec!(0.5)),
+            percentage: dec!(1.5),
+            min: Amount(dec!(1)),
+            max: Some(Amount(dec!(25))),
+        }
+    }
+
+    #[test_case(dec!(100), dec!(2) ; "flat and percentage")]
+    #[test_case(dec!(10), dec!(1) ; "at least the minimum")]
+    #[test_case(dec!(10000), dec!(25) ; "at most the maximum")]
+    fn charge_fee(amount: Decimal, charged: Decimal) {
+        assert_eq!(fee().charge(Amount(amount)), Ok(Amount(charged)));
+    }
+
+    #[test]
+    fn no_fee_by_default() {
+        assert_eq!(Fee::default().charge(Amount(dec!(100))), Ok(Amount::ZERO));
+    }
+
+    #[test]
+    fn invalid_fee() {
+        assert!(fee().is_valid());
+        assert!(!Fee {
+            min: Amount(dec!(30)),
+            ..fee()
+        }
+        .is_valid());
+        assert!(!Fee {
+            percentage: dec!(-1),
+            ..fee()
+        }
+        .is_valid());
+    }
+}
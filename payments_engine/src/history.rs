@@ -0,0 +1,464 @@
+use crate::{
+    Amount, Authorization, AuthorizationStatus, ClientId, Currency, Disputable, DisputeStatus,
+    TransactionId,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// What a client's history keeps of an accepted transaction, keyed by its id:
+// only what later transactions can refer to, so a long history stays small.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransactionRecord {
+    Deposit(Disputable),
+    Withdraw(Disputable),
+    // kept by the sender, who is the one disputing it
+    Transfer {
+        to: ClientId,
+        funds: Disputable,
+    },
+    // the recipient's side of a transfer, dropped and spilled along with it
+    ReceivedTransfer {
+        from: ClientId,
+        amount: Amount,
+        currency: Currency,
+    },
+    Authorize(Box<Authorization>),
+    Unlock {
+        reason: String,
+    },
+    CreditLimit {
+        credit_limit: Amount,
+        currency: Currency,
+    },
+}
+
+impl TransactionRecord {
+    // whether the history can drop or spill the transaction once it's settled
+    pub(crate) fn leaves_memory(&self) -> bool {
+        matches!(
+            self,
+            TransactionRecord::Deposit(_)
+                | TransactionRecord::Withdraw(_)
+                | TransactionRecord::Transfer { .. }
+                | TransactionRecord::Authorize(_)
+        )
+    }
+
+    // under dispute, or an authorization still holding funds
+    pub(crate) fn is_open(&self) -> bool {
+        match self {
+            TransactionRecord::Deposit(funds)
+            | TransactionRecord::Withdraw(funds)
+            | TransactionRecord::Transfer { funds, .. } => funds.is_open(),
+            TransactionRecord::Authorize(authorization) => {
+                authorization.status == AuthorizationStatus::Open
+            }
+            TransactionRecord::ReceivedTransfer { .. }
+            | TransactionRecord::Unlock { .. }
+            | TransactionRecord::CreditLimit { .. } => false,
+        }
+    }
+
+    // sequence an open authorization expires at
+    pub(crate) fn expires_at(&self) -> Option<u64> {
+        match self {
+            TransactionRecord::Authorize(authorization)
+                if authorization.status == AuthorizationStatus::Open =>
+            {
+                Some(authorization.expires_at)
+            }
+            _ => None,
+        }
+    }
+
+    // what disputes work on, for the transactions that can be disputed
+    pub(crate) fn funds_mut(&mut self) -> Option<&mut Disputable> {
+        match self {
+            TransactionRecord::Deposit(funds)
+            | TransactionRecord::Withdraw(funds)
+            | TransactionRecord::Transfer { funds, .. } => Some(funds),
+            TransactionRecord::ReceivedTransfer { .. }
+            | TransactionRecord::Authorize(_)
+            | TransactionRecord::Unlock { .. }
+            | TransactionRecord::CreditLimit { .. } => None,
+        }
+    }
+}
+
+// ids per chunk of TransactionIds, 8 KiB of bits
+const CHUNK_IDS: u32 = 1 << 16;
+const CHUNK_WORDS: usize = (CHUNK_IDS / 64) as usize;
+
+// One bit per transaction id, allocated in chunks as ids show up, so the ids
+// of a dense input cost an eighth of a byte each. Used to tell duplicates
+// apart once a transaction itself is no longer kept.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionIds(BTreeMap<u32, Vec<u64>>);
+
+impl TransactionIds {
+    // false if the id was already there
+    pub fn insert(&mut self, transaction_id: TransactionId) -> bool {
+        let (chunk, word, bit) = position(transaction_id);
+        let words = self.0.entry(chunk).or_insert_with(|| vec![0; CHUNK_WORDS]);
+        let inserted = words[word] & bit == 0;
+        words[word] |= bit;
+        inserted
+    }
+
+    pub fn contains(&self, transaction_id: TransactionId) -> bool {
+        let (chunk, word, bit) = position(transaction_id);
+        self.0
+            .get(&chunk)
+            .is_some_and(|words| words[word] & bit != 0)
+    }
+
+    pub fn extend(&mut self, other: &TransactionIds) {
+        for (chunk, other_words) in &other.0 {
+            let words = self.0.entry(*chunk).or_insert_with(|| vec![0; CHUNK_WORDS]);
+            for (word, other_word) in words.iter_mut().zip(other_words) {
+                *word |= other_word;
+            }
+        }
+    }
+}
+
+fn position(transaction_id: TransactionId) -> (u32, usize, u64) {
+    let id = transaction_id.0;
+    let offset = id % CHUNK_IDS;
+    (id / CHUNK_IDS, (offset / 64) as usize, 1 << (offset % 64))
+}
+
+// Every record has a fixed size and sits at the offset given by its id, so
+// the file needs no index and unused ids cost nothing on a sparse file:
+//
+//     kind u8, owner u16, recipient u16, currency [u8; 3], status u8,
+//     amount, disputed or captured, charged_back, resolved as 16 byte
+//     decimals, accepted_at u64, expires_at u64
+//
+// A kind of 0 means the id was never spilled.
+const RECORD_SIZE: usize = 96;
+
+const DEPOSIT: u8 = 1;
+const WITHDRAW: u8 = 2;
+const TRANSFER: u8 = 3;
+const AUTHORIZE: u8 = 4;
+
+// Transactions moved out of memory while they can still be disputed,
+// captured or voided. A spilled transaction is read back into its client's
+// history when a transaction refers to it.
+#[derive(Debug)]
+pub struct SpillFile {
+    file: File,
+}
+
+impl SpillFile {
+    // starts an empty file, anything already at the path is dropped
+    pub fn create(path: &Path) -> io::Result<SpillFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(SpillFile { file })
+    }
+
+    // returns false for the kinds of transactions that aren't spilled
+    pub(crate) fn write(
+        &mut self,
+        accepted_at: u64,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        record: &TransactionRecord,
+    ) -> io::Result<bool> {
+        let record = match encode(accepted_at, client_id, record) {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        self.file.seek(SeekFrom::Start(offset(transaction_id)))?;
+        self.file.write_all(&record)?;
+        Ok(true)
+    }
+
+    // the record with the sequence it was accepted at and its owner
+    pub(crate) fn read(&mut self, transaction_id: TransactionId) -> io::Result<Option<Spilled>> {
+        let offset = offset(transaction_id);
+        if offset + RECORD_SIZE as u64 > self.file.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut record = [0; RECORD_SIZE];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut record)?;
+        decode(transaction_id, &record)
+            .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
+}
+
+// a record read back from the spill file
+#[derive(Debug, PartialEq)]
+pub(crate) struct Spilled {
+    pub accepted_at: u64,
+    pub client_id: ClientId,
+    pub record: TransactionRecord,
+}
+
+fn offset(transaction_id: TransactionId) -> u64 {
+    u64::from(transaction_id.0) * RECORD_SIZE as u64
+}
+
+fn encode(
+    accepted_at: u64,
+    owner: ClientId,
+    record: &TransactionRecord,
+) -> Option<[u8; RECORD_SIZE]> {
+    let (kind, to, currency, status, amount, disputed, charged_back, resolved, expires_at) =
+        match record {
+            TransactionRecord::Deposit(funds) => (
+                DEPOSIT,
+                ClientId(0),
+                funds.currency,
+                dispute_status(funds.status),
+                funds.amount,
+                funds.disputed(),
+                funds.charged_back(),
+                funds.resolved(),
+                0,
+            ),
+            TransactionRecord::Withdraw(funds) => (
+                WITHDRAW,
+                ClientId(0),
+                funds.currency,
+                dispute_status(funds.status),
+                funds.amount,
+                funds.disputed(),
+                funds.charged_back(),
+                funds.resolved(),
+                0,
+            ),
+            TransactionRecord::Transfer { to, funds } => (
+                TRANSFER,
+                *to,
+                funds.currency,
+                dispute_status(funds.status),
+                funds.amount,
+                funds.disputed(),
+                funds.charged_back(),
+                funds.resolved(),
+                0,
+            ),
+            TransactionRecord::Authorize(authorization) => (
+                AUTHORIZE,
+                ClientId(0),
+                authorization.currency,
+                authorization_status(authorization.status),
+                authorization.amount,
+                authorization.captured,
+                Amount::ZERO,
+                Amount::ZERO,
+                authorization.expires_at,
+            ),
+            TransactionRecord::ReceivedTransfer { .. }
+            | TransactionRecord::Unlock { .. }
+            | TransactionRecord::CreditLimit { .. } => return None,
+        };
+
+    let mut record = [0; RECORD_SIZE];
+    record[0] = kind;
+    record[1..3].copy_from_slice(&owner.0.to_le_bytes());
+    record[3..5].copy_from_slice(&to.0.to_le_bytes());
+    record[5..8].copy_from_slice(currency.code().as_bytes());
+    record[8] = status;
+    record[9..25].copy_from_slice(&amount.0.serialize());
+    record[25..41].copy_from_slice(&disputed.0.serialize());
+    record[41..57].copy_from_slice(&charged_back.0.serialize());
+    record[57..73].copy_from_slice(&resolved.0.serialize());
+    record[73..81].copy_from_slice(&accepted_at.to_le_bytes());
+    record[81..89].copy_from_slice(&expires_at.to_le_bytes());
+    Some(record)
+}
+
+fn decode(
+    transaction_id: TransactionId,
+    record: &[u8; RECORD_SIZE],
+) -> Result<Option<Spilled>, String> {
+    if record[0] == 0 {
+        return Ok(None);
+    }
+    let client = |at: usize| ClientId(u16::from_le_bytes([record[at], record[at + 1]]));
+    let amount = |at: usize| {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&record[at..at + 16]);
+        Amount(Decimal::deserialize(bytes))
+    };
+    let number = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&record[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let currency: Currency = std::str::from_utf8(&record[5..8])
+        .ok()
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("bad currency in spilled transaction {}", transaction_id.0))?;
+    let bad_status = || format!("bad status in spilled transaction {}", transaction_id.0);
+    let funds = || -> Result<Disputable, String> {
+        Ok(Disputable::with_amounts(
+            amount(9),
+            currency,
+            parse_dispute_status(record[8]).ok_or_else(bad_status)?,
+            amount(25),
+            amount(41),
+            amount(57),
+        ))
+    };
+
+    let transaction = match record[0] {
+        DEPOSIT => TransactionRecord::Deposit(funds()?),
+        WITHDRAW => TransactionRecord::Withdraw(funds()?),
+        TRANSFER => TransactionRecord::Transfer {
+            to: client(3),
+            funds: funds()?,
+        },
+        AUTHORIZE => TransactionRecord::Authorize(Box::new(Authorization {
+            amount: amount(9),
+            currency,
+            status: parse_authorization_status(record[8]).ok_or_else(bad_status)?,
+            captured: amount(25),
+            expires_at: number(81),
+        })),
+        kind => {
+            return Err(format!(
+                "unknown kind {} of spilled transaction {}",
+                kind, transaction_id.0
+            ))
+        }
+    };
+    Ok(Some(Spilled {
+        accepted_at: number(73),
+        client_id: client(1),
+        record: transaction,
+    }))
+}
+
+fn dispute_status(status: DisputeStatus) -> u8 {
+    match status {
+        DisputeStatus::NotDisputed => 0,
+        DisputeStatus::Disputed => 1,
+        DisputeStatus::Resolved => 2,
+        DisputeStatus::Chargebacked => 3,
+    }
+}
+
+fn parse_dispute_status(status: u8) -> Option<DisputeStatus> {
+    match status {
+        0 => Some(DisputeStatus::NotDisputed),
+        1 => Some(DisputeStatus::Disputed),
+        2 => Some(DisputeStatus::Resolved),
+        3 => Some(DisputeStatus::Chargebacked),
+        _ => None,
+    }
+}
+
+fn authorization_status(status: AuthorizationStatus) -> u8 {
+    match status {
+        AuthorizationStatus::Open => 0,
+        AuthorizationStatus::Captured => 1,
+        AuthorizationStatus::Voided => 2,
+        AuthorizationStatus::Expired => 3,
+    }
+}
+
+fn parse_authorization_status(status: u8) -> Option<AuthorizationStatus> {
+    match status {
+        0 => Some(AuthorizationStatus::Open),
+        1 => Some(AuthorizationStatus::Captured),
+        2 => Some(AuthorizationStatus::Voided),
+        3 => Some(AuthorizationStatus::Expired),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::fs;
+
+    #[test]
+    fn transaction_ids() {
+        let mut ids = TransactionIds::default();
+
+        assert!(ids.insert(TransactionId(1)));
+        assert!(!ids.insert(TransactionId(1)));
+        assert!(ids.insert(TransactionId(u32::MAX)));
+
+        assert!(ids.contains(TransactionId(1)));
+        assert!(ids.contains(TransactionId(u32::MAX)));
+        assert!(!ids.contains(TransactionId(2)));
+        assert!(!ids.contains(TransactionId(1 + CHUNK_IDS)));
+    }
+
+    #[test]
+    fn spill_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "payments_engine_{}_round_trip.spill",
+            std::process::id()
+        ));
+        let mut spill = SpillFile::create(&path).expect("spill error");
+        let transfer = TransactionRecord::Transfer {
+            to: ClientId(65535),
+            funds: Disputable::with_amounts(
+                Amount(dec!(10.5)),
+                Currency::EUR,
+                DisputeStatus::Resolved,
+                Amount::ZERO,
+                Amount(dec!(0.25)),
+                Amount(dec!(10.25)),
+            ),
+        };
+        let authorize = TransactionRecord::Authorize(Box::new(Authorization {
+            amount: Amount(dec!(5)),
+            currency: Currency::XXX,
+            status: AuthorizationStatus::Captured,
+            captured: Amount(dec!(3)),
+            expires_at: 42,
+        }));
+
+        assert_eq!(
+            spill
+                .write(10, ClientId(1), TransactionId(7), &transfer)
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(
+            spill
+                .write(11, ClientId(2), TransactionId(3), &authorize)
+                .ok(),
+            Some(true)
+        );
+
+        assert_eq!(
+            spill.read(TransactionId(7)).ok(),
+            Some(Some(Spilled {
+                accepted_at: 10,
+                client_id: ClientId(1),
+                record: transfer,
+            }))
+        );
+        assert_eq!(
+            spill.read(TransactionId(3)).ok(),
+            Some(Some(Spilled {
+                accepted_at: 11,
+                client_id: ClientId(2),
+                record: authorize,
+            }))
+        );
+        assert_eq!(spill.read(TransactionId(5)).ok(), Some(None));
+        assert_eq!(spill.read(TransactionId(8)).ok(), Some(None));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{Amount, ClientId, Currency, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Account {
+    Available(ClientId),
+    Held(ClientId),
+    Reserved(ClientId),
+    // what a client owes for disputes held on credit, negative like
+    // Settlement as the money went out to the client
+    Receivable(ClientId),
+    // suspense account for the difference between the amounts clients gave
+    // and what was applied after rounding
+    Rounding(ClientId),
+    // money coming from and going to the outside world, deposits make it
+    // negative and withdrawals bring it back
+    Settlement,
+    // refunds of disputed withdrawals paid out of our own pocket
+    ChargebackLoss,
+    // fees charged to clients
+    House,
+}
+
+impl Account {
+    // the client owning the account, None for the engine's own accounts
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self {
+            Account::Available(client_id)
+            | Account::Held(client_id)
+            | Account::Reserved(client_id)
+            | Account::Receivable(client_id)
+            | Account::Rounding(client_id) => Some(*client_id),
+            Account::Settlement | Account::ChargebackLoss | Account::House => None,
+        }
+    }
+}
+
+// Moves an amount from one account to another. Every entry has exactly one
+// side leaving an account and one entering another, so the ledger as a whole
+// can't create or destroy money. A transaction may post several entries, all
+// sharing its sequence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    pub currency: Currency,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Amount,
+}
+
+// Every entry ever posted, oldest first, with the running balance of every
+// account so the trial balance doesn't sum the whole journal each time.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ledger {
+    entries: Vec<JournalEntry>,
+    balances: TrialBalance,
+    // set once a balance went past what an amount can hold, the trial
+    // balance can't be trusted after that
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    overflowed: bool,
+    // where the entries of the latest transaction start
+    #[serde(skip)]
+    latest: usize,
+}
+
+impl Ledger {
+    pub(crate) fn from_entries(entries: Vec<JournalEntry>) -> Ledger {
+        let mut ledger = Ledger::default();
+        for entry in entries {
+            ledger.post(
+                entry.sequence,
+                entry.currency,
+                entry.from,
+                entry.to,
+                entry.amount,
+            );
+        }
+        ledger.start_transaction();
+        ledger
+    }
+
+    pub(crate) fn post(
+        &mut self,
+        sequence: u64,
+        currency: Currency,
+        from: Account,
+        to: Account,
+        amount: Amount,
+    ) {
+        let entry = JournalEntry {
+            sequence,
+            currency,
+            from,
+            to,
+            amount,
+        };
+        if self.balances.apply(&entry).is_err() {
+            self.overflowed = true;
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    // entries posted by the latest transaction
+    pub fn latest_entries(&self) -> &[JournalEntry] {
+        &self.entries[self.latest..]
+    }
+
+    pub(crate) fn start_transaction(&mut self) {
+        self.latest = self.entries.len();
+    }
+
+    // balance of an account as last posted, even after an overflow
+    pub fn balance(&self, account: Account, currency: Currency) -> Amount {
+        self.balances.balance(account, currency)
+    }
+
+    pub fn trial_balance(&self) -> Result<&TrialBalance, Error> {
+        if self.overflowed {
+            return Err(Error::AmountOverflow);
+        }
+        Ok(&self.balances)
+    }
+}
+
+// balance of every account that was ever posted to, per currency
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrialBalance(BTreeMap<(Account, Currency), Amount>);
+
+impl TrialBalance {
+    pub fn balance(&self, account: Account, currency: Currency) -> Amount {
+        self.0
+            .get(&(account, currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Account, Currency, Amount)> + '_ {
+        self.0
+            .iter()
+            .map(|((account, currency), amount)| (*account, *currency, *amount))
+    }
+
+    // sum of all the accounts in a currency, zero for a consistent ledger
+    pub fn total(&self, currency: Currency) -> Result<Amount, Error> {
+        self.iter()
+            .filter(|(_, account_currency, _)| *account_currency == currency)
+            .try_fold(Amount::ZERO, |total, (_, _, amount)| {
+                total.checked_add(amount)
+            })
+    }
+
+    // both sides or neither
+    fn apply(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+        let from = self
+            .balance(entry.from, entry.currency)
+            .checked_subtract(entry.amount)?;
+        let to = if entry.from == entry.to {
+            from.checked_add(entry.amount)?
+        } else {
+            self.balance(entry.to, entry.currency)
+                .checked_add(entry.amount)?
+        };
+        self.0.insert((entry.from, entry.currency), from);
+        self.0.insert((entry.to, entry.currency), to);
+        Ok(())
+    }
+}
+
+impl FromIterator<(Account, Currency, Amount)> for TrialBalance {
+    fn from_iter<I: IntoIterator<Item = (Account, Currency, Amount)>>(iter: I) -> Self {
+        TrialBalance(
+            iter.into_iter()
+                .map(|(account, currency, amount)| ((account, currency), amount))
+                .collect(),
+        )
+    }
+}
+
+// a list of (account, currency, amount), as JSON maps only take string keys
+impl Serialize for TrialBalance {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TrialBalance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(
+            Vec::<(Account, Currency, Amount)>::deserialize(deserializer)?
+                .into_iter()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn trial_balance_sums_to_zero() {
+        let mut ledger = Ledger::default();
+        let client = ClientId(1);
+
+        ledger.post(
+            1,
+            Currency::EUR,
+            Account::Settlement,
+            Account::Available(client),
+            Amount(Decimal::TEN),
+        );
+        ledger.post(
+            2,
+            Currency::EUR,
+            Account::Available(client),
+            Account::Held(client),
+            Amount(Decimal::TWO),
+        );
+
+        let trial_balance = ledger.trial_balance().expect("overflow");
+
+        assert_eq!(
+            trial_balance.balance(Account::Settlement, Currency::EUR),
+            Amount(-Decimal::TEN)
+        );
+        assert_eq!(
+            trial_balance.balance(Account::Available(client), Currency::EUR),
+            Amount(Decimal::from(8))
+        );
+        assert_eq!(
+            trial_balance.balance(Account::Held(client), Currency::EUR),
+            Amount(Decimal::TWO)
+        );
+        assert_eq!(trial_balance.total(Currency::EUR), Ok(Amount::ZERO));
+        assert_eq!(trial_balance.total(Currency::USD), Ok(Amount::ZERO));
+    }
+
+    #[test]
+    fn overflow_taints_the_trial_balance() {
+        let mut ledger = Ledger::default();
+        let client = ClientId(1);
+
+        for sequence in 1..=2 {
+            ledger.post(
+                sequence,
+                Currency::EUR,
+                Account::Settlement,
+                Account::Available(client),
+                Amount(Decimal::MAX),
+            );
+        }
+
+        assert_eq!(ledger.trial_balance(), Err(Error::AmountOverflow));
+        // the entry that overflowed wasn't applied to either side
+        assert_eq!(
+            ledger.balance(Account::Available(client), Currency::EUR),
+            Amount(Decimal::MAX)
+        );
+        assert_eq!(ledger.entries().len(), 2);
+    }
+
+    #[test]
+    fn journal_keeps_every_transaction() {
+        let mut ledger = Ledger::default();
+        let client = ClientId(1);
+
+        ledger.post(
+            1,
+            Currency::EUR,
+            Account::Settlement,
+            Account::Available(client),
+            Amount(Decimal::TEN),
+        );
+        ledger.start_transaction();
+        ledger.post(
+            2,
+            Currency::EUR,
+            Account::Available(client),
+            Account::Settlement,
+            Amount(Decimal::ONE),
+        );
+
+        assert_eq!(ledger.entries().len(), 2);
+        assert_eq!(ledger.latest_entries().len(), 1);
+        assert_eq!(ledger.latest_entries()[0].sequence, 2);
+
+        let replayed = Ledger::from_entries(ledger.entries().to_vec());
+        assert_eq!(replayed.latest_entries(), &[]);
+        assert_eq!(replayed.trial_balance(), ledger.trial_balance());
+    }
+}
@@ -1,330 +1,2125 @@
 #![allow(dead_code)]
 
+mod authorization;
+mod config;
+mod currency;
+mod dispute;
+mod event_log;
+mod fee;
+mod history;
+mod ledger;
+#[cfg(any(feature = "tokio", test))]
+mod service;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod wal;
+
+pub use authorization::{Authorization, AuthorizationStatus, Authorize, Capture, Void};
+pub use config::{
+    ConfigError, CreditLine, CurrencyLimits, EngineConfig, Rounding, ShortfallPolicy,
+    WithdrawalDisputePolicy,
+};
+pub use currency::Currency;
+pub use dispute::{Disputable, DisputeAction, DisputeEvent, DisputeParts, DisputeStatus};
+pub use event_log::{read_events, EventLog, EventLogError, EVENT_LOG_VERSION};
+pub use fee::{Fee, FeeKind, FeeLine, FeeSchedule};
+use history::Spilled;
+pub use history::{SpillFile, TransactionIds, TransactionRecord};
+pub use ledger::{Account, JournalEntry, Ledger, TrialBalance};
+#[cfg(any(feature = "tokio", test))]
+pub use service::{ClientBalances, EngineHandle, Reply};
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use store::{ClientStore, Commit, MemoryStore, StoreError, StoredTransaction};
+pub use wal::{InputFile, Recovery, SyncPolicy, WalError, WriteAheadLog, WAL_VERSION};
+
 use rust_decimal::Decimal;
-use rust_decimal::RoundingStrategy;
-use rust_decimal_macros::dec;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 //use std::ops::Add;
 //use std::ops::AddAssign;
+use serde::de::{self, Visitor};
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use std::fmt;
 use thiserror::Error;
 
-const MIN_DEPOSIT: Decimal = dec!(0.0001);
-const MAX_DEPOSIT: Decimal = dec!(50000);
-const MIN_WITHDRAW: Decimal = dec!(0.0001);
-const MAX_WITHDRAW: Decimal = dec!(50000);
-const DECIMAL_POINTS: u32 = 4;
-const ROUNDING_STRATEGY: RoundingStrategy = RoundingStrategy::MidpointNearestEven;
-
 #[derive(Debug)]
-pub struct PaymentsEngine {
-    pub client_list: HashMap<ClientId, Client>,
+pub struct PaymentsEngine<S: ClientStore = MemoryStore> {
+    client_list: S,
+    config: EngineConfig,
+    // every transaction id accepted so far, across all clients
+    transaction_ids: TransactionIds,
+    // owner of every transaction kept in memory
+    transaction_index: HashMap<TransactionId, ClientId>,
+    // transactions dropped from the history by the dispute window
+    evicted: TransactionIds,
+    // transactions that can leave the memory, by the sequence they were
+    // accepted at, only kept with a dispute window or a spill file
+    history: BTreeSet<(u64, TransactionId)>,
+    spill: Option<SpillFile>,
+    // number of transactions accepted so far
+    sequence: u64,
+    // every balance change, client balances must always agree with it
+    ledger: Ledger,
+    // open authorizations by the sequence they expire at
+    authorization_expiry: BTreeSet<(u64, TransactionId)>,
+    // credit limits from the config, by client and currency
+    credit_lines: HashMap<(ClientId, Currency), Amount>,
+    // fees collected, per currency
+    house: BTreeMap<Currency, Amount>,
+    // charged by the latest transaction, handed to the store with it
+    fee_lines: Vec<FeeLine>,
+    // records of client histories the latest transaction added, changed or
+    // dropped, for the store
+    changed: BTreeSet<(ClientId, TransactionId)>,
+    // set once a commit failed, the engine is ahead of its store from then on
+    // and refuses any further transaction
+    store_error: Option<String>,
+}
+
+impl Default for PaymentsEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PaymentsEngine {
+    pub fn new() -> PaymentsEngine {
+        PaymentsEngine {
+            client_list: MemoryStore::default(),
+            config: EngineConfig::default(),
+            transaction_ids: TransactionIds::default(),
+            transaction_index: HashMap::new(),
+            evicted: TransactionIds::default(),
+            history: BTreeSet::new(),
+            spill: None,
+            sequence: 0,
+            ledger: Ledger::default(),
+            authorization_expiry: BTreeSet::new(),
+            credit_lines: HashMap::new(),
+            house: BTreeMap::new(),
+            fee_lines: vec![],
+            changed: BTreeSet::new(),
+            store_error: None,
+        }
+    }
+
+    pub fn with_config(config: EngineConfig) -> Result<PaymentsEngine, ConfigError> {
+        PaymentsEngine::with_store(config, MemoryStore::default())
+    }
+}
+
+impl<S: ClientStore> PaymentsEngine<S> {
+    // Picks up where the engine that last committed to the store left off.
+    // What isn't kept in the store is rebuilt from its clients and journal.
+    pub fn with_store(
+        config: EngineConfig,
+        mut store: S,
+    ) -> Result<PaymentsEngine<S>, ConfigError> {
+        config.validate()?;
+        let credit_lines = config
+            .credit_lines
+            .iter()
+            .map(|line| {
+                let currency = line.currency.unwrap_or(config.default_currency);
+                (
+                    (line.client, currency),
+                    config.round(line.credit_limit, currency),
+                )
+            })
+            .collect();
+
+        let mut transaction_ids = store.take_transaction_ids();
+        let mut transaction_index = HashMap::new();
+        let mut authorization_expiry = BTreeSet::new();
+        let mut history = BTreeSet::new();
+        // the clock starts now for the history, if it's tracked now or once
+        // a spill file is given
+        let track = config.dispute_window.is_some() || config.spill_after.is_some();
+        for stored in store.take_transactions() {
+            transaction_ids.insert(stored.transaction_id);
+            // a transfer belongs to its sender
+            if stored.owned {
+                transaction_index.insert(stored.transaction_id, stored.client_id);
+            }
+            if let Some(expires_at) = stored.expires_at {
+                authorization_expiry.insert((expires_at, stored.transaction_id));
+            }
+            if stored.leaves_memory && track {
+                history.insert((store.sequence(), stored.transaction_id));
+            }
+        }
+        let ledger = Ledger::from_entries(store.take_journal());
+        let house = match ledger.trial_balance() {
+            Ok(trial_balance) => trial_balance
+                .iter()
+                .filter(|(account, _, _)| *account == Account::House)
+                .map(|(_, currency, amount)| (currency, amount))
+                .collect(),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(PaymentsEngine {
+            sequence: store.sequence(),
+            client_list: store,
+            config,
+            transaction_ids,
+            transaction_index,
+            evicted: TransactionIds::default(),
+            history,
+            spill: None,
+            ledger,
+            authorization_expiry,
+            credit_lines,
+            house,
+            fee_lines: vec![],
+            changed: BTreeSet::new(),
+            store_error: None,
+        })
+    }
+
+    // Moves transactions that can still be disputed, captured or voided to
+    // the spill file once they are older than the config's spill_after. The
+    // engine can't be saved to a snapshot after that.
+    pub fn spill_history(&mut self, spill: SpillFile) {
+        self.spill = Some(spill);
+        self.track_history();
+    }
+
+    fn tracks_history(&self) -> bool {
+        self.config.dispute_window.is_some()
+            || (self.spill.is_some() && self.config.spill_after.is_some())
+    }
+
+    // starts the clock for the transactions in memory that aren't tracked
+    // yet, like the ones of a store or snapshot
+    pub(crate) fn track_history(&mut self) {
+        if !self.tracks_history() {
+            return;
+        }
+        let tracked: HashSet<TransactionId> = self.history.iter().map(|(_, id)| *id).collect();
+        for client in self.client_list.values() {
+            for (transaction_id, record) in &client.transaction_list {
+                if record.leaves_memory() && !tracked.contains(transaction_id) {
+                    self.history.insert((self.sequence, *transaction_id));
+                }
+            }
+        }
+    }
+
+    // Brings every client of the store into memory, as a store may leave the
+    // ones no transaction needed yet out of client() and clients().
+    pub fn load_clients(&mut self) -> Result<(), Error> {
+        self.client_list
+            .load_all()
+            .map_err(|err| Error::Storage(err.to_string()))
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<&Client> {
+        self.client_list.get(&client_id)
+    }
+
+    // by client id
+    pub fn clients(&self) -> impl Iterator<Item = &Client> + '_ {
+        self.client_list.values()
+    }
+
+    pub fn store(&self) -> &S {
+        &self.client_list
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn trial_balance(&self) -> Result<&TrialBalance, Error> {
+        self.ledger.trial_balance()
+    }
+
+    // fees collected, per currency
+    pub fn house(&self) -> &BTreeMap<Currency, Amount> {
+        &self.house
+    }
+
+    // fees charged by the latest transaction, Client::fees lists them all
+    pub fn fee_lines(&self) -> &[FeeLine] {
+        &self.fee_lines
+    }
+
+    // rounding residue of all the clients together
+    pub fn rounding_residue(&self, currency: Currency) -> Result<Amount, Error> {
+        self.client_list
+            .values()
+            .try_fold(Amount::ZERO, |total, client| {
+                total.checked_add(client.rounding_residue(currency))
+            })
+    }
+
+    // how far below zero the client's available funds in a currency may go,
+    // a limit set by an admin transaction wins over the config
+    pub fn credit_limit(&self, client_id: ClientId, currency: Currency) -> Amount {
+        self.client_list
+            .get(&client_id)
+            .and_then(|client| client.credit_limits.get(&currency))
+            .or_else(|| self.credit_lines.get(&(client_id, currency)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // checks every client balance against its ledger accounts
+    pub fn reconcile(&self) -> Result<(), Error> {
+        let trial_balance = self.ledger.trial_balance()?;
+        for client in self.client_list.values() {
+            let client_id = &client.client_id;
+            for (currency, balance) in &client.balances {
+                if balance.available
+                    != trial_balance.balance(Account::Available(*client_id), *currency)
+                    || balance.held != trial_balance.balance(Account::Held(*client_id), *currency)
+                    || balance.reserved
+                        != trial_balance.balance(Account::Reserved(*client_id), *currency)
+                    || Amount(-balance.receivable.0)
+                        != trial_balance.balance(Account::Receivable(*client_id), *currency)
+                {
+                    return Err(Error::LedgerMismatch(*client_id));
+                }
+            }
+            for (currency, residue) in &client.rounding_residue {
+                if *residue != trial_balance.balance(Account::Rounding(*client_id), *currency) {
+                    return Err(Error::LedgerMismatch(*client_id));
+                }
+            }
+        }
+        for (currency, amount) in &self.house {
+            if *amount != trial_balance.balance(Account::House, *currency) {
+                return Err(Error::HouseMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    // Every call ends with a commit to the store, even if the transaction was
+    // rejected, as expiring authorizations may have changed clients. Once a
+    // commit fails the transaction is applied in memory only, so every later
+    // call fails as well rather than build on it.
     pub fn recv_tx(&mut self, transaction: Transaction) -> Result<(), Error> {
+        if let Some(err) = &self.store_error {
+            return Err(Error::Storage(err.clone()));
+        }
+        let transaction_id = transaction.transaction_id();
+        self.ledger.start_transaction();
+        self.fee_lines.clear();
+        self.changed.clear();
+        let result = self.process_tx(transaction);
+        if let Err(err) = self.client_list.commit(Commit {
+            sequence: self.sequence,
+            ledger: &self.ledger,
+            fee_lines: &self.fee_lines,
+            transactions: &self.changed,
+            transaction_id: transaction_id.filter(|_| result.is_ok()),
+        }) {
+            let err = err.to_string();
+            self.store_error = Some(err.clone());
+            return Err(Error::Storage(err));
+        }
+        result
+    }
+
+    // brings a client of the store into memory before it's looked up
+    fn load_client(&mut self, client_id: ClientId) -> Result<(), Error> {
+        self.client_list
+            .load(&client_id)
+            .map_err(|err| Error::Storage(err.to_string()))
+    }
+
+    fn process_tx(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.expire_authorizations()?;
+        self.retire_history()?;
+
+        let client_id = transaction.client_id();
+        let new_transaction_id = transaction.transaction_id();
+
+        if let Some(transaction_id) = new_transaction_id {
+            if self.transaction_ids.contains(transaction_id) {
+                return Err(Error::DuplicateTransaction);
+            }
+        }
+
+        if let Some(target_transaction_id) = transaction.target_transaction_id() {
+            self.restore_spilled(target_transaction_id)?;
+            if self.evicted.contains(target_transaction_id) {
+                return Err(Error::DisputeWindowClosed);
+            }
+            match self.transaction_index.get(&target_transaction_id) {
+                Some(owner) if *owner != client_id => return Err(Error::TransactionClientMismatch),
+                _ => (),
+            }
+        }
+
+        self.load_client(client_id)?;
+        if let Transaction::Transfer(transfer) = &transaction {
+            self.load_client(transfer.to)?;
+        }
+        let target_transaction_id = transaction.target_transaction_id();
+        self.apply_tx(transaction)?;
+        self.sequence += 1;
+
+        if let Some(target_transaction_id) = target_transaction_id {
+            self.changed.insert((client_id, target_transaction_id));
+        }
+        if let Some(transaction_id) = new_transaction_id {
+            self.transaction_ids.insert(transaction_id);
+            // only the transactions something can refer to later are kept
+            let kept = self
+                .client_list
+                .get(&client_id)
+                .and_then(|client| client.transaction_list.get(&transaction_id));
+            if let Some(record) = kept {
+                self.changed.insert((client_id, transaction_id));
+                if let TransactionRecord::Transfer { to, .. } = record {
+                    self.changed.insert((*to, transaction_id));
+                }
+                self.transaction_index.insert(transaction_id, client_id);
+                if record.leaves_memory() && self.tracks_history() {
+                    self.history.insert((self.sequence, transaction_id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_tx(&mut self, transaction: Transaction) -> Result<(), Error> {
         match transaction {
             Transaction::Deposit(deposit) => {
-                let amount = Amount::check_and_round_deposit(deposit.amount)?;
-                self.client_list
-                    .entry(deposit.client_id)
-                    .and_modify(|client| {
-                        client.available = client.available.checked_add(amount);
-                        client
-                            .transaction_list
-                            .insert(deposit.transaction_id, Transaction::Deposit(deposit));
-                    })
-                    .or_insert(Client::new_with_deposit(deposit));
-                Ok(())
+                let amount = Amount::check_and_round_deposit(
+                    deposit.amount,
+                    deposit.currency,
+                    &self.config,
+                )?;
+                // what was sent but not credited
+                let residue = deposit.amount.checked_subtract(amount)?;
+                let deposit = Deposit::new(
+                    deposit.transaction_id,
+                    deposit.client_id,
+                    amount,
+                    deposit.currency,
+                );
+                match self.client_list.get_mut(&deposit.client_id) {
+                    Some(client) => {
+                        let mut balance = client.balance(deposit.currency);
+                        // a locked client can still pay back what it owes
+                        if client.locked && !balance.owes() {
+                            return Err(Error::ClientLocked);
+                        }
+                        balance.available = balance.available.checked_add(amount)?;
+                        let recovered = balance.recover()?;
+                        client.balances.insert(deposit.currency, balance);
+                        client.transaction_list.insert(
+                            deposit.transaction_id,
+                            TransactionRecord::Deposit(Disputable::new(amount, deposit.currency)),
+                        );
+                        self.ledger.post(
+                            self.sequence + 1,
+                            deposit.currency,
+                            Account::Settlement,
+                            Account::Available(deposit.client_id),
+                            amount,
+                        );
+                        post_recovery(
+                            &mut self.ledger,
+                            self.sequence + 1,
+                            deposit.client_id,
+                            deposit.currency,
+                            recovered,
+                        );
+                    }
+                    None => {
+                        self.client_list
+                            .insert(deposit.client_id, Client::new_with_deposit(deposit));
+                        self.ledger.post(
+                            self.sequence + 1,
+                            deposit.currency,
+                            Account::Settlement,
+                            Account::Available(deposit.client_id),
+                            amount,
+                        );
+                    }
+                }
+                match self.client_list.get_mut(&deposit.client_id) {
+                    Some(client) => record_rounding(
+                        client,
+                        &mut self.ledger,
+                        self.sequence + 1,
+                        deposit.currency,
+                        residue,
+                    ),
+                    None => Ok(()),
+                }
             }
 
             Transaction::Withdraw(withdraw) => {
-                let amount = Amount::check_and_round_withdraw(withdraw.amount)?;
+                let amount = Amount::check_and_round_withdraw(
+                    withdraw.amount,
+                    withdraw.currency,
+                    &self.config,
+                )?;
+                // what was debited beyond the requested amount
+                let residue = amount.checked_subtract(withdraw.amount)?;
+                let fee = self
+                    .config
+                    .fee(FeeKind::Withdrawal, amount, withdraw.currency)?;
+                let credit_limit = self.credit_limit(withdraw.client_id, withdraw.currency);
                 let client = self
                     .client_list
                     .get_mut(&withdraw.client_id)
-                    .expect("client id doesn't exist");
-                if client.available < amount {
-                    return Err(Error::WithdrawMoreThanAvailable);
+                    .ok_or(Error::NonExistingClient)?;
+                if client.locked {
+                    return Err(Error::ClientLocked);
                 }
-                client.available = client.available.checked_subtract(amount);
-                client
-                    .transaction_list
-                    .insert(withdraw.transaction_id, Transaction::Withdraw(withdraw));
+                // the fee has to fit in the funds as well
+                client.check_funds(withdraw.currency, amount.checked_add(fee)?, credit_limit)?;
+                let balance = client.balances.entry(withdraw.currency).or_default();
+                balance.available = balance
+                    .available
+                    .checked_subtract(amount)?
+                    .checked_subtract(fee)?;
+                // kept only if it can be disputed
+                if self.config.withdrawal_disputes == WithdrawalDisputePolicy::Allow {
+                    client.transaction_list.insert(
+                        withdraw.transaction_id,
+                        TransactionRecord::Withdraw(Disputable::new(amount, withdraw.currency)),
+                    );
+                }
+                self.ledger.post(
+                    self.sequence + 1,
+                    withdraw.currency,
+                    Account::Available(withdraw.client_id),
+                    Account::Settlement,
+                    amount,
+                );
+                record_rounding(
+                    client,
+                    &mut self.ledger,
+                    self.sequence + 1,
+                    withdraw.currency,
+                    residue,
+                )?;
+                collect_fee(
+                    client,
+                    &mut self.house,
+                    &mut self.ledger,
+                    &mut self.fee_lines,
+                    FeeLine {
+                        sequence: self.sequence + 1,
+                        client_id: withdraw.client_id,
+                        kind: FeeKind::Withdrawal,
+                        transaction_id: withdraw.transaction_id,
+                        currency: withdraw.currency,
+                        amount: fee,
+                    },
+                    Amount::ZERO,
+                )
+            }
+
+            Transaction::Dispute(_) | Transaction::Resolve(_) | Transaction::Chargeback(_) => {
+                self.apply_dispute(transaction)
+            }
+
+            Transaction::Transfer(transfer) => {
+                if transfer.from == transfer.to {
+                    return Err(Error::TransferToSelf);
+                }
+                // the amount leaves one client and enters another, so it has
+                // to fit both the withdrawal and the deposit limits
+                let amount = Amount::check_and_round_withdraw(
+                    transfer.amount,
+                    transfer.currency,
+                    &self.config,
+                )?;
+                let amount =
+                    Amount::check_and_round_deposit(amount, transfer.currency, &self.config)?;
+                let sender = self
+                    .client_list
+                    .get(&transfer.from)
+                    .ok_or(Error::NonExistingClient)?;
+                let recipient = self
+                    .client_list
+                    .get(&transfer.to)
+                    .ok_or(Error::NonExistingClient)?;
+                if sender.locked || recipient.locked {
+                    return Err(Error::ClientLocked);
+                }
+                sender.check_funds(
+                    transfer.currency,
+                    amount,
+                    self.credit_limit(transfer.from, transfer.currency),
+                )?;
+                let sender_available = sender
+                    .balance(transfer.currency)
+                    .available
+                    .checked_subtract(amount)?;
+                let recipient_available = recipient
+                    .balance(transfer.currency)
+                    .available
+                    .checked_add(amount)?;
+                // what the sender paid beyond the requested amount, which is
+                // what the recipient got on top of it
+                let residue = amount.checked_subtract(transfer.amount)?;
+                let sender_rounding = RoundingEntry::new(sender, transfer.currency, residue)?;
+                let recipient_rounding =
+                    RoundingEntry::new(recipient, transfer.currency, Amount(-residue.0))?;
+
+                // nothing past this point can fail, so either both sides are
+                // updated or none is
+                if let Some(sender) = self.client_list.get_mut(&transfer.from) {
+                    sender
+                        .balances
+                        .entry(transfer.currency)
+                        .or_default()
+                        .available = sender_available;
+                    sender.transaction_list.insert(
+                        transfer.transaction_id,
+                        TransactionRecord::Transfer {
+                            to: transfer.to,
+                            funds: Disputable::new(amount, transfer.currency),
+                        },
+                    );
+                    sender_rounding.record(sender, &mut self.ledger, self.sequence + 1);
+                }
+                if let Some(recipient) = self.client_list.get_mut(&transfer.to) {
+                    recipient
+                        .balances
+                        .entry(transfer.currency)
+                        .or_default()
+                        .available = recipient_available;
+                    recipient.transaction_list.insert(
+                        transfer.transaction_id,
+                        TransactionRecord::ReceivedTransfer {
+                            from: transfer.from,
+                            amount,
+                            currency: transfer.currency,
+                        },
+                    );
+                    recipient_rounding.record(recipient, &mut self.ledger, self.sequence + 1);
+                }
+                self.ledger.post(
+                    self.sequence + 1,
+                    transfer.currency,
+                    Account::Available(transfer.from),
+                    Account::Available(transfer.to),
+                    amount,
+                );
                 Ok(())
             }
 
-            Transaction::Dispute(dispute) => {
+            Transaction::Authorize(authorize) => {
+                let amount = Amount::check_and_round_withdraw(
+                    authorize.amount,
+                    authorize.currency,
+                    &self.config,
+                )?;
+                // what was reserved beyond the requested amount
+                let residue = amount.checked_subtract(authorize.amount)?;
+                let credit_limit = self.credit_limit(authorize.client_id, authorize.currency);
                 let client = self
                     .client_list
-                    .get_mut(&dispute.client_id)
-                    .expect("client id doesn't exist");
-                let target_transaction = client
-                    .transaction_list
-                    .get_mut(&dispute.target_transaction_id);
-                match target_transaction {
-                    Some(Transaction::Deposit(target)) => {
-                        if target.dispute_status == DisputeStatus::NotDisputed {
-                            let amount = target.amount;
-                            client.available = client.available.checked_subtract(amount);
-                            client.held = client.held.checked_add(amount);
-                            target.dispute_status = DisputeStatus::Disputed;
-                            Ok(())
-                        } else {
-                            Err(Error::DepositTwiceDisputed)
-                        }
-                    }
-                    _ => Err(Error::DisputeError),
+                    .get_mut(&authorize.client_id)
+                    .ok_or(Error::NonExistingClient)?;
+                if client.locked {
+                    return Err(Error::ClientLocked);
                 }
+                client.check_funds(authorize.currency, amount, credit_limit)?;
+                let balance = client.balances.entry(authorize.currency).or_default();
+                let available = balance.available.checked_subtract(amount)?;
+                let reserved = balance.reserved.checked_add(amount)?;
+                balance.available = available;
+                balance.reserved = reserved;
+                let expires_at =
+                    (self.sequence + 1).saturating_add(self.config.authorization_lifetime);
+                client.transaction_list.insert(
+                    authorize.transaction_id,
+                    TransactionRecord::Authorize(Box::new(Authorization {
+                        amount,
+                        currency: authorize.currency,
+                        status: AuthorizationStatus::Open,
+                        captured: Amount::ZERO,
+                        expires_at,
+                    })),
+                );
+                self.authorization_expiry
+                    .insert((expires_at, authorize.transaction_id));
+                self.ledger.post(
+                    self.sequence + 1,
+                    authorize.currency,
+                    Account::Available(authorize.client_id),
+                    Account::Reserved(authorize.client_id),
+                    amount,
+                );
+                record_rounding(
+                    client,
+                    &mut self.ledger,
+                    self.sequence + 1,
+                    authorize.currency,
+                    residue,
+                )
             }
 
-            Transaction::Resolve(resolve) => {
+            Transaction::Capture(capture) => {
                 let client = self
                     .client_list
-                    .get_mut(&resolve.client_id)
-                    .expect("client id doesn't exist");
-                let target_transaction = client
+                    .get_mut(&capture.client_id)
+                    .ok_or(Error::NonExistingClient)?;
+                let authorize = match client
                     .transaction_list
-                    .get_mut(&resolve.target_transaction_id);
-                match target_transaction {
-                    Some(Transaction::Deposit(target)) => {
-                        if target.dispute_status == DisputeStatus::Disputed {
-                            let amount = target.amount;
-                            client.available = client.available.checked_add(amount);
-                            client.held = client.held.checked_subtract(amount);
-                            target.dispute_status = DisputeStatus::Resolved;
-                            Ok(())
-                        } else {
-                            Err(Error::DepositTwiceDisputed)
-                        }
-                    }
-                    _ => Err(Error::ResolveError),
+                    .get_mut(&capture.target_transaction_id)
+                {
+                    Some(TransactionRecord::Authorize(authorize)) => authorize,
+                    _ => return Err(Error::AuthorizationError),
+                };
+                authorize.check_open()?;
+                authorize.currency.check(capture.currency)?;
+                let amount = match capture.amount {
+                    Some(amount) => self.config.round_input(amount, authorize.currency)?,
+                    None => authorize.amount,
+                };
+                // what was captured beyond the requested amount
+                let residue = match capture.amount {
+                    Some(given) => amount.checked_subtract(given)?,
+                    None => Amount::ZERO,
+                };
+                if amount <= Amount::ZERO {
+                    return Err(Error::InvalidCaptureAmount);
+                }
+                if amount > authorize.amount {
+                    return Err(Error::CaptureMoreThanAuthorized);
                 }
+                let released = authorize.amount.checked_subtract(amount)?;
+                let balance = client.balances.entry(authorize.currency).or_default();
+                let reserved = balance.reserved.checked_subtract(authorize.amount)?;
+                let available = balance.available.checked_add(released)?;
+                balance.reserved = reserved;
+                balance.available = available;
+                authorize.captured = amount;
+                authorize.status = AuthorizationStatus::Captured;
+                self.authorization_expiry
+                    .remove(&(authorize.expires_at, capture.target_transaction_id));
+                self.ledger.post(
+                    self.sequence + 1,
+                    authorize.currency,
+                    Account::Reserved(capture.client_id),
+                    Account::Settlement,
+                    amount,
+                );
+                if released > Amount::ZERO {
+                    self.ledger.post(
+                        self.sequence + 1,
+                        authorize.currency,
+                        Account::Reserved(capture.client_id),
+                        Account::Available(capture.client_id),
+                        released,
+                    );
+                }
+                let currency = authorize.currency;
+                record_rounding(
+                    client,
+                    &mut self.ledger,
+                    self.sequence + 1,
+                    currency,
+                    residue,
+                )
             }
 
-            Transaction::Chargeback(chargeback) => {
+            Transaction::Void(void) => {
                 let client = self
                     .client_list
-                    .get_mut(&chargeback.client_id)
-                    .expect("client id doesn't exist");
-                let target_transaction = client
-                    .transaction_list
-                    .get_mut(&chargeback.target_transaction_id);
-                match target_transaction {
-                    Some(Transaction::Deposit(target)) => {
-                        if target.dispute_status == DisputeStatus::Disputed {
-                            let amount = target.amount;
-                            client.held = client.held.checked_subtract(amount);
-                            client.locked = true;
-                            target.dispute_status = DisputeStatus::Chargebacked;
-                            Ok(())
-                        } else {
-                            Err(Error::DepositTwiceDisputed)
-                        }
-                    }
-                    _ => Err(Error::ChargebackError),
+                    .get_mut(&void.client_id)
+                    .ok_or(Error::NonExistingClient)?;
+                let authorize = match client.transaction_list.get_mut(&void.target_transaction_id) {
+                    Some(TransactionRecord::Authorize(authorize)) => authorize,
+                    _ => return Err(Error::AuthorizationError),
+                };
+                authorize.check_open()?;
+                let balance = client.balances.entry(authorize.currency).or_default();
+                let reserved = balance.reserved.checked_subtract(authorize.amount)?;
+                let available = balance.available.checked_add(authorize.amount)?;
+                balance.reserved = reserved;
+                balance.available = available;
+                authorize.status = AuthorizationStatus::Voided;
+                self.authorization_expiry
+                    .remove(&(authorize.expires_at, void.target_transaction_id));
+                self.ledger.post(
+                    self.sequence + 1,
+                    authorize.currency,
+                    Account::Reserved(void.client_id),
+                    Account::Available(void.client_id),
+                    authorize.amount,
+                );
+                Ok(())
+            }
+
+            Transaction::Unlock(unlock) => {
+                if unlock.reason.trim().is_empty() {
+                    return Err(Error::MissingUnlockReason);
+                }
+                let client = self
+                    .client_list
+                    .get_mut(&unlock.client_id)
+                    .ok_or(Error::NonExistingClient)?;
+                if !client.locked {
+                    return Err(Error::ClientNotLocked);
+                }
+                client.locked = false;
+                client.transaction_list.insert(
+                    unlock.transaction_id,
+                    TransactionRecord::Unlock {
+                        reason: unlock.reason,
+                    },
+                );
+                Ok(())
+            }
+
+            Transaction::CreditLimit(credit_limit) => {
+                let limit = self
+                    .config
+                    .round_input(credit_limit.credit_limit, credit_limit.currency)?;
+                if limit < Amount::ZERO {
+                    return Err(Error::NegativeCreditLimit);
+                }
+                let client = self
+                    .client_list
+                    .get_mut(&credit_limit.client_id)
+                    .ok_or(Error::NonExistingClient)?;
+                // lowering a limit can't leave the client overdrawn beyond it
+                if client.balance(credit_limit.currency).headroom(limit)? < Amount::ZERO {
+                    return Err(Error::OverCreditLimit);
                 }
+                client.credit_limits.insert(credit_limit.currency, limit);
+                client.transaction_list.insert(
+                    credit_limit.transaction_id,
+                    TransactionRecord::CreditLimit {
+                        credit_limit: limit,
+                        currency: credit_limit.currency,
+                    },
+                );
+                Ok(())
             }
         }
     }
-}
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("transaction id doesn't exist")]
-    NonExistingTransaction,
+    // Releases the funds of the open authorizations that outlived their
+    // lifetime. Runs before every transaction, so expiry depends only on the
+    // number of accepted transactions and replays the same way. An
+    // authorization whose funds can't be released without overflowing stays
+    // open and is tried again before the next transaction.
+    fn expire_authorizations(&mut self) -> Result<(), Error> {
+        let mut retry = vec![];
+        while let Some(&(expires_at, transaction_id)) = self.authorization_expiry.first() {
+            if expires_at > self.sequence {
+                break;
+            }
+            if let Some(client_id) = self.transaction_index.get(&transaction_id).copied() {
+                self.load_client(client_id)?;
+            }
+            self.authorization_expiry.pop_first();
 
-    #[error("deposit amount is lower than minimum")]
-    DepositLessThanMin,
+            let client = match self
+                .transaction_index
+                .get(&transaction_id)
+                .and_then(|client_id| self.client_list.get_mut(client_id))
+            {
+                Some(client) => client,
+                None => continue,
+            };
+            let authorize = match client.transaction_list.get_mut(&transaction_id) {
+                Some(TransactionRecord::Authorize(authorize))
+                    if authorize.status == AuthorizationStatus::Open =>
+                {
+                    authorize
+                }
+                _ => continue,
+            };
+            let balance = client.balances.entry(authorize.currency).or_default();
+            let (reserved, available) = match (
+                balance.reserved.checked_subtract(authorize.amount),
+                balance.available.checked_add(authorize.amount),
+            ) {
+                (Ok(reserved), Ok(available)) => (reserved, available),
+                _ => {
+                    retry.push((self.sequence + 1, transaction_id));
+                    continue;
+                }
+            };
+            balance.reserved = reserved;
+            balance.available = available;
+            authorize.status = AuthorizationStatus::Expired;
+            // posted along with the entries of the transaction it runs before
+            self.ledger.post(
+                self.sequence + 1,
+                authorize.currency,
+                Account::Reserved(client.client_id),
+                Account::Available(client.client_id),
+                authorize.amount,
+            );
+            self.changed.insert((client.client_id, transaction_id));
+        }
+        self.authorization_expiry.extend(retry);
+        Ok(())
+    }
 
-    #[error("deposit amount is bigger than maximum")]
-    DepositMoreThanMax,
+    // Drops the transactions that left the dispute window from the history
+    // and moves the ones past spill_after to the spill file. A transaction
+    // under dispute or an open authorization stays in memory and is looked at
+    // again later. Runs before every transaction, like the expiry of
+    // authorizations.
+    fn retire_history(&mut self) -> Result<(), Error> {
+        let window = self.config.dispute_window;
+        let spill_after = self.spill.as_ref().and(self.config.spill_after);
+        let age = match (window, spill_after) {
+            (Some(window), Some(spill_after)) => window.min(spill_after),
+            (Some(age), None) | (None, Some(age)) => age,
+            (None, None) => return Ok(()),
+        };
 
-    #[error("withdraw amount is lower than minimum")]
-    WithdrawLessThanMin,
+        while let Some(&(accepted_at, transaction_id)) = self.history.first() {
+            if accepted_at.saturating_add(age) > self.sequence {
+                break;
+            }
+            // the owner, and the recipient of a transfer as its note leaves
+            // with it
+            if let Some(client_id) = self.transaction_index.get(&transaction_id).copied() {
+                self.load_client(client_id)?;
+                let recipient = match self
+                    .client_list
+                    .get(&client_id)
+                    .and_then(|client| client.transaction_list.get(&transaction_id))
+                {
+                    Some(TransactionRecord::Transfer { to, .. }) => Some(*to),
+                    _ => None,
+                };
+                if let Some(recipient) = recipient {
+                    self.load_client(recipient)?;
+                }
+            }
+            self.history.pop_first();
 
-    #[error("withdraw amount is bigger than maximum")]
-    WithdrawMoreThanMax,
+            let client = match self
+                .transaction_index
+                .get(&transaction_id)
+                .and_then(|client_id| self.client_list.get_mut(client_id))
+            {
+                Some(client) => client,
+                None => continue,
+            };
+            let record = match client.transaction_list.get(&transaction_id) {
+                Some(record) => record,
+                None => continue,
+            };
+            if record.is_open() {
+                self.history.insert((self.sequence, transaction_id));
+                continue;
+            }
 
-    #[error("withdraw amount is bigger than available amount")]
-    WithdrawMoreThanAvailable,
+            if window.is_some_and(|window| accepted_at.saturating_add(window) <= self.sequence) {
+                client.dispute_history.remove(&transaction_id);
+                self.evicted.insert(transaction_id);
+            } else if let Some(spill) = &mut self.spill {
+                spill
+                    .write(accepted_at, client.client_id, transaction_id, record)
+                    .map_err(|err| Error::Spill(err.to_string()))?;
+            } else {
+                continue;
+            }
+            let recipient = match client.transaction_list.remove(&transaction_id) {
+                Some(TransactionRecord::Transfer { to, .. }) => Some(to),
+                _ => None,
+            };
+            self.changed.insert((client.client_id, transaction_id));
+            self.transaction_index.remove(&transaction_id);
+            // the recipient's note of a transfer leaves with it
+            if let Some(recipient) = recipient.and_then(|to| self.client_list.get_mut(&to)) {
+                recipient.transaction_list.remove(&transaction_id);
+                self.changed.insert((recipient.client_id, transaction_id));
+            }
+        }
+        Ok(())
+    }
 
-    #[error("deposit is under dipuste")]
-    DepositTwiceDisputed,
+    // reads a spilled transaction back into its client's history, unless it
+    // left the dispute window in the meantime
+    fn restore_spilled(&mut self, transaction_id: TransactionId) -> Result<(), Error> {
+        let spill = match &mut self.spill {
+            Some(spill) if !self.transaction_index.contains_key(&transaction_id) => spill,
+            _ => return Ok(()),
+        };
+        let Spilled {
+            accepted_at,
+            client_id,
+            record,
+        } = match spill
+            .read(transaction_id)
+            .map_err(|err| Error::Spill(err.to_string()))?
+        {
+            Some(spilled) => spilled,
+            None => return Ok(()),
+        };
+        if self
+            .config
+            .dispute_window
+            .is_some_and(|window| accepted_at.saturating_add(window) <= self.sequence)
+        {
+            self.evicted.insert(transaction_id);
+            return Ok(());
+        }
+        self.load_client(client_id)?;
+        if let TransactionRecord::Transfer { to, funds } = &record {
+            self.load_client(*to)?;
+            if let Some(recipient) = self.client_list.get_mut(to) {
+                self.changed.insert((*to, transaction_id));
+                recipient.transaction_list.insert(
+                    transaction_id,
+                    TransactionRecord::ReceivedTransfer {
+                        from: client_id,
+                        amount: funds.amount,
+                        currency: funds.currency,
+                    },
+                );
+            }
+        }
+        if let Some(client) = self.client_list.get_mut(&client_id) {
+            client.transaction_list.insert(transaction_id, record);
+            self.changed.insert((client_id, transaction_id));
+            self.transaction_index.insert(transaction_id, client_id);
+            self.history.insert((accepted_at, transaction_id));
+        }
+        Ok(())
+    }
 
-    #[error("either a transaction is non deposit or transaction doesn't exist")]
-    DisputeError,
+    // Disputes, resolves or charges back a deposit, withdrawal or transfer
+    // of the client. The funds are held where they went: on the client for a
+    // deposit, on the recipient for a transfer, and a disputed withdrawal is
+    // provisionally credited back as held.
+    fn apply_dispute(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let (action, client_id, target_transaction_id, given, currency, target_error) =
+            match transaction {
+                Transaction::Dispute(dispute) => (
+                    DisputeAction::Dispute,
+                    dispute.client_id,
+                    dispute.target_transaction_id,
+                    dispute.amount,
+                    dispute.currency,
+                    Error::DisputeError,
+                ),
+                Transaction::Resolve(resolve) => (
+                    DisputeAction::Resolve,
+                    resolve.client_id,
+                    resolve.target_transaction_id,
+                    resolve.amount,
+                    resolve.currency,
+                    Error::ResolveError,
+                ),
+                Transaction::Chargeback(chargeback) => (
+                    DisputeAction::Chargeback,
+                    chargeback.client_id,
+                    chargeback.target_transaction_id,
+                    chargeback.amount,
+                    chargeback.currency,
+                    Error::ChargebackError,
+                ),
+                _ => return Err(Error::NonExistingTransaction),
+            };
 
-    #[error("either a transaction is non deposit or transaction doesn't exist")]
-    ResolveError,
+        let client = self
+            .client_list
+            .get(&client_id)
+            .ok_or(Error::NonExistingClient)?;
+        if action == DisputeAction::Dispute && client.locked && !self.config.dispute_locked_clients
+        {
+            return Err(Error::ClientLocked);
+        }
+        let (target, funds) = match client.transaction_list.get(&target_transaction_id) {
+            Some(TransactionRecord::Deposit(funds)) => (DisputeTarget::Deposit, funds),
+            Some(TransactionRecord::Withdraw(funds))
+                if self.config.withdrawal_disputes == WithdrawalDisputePolicy::Allow =>
+            {
+                (DisputeTarget::Withdraw, funds)
+            }
+            Some(TransactionRecord::Transfer { to, funds }) => {
+                (DisputeTarget::Transfer(*to), funds)
+            }
+            _ => return Err(target_error),
+        };
+        let from = funds.status;
+        let (to, amount) = funds.check(action, given, currency, &self.config)?;
+        let currency = funds.currency;
+        let mut settled = funds.clone();
+        settled.apply(action, to, amount)?;
 
-    #[error("either a transaction is non deposit or transaction doesn't exist")]
-    ChargebackError,
-}
+        let client_balance = client.balance(currency);
 
-#[derive(Debug, Eq, Hash, PartialEq, Copy, Clone, Serialize, Deserialize)]
-pub struct ClientId(pub u16);
+        let holder_id = match target {
+            DisputeTarget::Transfer(recipient) => recipient,
+            DisputeTarget::Deposit | DisputeTarget::Withdraw => client_id,
+        };
+        self.load_client(holder_id)?;
+        let mut balance = self
+            .client_list
+            .get(&holder_id)
+            .ok_or(Error::NonExistingClient)?
+            .balance(currency);
+        // part of a dispute held on credit, and of a receivable paid back
+        let mut on_credit = Amount::ZERO;
+        let mut recovered = Amount::ZERO;
+        // available funds of the client after a transfer is charged back
+        let mut refunded = None;
+        let (debit, credit) = match (action, target) {
+            (DisputeAction::Dispute, DisputeTarget::Withdraw) => {
+                balance.held = balance.held.checked_add(amount)?;
+                (Account::ChargebackLoss, Account::Held(holder_id))
+            }
+            (DisputeAction::Dispute, _) => {
+                on_credit = balance.hold(amount, self.config.dispute_shortfall)?;
+                (Account::Available(holder_id), Account::Held(holder_id))
+            }
+            // the withdrawal stands, the provisional credit is dropped
+            (DisputeAction::Resolve, DisputeTarget::Withdraw) => {
+                balance.held = balance.held.checked_subtract(amount)?;
+                (Account::Held(holder_id), Account::ChargebackLoss)
+            }
+            (DisputeAction::Resolve, _) => {
+                balance.available = balance.available.checked_add(amount)?;
+                balance.held = balance.held.checked_subtract(amount)?;
+                recovered = balance.recover()?;
+                (Account::Held(holder_id), Account::Available(holder_id))
+            }
+            (DisputeAction::Chargeback, DisputeTarget::Deposit) => {
+                balance.held = balance.held.checked_subtract(amount)?;
+                (Account::Held(holder_id), Account::Settlement)
+            }
+            // the withdrawal is refunded to the client, who is not at fault
+            // here so the account stays unlocked
+            (DisputeAction::Chargeback, DisputeTarget::Withdraw) => {
+                balance.held = balance.held.checked_subtract(amount)?;
+                balance.available = balance.available.checked_add(amount)?;
+                (Account::Held(holder_id), Account::Available(client_id))
+            }
+            (DisputeAction::Chargeback, DisputeTarget::Transfer(_)) => {
+                balance.held = balance.held.checked_subtract(amount)?;
+                refunded = Some(client_balance.available.checked_add(amount)?);
+                (Account::Held(holder_id), Account::Available(client_id))
+            }
+        };
+        // what rounding the given amount cost the client whose funds it
+        // moved, see record_rounding; a transfer charged back comes out of the
+        // recipient and into the sender
+        let rounded_by = match given {
+            Some(given) => amount.checked_subtract(given)?,
+            None => Amount::ZERO,
+        };
+        let (holder_residue, client_residue) = match (action, target) {
+            (DisputeAction::Resolve, _) | (DisputeAction::Chargeback, DisputeTarget::Withdraw) => {
+                (Amount(-rounded_by.0), Amount::ZERO)
+            }
+            (DisputeAction::Chargeback, DisputeTarget::Transfer(_)) => {
+                (rounded_by, Amount(-rounded_by.0))
+            }
+            _ => (rounded_by, Amount::ZERO),
+        };
+        let fee_kind = match action {
+            DisputeAction::Dispute => Some(FeeKind::Dispute),
+            DisputeAction::Chargeback => Some(FeeKind::Chargeback),
+            DisputeAction::Resolve => None,
+        };
+        let fee = match fee_kind {
+            Some(kind) => self.config.fee(kind, amount, currency)?,
+            None => Amount::ZERO,
+        };
+        // the fee comes out of what the client has left after the dispute,
+        // within its credit limit and under the same shortfall policy
+        let mut payer = if holder_id == client_id {
+            balance
+        } else {
+            Balance {
+                available: refunded.unwrap_or(client_balance.available),
+                ..client_balance
+            }
+        };
+        let fee_on_credit = payer.charge(
+            fee,
+            self.credit_limit(client_id, currency),
+            self.config.dispute_shortfall,
+        )?;
+        if holder_id == client_id {
+            balance = payer;
+        }
+        let held_from_available = amount.checked_subtract(on_credit)?;
+        // only a transfer charged back rounds for the client, whose recipient
+        // is the holder, so the two never add to the same residue
+        let holder_rounding = RoundingEntry::new(
+            self.client_list
+                .get(&holder_id)
+                .ok_or(Error::NonExistingClient)?,
+            currency,
+            holder_residue,
+        )?;
+        let client_rounding = RoundingEntry::new(
+            self.client_list
+                .get(&client_id)
+                .ok_or(Error::NonExistingClient)?,
+            currency,
+            client_residue,
+        )?;
+        let fee_collection = fee_kind
+            .map(|kind| {
+                FeeCollection::new(
+                    &self.house,
+                    FeeLine {
+                        sequence: self.sequence + 1,
+                        client_id,
+                        kind,
+                        transaction_id: target_transaction_id,
+                        currency,
+                        amount: fee,
+                    },
+                    fee_on_credit,
+                )
+            })
+            .transpose()?;
 
-#[derive(Debug, PartialEq)]
-pub struct Client {
-    pub client_id: ClientId,
-    pub available: Amount,
-    pub held: Amount,
-    pub locked: bool,
-    transaction_list: HashMap<TransactionId, Transaction>,
-}
+        // nothing past this point can fail, so the dispute is applied whole
+        // or not at all
+        if let Some(holder) = self.client_list.get_mut(&holder_id) {
+            holder.balances.insert(currency, balance);
+            if (action, target) == (DisputeAction::Chargeback, DisputeTarget::Deposit) {
+                holder.locked = true;
+            }
+            holder_rounding.record(holder, &mut self.ledger, self.sequence + 1);
+        }
+        if action == DisputeAction::Dispute && target != DisputeTarget::Withdraw {
+            post_hold(
+                &mut self.ledger,
+                self.sequence + 1,
+                holder_id,
+                currency,
+                held_from_available,
+                on_credit,
+            );
+        } else {
+            self.ledger
+                .post(self.sequence + 1, currency, debit, credit, amount);
+        }
+        post_recovery(
+            &mut self.ledger,
+            self.sequence + 1,
+            holder_id,
+            currency,
+            recovered,
+        );
 
-impl Client {
-    fn new_with_deposit(deposit: Deposit) -> Client {
-        let mut transaction_list = HashMap::new();
-        transaction_list.insert(deposit.transaction_id, Transaction::Deposit(deposit));
-        let amount = deposit
-            .amount
-            .0
-            .round_dp_with_strategy(DECIMAL_POINTS, ROUNDING_STRATEGY);
-        Client {
-            client_id: deposit.client_id,
-            available: Amount(amount),
-            held: Amount(Decimal::ZERO),
-            locked: false,
-            transaction_list,
+        if let Some(client) = self.client_list.get_mut(&client_id) {
+            if holder_id != client_id {
+                client.balances.insert(currency, payer);
+            }
+            let (status, parts) = (settled.status, settled.parts());
+            if let Some(funds) = client
+                .transaction_list
+                .get_mut(&target_transaction_id)
+                .and_then(TransactionRecord::funds_mut)
+            {
+                *funds = settled;
+            }
+            client_rounding.record(client, &mut self.ledger, self.sequence + 1);
+            client.record_dispute_event(
+                target_transaction_id,
+                DisputeEvent {
+                    sequence: self.sequence + 1,
+                    from,
+                    to: status,
+                    amount,
+                    parts,
+                    transaction,
+                },
+            );
+            if let Some(fee_collection) = fee_collection {
+                fee_collection.collect(
+                    client,
+                    &mut self.house,
+                    &mut self.ledger,
+                    &mut self.fee_lines,
+                );
+            }
         }
+        Ok(())
     }
 }
 
-#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Copy, Clone, Serialize, Deserialize)]
-pub struct Amount(pub Decimal);
+// where the funds of a disputed transaction went
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum DisputeTarget {
+    Deposit,
+    Withdraw,
+    // to the recipient, a chargeback gives them back to the sender
+    Transfer(ClientId),
+}
 
-impl Amount {
-    pub fn checked_add(self, rhs: Amount) -> Amount {
-        let checked_add_decimal = self.0.checked_add(rhs.0).expect("overflow");
-        Amount(checked_add_decimal)
-    }
+// Keeps what rounding an amount cost the client, negative when it was in
+// their favour: funds moved out beyond the amount given, or short of it moved
+// in. The outside world is on the other side, it dealt in the amount as given.
+fn record_rounding(
+    client: &mut Client,
+    ledger: &mut Ledger,
+    sequence: u64,
+    currency: Currency,
+    residue: Amount,
+) -> Result<(), Error> {
+    RoundingEntry::new(client, currency, residue)?.record(client, ledger, sequence);
+    Ok(())
+}
 
-    pub fn checked_subtract(self, rhs: Amount) -> Amount {
-        let checked_subtract_decimal = self.0.checked_sub(rhs.0).expect("overflow");
-        Amount(checked_subtract_decimal)
-    }
+// The residue of a client once some rounding is added, worked out before a
+// transaction changes anything so it can still be rejected if it overflows.
+struct RoundingEntry {
+    currency: Currency,
+    residue: Amount,
+    accumulated: Amount,
+}
 
-    fn check_and_round_deposit(amount: Amount) -> Result<Amount, Error> {
-        if amount.0 < MIN_DEPOSIT {
-            Err(Error::DepositLessThanMin)
-        } else if amount.0 > MAX_DEPOSIT {
-            Err(Error::DepositMoreThanMax)
-        } else {
-            Ok(Amount(
-                amount
-                    .0
-                    .round_dp_with_strategy(DECIMAL_POINTS, ROUNDING_STRATEGY),
-            ))
-        }
+impl RoundingEntry {
+    fn new(client: &Client, currency: Currency, residue: Amount) -> Result<RoundingEntry, Error> {
+        Ok(RoundingEntry {
+            currency,
+            residue,
+            accumulated: client.rounding_residue(currency).checked_add(residue)?,
+        })
     }
 
-    fn check_and_round_withdraw(amount: Amount) -> Result<Amount, Error> {
-        if amount.0 < MIN_WITHDRAW {
-            Err(Error::WithdrawLessThanMin)
-        } else if amount.0 > MAX_WITHDRAW {
-            Err(Error::WithdrawMoreThanMax)
+    fn record(self, client: &mut Client, ledger: &mut Ledger, sequence: u64) {
+        if self.residue == Amount::ZERO {
+            return;
+        }
+        client
+            .rounding_residue
+            .insert(self.currency, self.accumulated);
+        let account = Account::Rounding(client.client_id);
+        if self.residue > Amount::ZERO {
+            ledger.post(
+                sequence,
+                self.currency,
+                Account::Settlement,
+                account,
+                self.residue,
+            );
         } else {
-            Ok(Amount(
-                amount
-                    .0
-                    .round_dp_with_strategy(DECIMAL_POINTS, ROUNDING_STRATEGY),
-            ))
+            ledger.post(
+                sequence,
+                self.currency,
+                account,
+                Account::Settlement,
+                Amount(-self.residue.0),
+            );
         }
     }
 }
 
-/* TODO
-impl Add for Amount {
-    type Output = Amount;
+// Moves a fee already taken from the client's balance into the house
+// account, the part held on credit comes out of the client's receivable.
+fn collect_fee(
+    client: &mut Client,
+    house: &mut BTreeMap<Currency, Amount>,
+    ledger: &mut Ledger,
+    fee_lines: &mut Vec<FeeLine>,
+    line: FeeLine,
+    on_credit: Amount,
+) -> Result<(), Error> {
+    FeeCollection::new(house, line, on_credit)?.collect(client, house, ledger, fee_lines);
+    Ok(())
+}
 
-    fn add(self, other: Amount) -> Self::Output {
-        self.0 + other.0
+// what the house holds once a fee is collected, worked out like RoundingEntry
+struct FeeCollection {
+    line: FeeLine,
+    from_available: Amount,
+    on_credit: Amount,
+    collected: Amount,
+}
+
+impl FeeCollection {
+    fn new(
+        house: &BTreeMap<Currency, Amount>,
+        line: FeeLine,
+        on_credit: Amount,
+    ) -> Result<FeeCollection, Error> {
+        let collected = house.get(&line.currency).copied().unwrap_or_default();
+        Ok(FeeCollection {
+            line,
+            from_available: line.amount.checked_subtract(on_credit)?,
+            on_credit,
+            collected: collected.checked_add(line.amount)?,
+        })
+    }
+
+    fn collect(
+        self,
+        client: &mut Client,
+        house: &mut BTreeMap<Currency, Amount>,
+        ledger: &mut Ledger,
+        fee_lines: &mut Vec<FeeLine>,
+    ) {
+        let line = self.line;
+        if line.amount == Amount::ZERO {
+            return;
+        }
+        house.insert(line.currency, self.collected);
+        client.fees.push(line);
+        fee_lines.push(line);
+        if self.from_available > Amount::ZERO {
+            ledger.post(
+                line.sequence,
+                line.currency,
+                Account::Available(line.client_id),
+                Account::House,
+                self.from_available,
+            );
+        }
+        if self.on_credit > Amount::ZERO {
+            ledger.post(
+                line.sequence,
+                line.currency,
+                Account::Receivable(line.client_id),
+                Account::House,
+                self.on_credit,
+            );
+        }
     }
 }
 
-impl AddAssign for Amount {
-    fn add_assign(&mut self, other: Amount) {
-        *self.0 = *self.0 + other.0
+// the part of a held amount the client still had comes out of available,
+// the rest is held on credit
+fn post_hold(
+    ledger: &mut Ledger,
+    sequence: u64,
+    client_id: ClientId,
+    currency: Currency,
+    from_available: Amount,
+    on_credit: Amount,
+) {
+    if from_available > Amount::ZERO {
+        ledger.post(
+            sequence,
+            currency,
+            Account::Available(client_id),
+            Account::Held(client_id),
+            from_available,
+        );
+    }
+    if on_credit > Amount::ZERO {
+        ledger.post(
+            sequence,
+            currency,
+            Account::Receivable(client_id),
+            Account::Held(client_id),
+            on_credit,
+        );
     }
 }
-*/
 
-#[derive(Debug, Eq, Hash, PartialEq, Copy, Clone, Serialize, Deserialize)]
-pub struct TransactionId(pub u32);
+fn post_recovery(
+    ledger: &mut Ledger,
+    sequence: u64,
+    client_id: ClientId,
+    currency: Currency,
+    recovered: Amount,
+) {
+    if recovered > Amount::ZERO {
+        ledger.post(
+            sequence,
+            currency,
+            Account::Available(client_id),
+            Account::Receivable(client_id),
+            recovered,
+        );
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("transaction id doesn't exist")]
+    NonExistingTransaction,
+
+    #[error("client id doesn't exist")]
+    NonExistingClient,
+
+    #[error("amount overflow")]
+    AmountOverflow,
+
+    #[error("transaction id was already used")]
+    DuplicateTransaction,
+
+    #[error("transaction id belongs to another client")]
+    TransactionClientMismatch,
+
+    #[error("deposit amount is lower than minimum")]
+    DepositLessThanMin,
+
+    #[error("deposit amount is bigger than maximum")]
+    DepositMoreThanMax,
+
+    #[error("withdraw amount is lower than minimum")]
+    WithdrawLessThanMin,
+
+    #[error("withdraw amount is bigger than maximum")]
+    WithdrawMoreThanMax,
+
+    #[error("client account is locked")]
+    ClientLocked,
+
+    #[error("client account is not locked")]
+    ClientNotLocked,
+
+    #[error("unlock must give a reason")]
+    MissingUnlockReason,
+
+    #[error("unknown currency")]
+    UnknownCurrency,
+
+    #[error("no deposit and withdraw limits are configured for the currency")]
+    NoCurrencyLimits,
+
+    #[error("amount has more decimal points than its currency allows")]
+    ExcessPrecision,
+
+    #[error("transaction currency doesn't match the client's balance")]
+    CurrencyMismatch,
+
+    #[error("balances of client {0:?} don't match the ledger")]
+    LedgerMismatch(ClientId),
+
+    #[error("house account doesn't match the ledger")]
+    HouseMismatch,
+
+    #[error("client store error: {0}")]
+    Storage(String),
+
+    #[error("history spill file error: {0}")]
+    Spill(String),
+
+    #[error("transaction is past the dispute window")]
+    DisputeWindowClosed,
+
+    #[error("engine is no longer running")]
+    EngineStopped,
+
+    #[error("can't transfer to the same client")]
+    TransferToSelf,
+
+    #[error("either a transaction isn't an authorization or transaction doesn't exist")]
+    AuthorizationError,
+
+    #[error("authorization is already {0}")]
+    AuthorizationClosed(AuthorizationStatus),
+
+    #[error("capture amount must be positive")]
+    InvalidCaptureAmount,
+
+    #[error("capture amount is bigger than the authorized amount")]
+    CaptureMoreThanAuthorized,
+
+    #[error("withdraw amount is bigger than available amount")]
+    WithdrawMoreThanAvailable,
+
+    #[error("dispute amount is bigger than available amount")]
+    DisputeMoreThanAvailable,
+
+    #[error("fee is bigger than available amount")]
+    FeeMoreThanAvailable,
+
+    #[error("credit limit can't be negative")]
+    NegativeCreditLimit,
+
+    #[error("client is already overdrawn beyond the new credit limit")]
+    OverCreditLimit,
+
+    #[error("can't {action} a transaction that is {status}")]
+    InvalidDisputeTransition {
+        status: DisputeStatus,
+        action: DisputeAction,
+    },
+
+    #[error("dispute amount must be positive")]
+    InvalidDisputeAmount,
+
+    #[error("dispute amount is bigger than the undisputed amount")]
+    DisputeMoreThanAmount,
+
+    #[error("resolve or chargeback amount is bigger than the disputed amount")]
+    SettlementMoreThanDisputed,
+
+    #[error("either a transaction can't be disputed or transaction doesn't exist")]
+    DisputeError,
+
+    #[error("either a transaction can't be disputed or transaction doesn't exist")]
+    ResolveError,
+
+    #[error("either a transaction can't be disputed or transaction doesn't exist")]
+    ChargebackError,
+}
+
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Copy, Clone, Serialize, Deserialize)]
+pub struct ClientId(pub u16);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Client {
+    pub client_id: ClientId,
+    // one balance per currency the client ever deposited
+    pub balances: BTreeMap<Currency, Balance>,
+    pub locked: bool,
+    // set by admin transactions, replacing the config's credit lines
+    credit_limits: BTreeMap<Currency, Amount>,
+    // every fee charged, oldest first
+    fees: Vec<FeeLine>,
+    // accumulated rounding of deposits and withdrawals, see record_rounding
+    rounding_residue: BTreeMap<Currency, Amount>,
+    transaction_list: HashMap<TransactionId, TransactionRecord>,
+    dispute_history: HashMap<TransactionId, Vec<DisputeEvent>>,
+}
+
+impl Client {
+    // expects the deposit amount to be already checked and rounded
+    fn new_with_deposit(deposit: Deposit) -> Client {
+        let mut transaction_list = HashMap::new();
+        transaction_list.insert(
+            deposit.transaction_id,
+            TransactionRecord::Deposit(Disputable::new(deposit.amount, deposit.currency)),
+        );
+        Client {
+            client_id: deposit.client_id,
+            balances: BTreeMap::from([(
+                deposit.currency,
+                Balance {
+                    available: deposit.amount,
+                    held: Amount::ZERO,
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
+            locked: false,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
+            transaction_list,
+            dispute_history: HashMap::new(),
+        }
+    }
+
+    pub fn fees(&self) -> &[FeeLine] {
+        &self.fees
+    }
+
+    pub fn rounding_residue(&self, currency: Currency) -> Amount {
+        self.rounding_residue
+            .get(&currency)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    // Checks the client can spend the amount, available funds may go down to
+    // minus the credit limit. Without a credit line only currencies the client
+    // already holds can be spent.
+    fn check_funds(
+        &self,
+        currency: Currency,
+        amount: Amount,
+        credit_limit: Amount,
+    ) -> Result<(), Error> {
+        let balance = match self.balances.get(&currency) {
+            Some(balance) => *balance,
+            None if credit_limit > Amount::ZERO => Balance::default(),
+            None => return Err(Error::CurrencyMismatch),
+        };
+        if balance.headroom(credit_limit)? < amount {
+            Err(Error::WithdrawMoreThanAvailable)
+        } else {
+            Ok(())
+        }
+    }
+
+    // every dispute, resolve and chargeback applied to the given deposit or
+    // withdrawal, oldest first
+    pub fn dispute_history(&self, transaction_id: TransactionId) -> &[DisputeEvent] {
+        self.dispute_history
+            .get(&transaction_id)
+            .map_or(&[], |events| events.as_slice())
+    }
+
+    fn record_dispute_event(&mut self, transaction_id: TransactionId, event: DisputeEvent) {
+        self.dispute_history
+            .entry(transaction_id)
+            .or_default()
+            .push(event);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: Amount,
+    pub held: Amount,
+    // set aside by open authorizations
+    pub reserved: Amount,
+    // owed for disputes held on credit, see ShortfallPolicy::HoldAvailable
+    pub receivable: Amount,
+}
+
+impl Balance {
+    pub fn total(&self) -> Result<Amount, Error> {
+        self.available
+            .checked_add(self.held)?
+            .checked_add(self.reserved)?
+            .checked_subtract(self.receivable)
+    }
+
+    // Takes a fee out of available, going no further than the credit limit
+    // unless the policy allows it. Returns the part that is added to the
+    // receivable instead, zero unless the policy is HoldAvailable.
+    fn charge(
+        &mut self,
+        fee: Amount,
+        credit_limit: Amount,
+        policy: ShortfallPolicy,
+    ) -> Result<Amount, Error> {
+        let shortfall = fee
+            .checked_subtract(self.headroom(credit_limit)?.max(Amount::ZERO))?
+            .max(Amount::ZERO);
+        let on_credit = match policy {
+            ShortfallPolicy::AllowNegative => Amount::ZERO,
+            ShortfallPolicy::Reject if shortfall > Amount::ZERO => {
+                return Err(Error::FeeMoreThanAvailable)
+            }
+            ShortfallPolicy::Reject => Amount::ZERO,
+            ShortfallPolicy::HoldAvailable => shortfall,
+        };
+        self.available = self
+            .available
+            .checked_subtract(fee.checked_subtract(on_credit)?)?;
+        self.receivable = self.receivable.checked_add(on_credit)?;
+        Ok(on_credit)
+    }
+
+    // Moves a disputed amount from available to held. Returns the part held
+    // on credit because the client no longer had it, zero unless the policy
+    // is HoldAvailable.
+    fn hold(&mut self, amount: Amount, policy: ShortfallPolicy) -> Result<Amount, Error> {
+        let shortfall = amount
+            .checked_subtract(self.available.max(Amount::ZERO))?
+            .max(Amount::ZERO);
+        let on_credit = match policy {
+            ShortfallPolicy::AllowNegative => Amount::ZERO,
+            ShortfallPolicy::Reject if shortfall > Amount::ZERO => {
+                return Err(Error::DisputeMoreThanAvailable)
+            }
+            ShortfallPolicy::Reject => Amount::ZERO,
+            ShortfallPolicy::HoldAvailable => shortfall,
+        };
+        let available = self
+            .available
+            .checked_subtract(amount.checked_subtract(on_credit)?)?;
+        let held = self.held.checked_add(amount)?;
+        let receivable = self.receivable.checked_add(on_credit)?;
+        self.available = available;
+        self.held = held;
+        self.receivable = receivable;
+        Ok(on_credit)
+    }
+
+    // pays the receivable back out of available funds, returning how much
+    fn recover(&mut self) -> Result<Amount, Error> {
+        let recovered = self.available.max(Amount::ZERO).min(self.receivable);
+        let available = self.available.checked_subtract(recovered)?;
+        let receivable = self.receivable.checked_subtract(recovered)?;
+        self.available = available;
+        self.receivable = receivable;
+        Ok(recovered)
+    }
+
+    // a receivable, or available funds below zero
+    fn owes(&self) -> bool {
+        self.receivable > Amount::ZERO || self.available < Amount::ZERO
+    }
+
+    // what is left to spend, including what the credit limit allows
+    pub fn headroom(&self, credit_limit: Amount) -> Result<Amount, Error> {
+        self.available.checked_add(credit_limit)
+    }
+}
+
+#[derive(Debug, Default, Eq, Hash, PartialEq, PartialOrd, Ord, Copy, Clone, Serialize)]
+pub struct Amount(pub Decimal);
+
+// Takes whatever the format holds, a string or a JSON number. Text is parsed
+// exactly, see deserialize_text for formats that would turn it into f64.
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+        value.parse().map(Amount).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Amount, E> {
+        Ok(Amount(Decimal::from(value)))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Amount, E> {
+        Ok(Amount(Decimal::from(value)))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Amount, E> {
+        Decimal::try_from(value).map(Amount).map_err(E::custom)
+    }
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Decimal::ZERO);
+
+    // For deserialize_with on CSV fields: asked for any type, csv hands a
+    // decimal over as f64, losing digits before they even reach the rounding.
+    pub fn deserialize_text<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Amount, D::Error> {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Result<Amount, Error> {
+        let checked_add_decimal = self.0.checked_add(rhs.0).ok_or(Error::AmountOverflow)?;
+        Ok(Amount(checked_add_decimal))
+    }
+
+    pub fn checked_subtract(self, rhs: Amount) -> Result<Amount, Error> {
+        let checked_subtract_decimal = self.0.checked_sub(rhs.0).ok_or(Error::AmountOverflow)?;
+        Ok(Amount(checked_subtract_decimal))
+    }
+
+    fn check_and_round_deposit(
+        amount: Amount,
+        currency: Currency,
+        config: &EngineConfig,
+    ) -> Result<Amount, Error> {
+        let limits = config.limits(currency)?;
+        let rounded = config.round_input(amount, currency)?;
+        if amount < limits.min_deposit || rounded <= Amount::ZERO {
+            Err(Error::DepositLessThanMin)
+        } else if amount > limits.max_deposit {
+            Err(Error::DepositMoreThanMax)
+        } else {
+            Ok(rounded)
+        }
+    }
+
+    // a dispute without an amount covers everything not disputed yet
+    fn check_and_round_dispute(
+        amount: Option<Amount>,
+        disputable: Amount,
+        currency: Currency,
+        config: &EngineConfig,
+    ) -> Result<Amount, Error> {
+        match amount
+            .map(|amount| config.round_input(amount, currency))
+            .transpose()?
+        {
+            Some(amount) if amount <= Amount::ZERO => Err(Error::InvalidDisputeAmount),
+            Some(amount) if amount > disputable => Err(Error::DisputeMoreThanAmount),
+            Some(amount) => Ok(amount),
+            None if disputable > Amount::ZERO => Ok(disputable),
+            None => Err(Error::DisputeMoreThanAmount),
+        }
+    }
+
+    // resolves and chargebacks without an amount settle the whole dispute
+    fn check_and_round_settlement(
+        amount: Option<Amount>,
+        disputed: Amount,
+        currency: Currency,
+        config: &EngineConfig,
+    ) -> Result<Amount, Error> {
+        let amount = match amount {
+            Some(amount) => config.round_input(amount, currency)?,
+            None => disputed,
+        };
+        if amount <= Amount::ZERO {
+            Err(Error::InvalidDisputeAmount)
+        } else if amount > disputed {
+            Err(Error::SettlementMoreThanDisputed)
+        } else {
+            Ok(amount)
+        }
+    }
+
+    fn check_and_round_withdraw(
+        amount: Amount,
+        currency: Currency,
+        config: &EngineConfig,
+    ) -> Result<Amount, Error> {
+        let limits = config.limits(currency)?;
+        let rounded = config.round_input(amount, currency)?;
+        if amount < limits.min_withdraw || rounded <= Amount::ZERO {
+            Err(Error::WithdrawLessThanMin)
+        } else if amount > limits.max_withdraw {
+            Err(Error::WithdrawMoreThanMax)
+        } else {
+            Ok(rounded)
+        }
+    }
+}
+
+/* TODO
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Self::Output {
+        self.0 + other.0
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        *self.0 = *self.0 + other.0
+    }
+}
+*/
+
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Copy, Clone, Serialize, Deserialize)]
+pub struct TransactionId(pub u32);
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Transaction {
     Deposit(Deposit),
     Withdraw(Withdraw),
     Dispute(Dispute),
     Resolve(Resolve),
     Chargeback(Chargeback),
+    Transfer(Transfer),
+    Authorize(Authorize),
+    Capture(Capture),
+    Void(Void),
+    Unlock(Unlock),
+    CreditLimit(CreditLimit),
+}
+
+impl Transaction {
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            Transaction::Deposit(deposit) => deposit.client_id,
+            Transaction::Withdraw(withdraw) => withdraw.client_id,
+            Transaction::Dispute(dispute) => dispute.client_id,
+            Transaction::Resolve(resolve) => resolve.client_id,
+            Transaction::Chargeback(chargeback) => chargeback.client_id,
+            Transaction::Transfer(transfer) => transfer.from,
+            Transaction::Authorize(authorize) => authorize.client_id,
+            Transaction::Capture(capture) => capture.client_id,
+            Transaction::Void(void) => void.client_id,
+            Transaction::Unlock(unlock) => unlock.client_id,
+            Transaction::CreditLimit(credit_limit) => credit_limit.client_id,
+        }
+    }
+
+    // id of a new transaction, None for the ones referring to an existing one
+    pub fn transaction_id(&self) -> Option<TransactionId> {
+        match self {
+            Transaction::Deposit(deposit) => Some(deposit.transaction_id),
+            Transaction::Withdraw(withdraw) => Some(withdraw.transaction_id),
+            Transaction::Transfer(transfer) => Some(transfer.transaction_id),
+            Transaction::Authorize(authorize) => Some(authorize.transaction_id),
+            Transaction::Unlock(unlock) => Some(unlock.transaction_id),
+            Transaction::CreditLimit(credit_limit) => Some(credit_limit.transaction_id),
+            Transaction::Dispute(_)
+            | Transaction::Resolve(_)
+            | Transaction::Chargeback(_)
+            | Transaction::Capture(_)
+            | Transaction::Void(_) => None,
+        }
+    }
+
+    pub fn target_transaction_id(&self) -> Option<TransactionId> {
+        match self {
+            Transaction::Dispute(dispute) => Some(dispute.target_transaction_id),
+            Transaction::Resolve(resolve) => Some(resolve.target_transaction_id),
+            Transaction::Chargeback(chargeback) => Some(chargeback.target_transaction_id),
+            Transaction::Capture(capture) => Some(capture.target_transaction_id),
+            Transaction::Void(void) => Some(void.target_transaction_id),
+            Transaction::Deposit(_)
+            | Transaction::Withdraw(_)
+            | Transaction::Transfer(_)
+            | Transaction::Authorize(_)
+            | Transaction::Unlock(_)
+            | Transaction::CreditLimit(_) => None,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Deposit {
     pub transaction_id: TransactionId,
     pub client_id: ClientId,
     pub amount: Amount,
-    pub dispute_status: DisputeStatus,
+    pub currency: Currency,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
-pub enum DisputeStatus {
-    NotDisputed,
-    Disputed,
-    Resolved,
-    Chargebacked,
+impl Deposit {
+    pub fn new(
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        currency: Currency,
+    ) -> Deposit {
+        Deposit {
+            transaction_id,
+            client_id,
+            amount,
+            currency,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Withdraw {
     pub transaction_id: TransactionId,
     pub client_id: ClientId,
     pub amount: Amount,
+    pub currency: Currency,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+impl Withdraw {
+    pub fn new(
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        currency: Currency,
+    ) -> Withdraw {
+        Withdraw {
+            transaction_id,
+            client_id,
+            amount,
+            currency,
+        }
+    }
+}
+
+// moves funds from one client to another, kept in the history of both but
+// only the sender can dispute it
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub transaction_id: TransactionId,
+    pub from: ClientId,
+    pub to: ClientId,
+    pub amount: Amount,
+    pub currency: Currency,
+}
+
+impl Transfer {
+    pub fn new(
+        transaction_id: TransactionId,
+        from: ClientId,
+        to: ClientId,
+        amount: Amount,
+        currency: Currency,
+    ) -> Transfer {
+        Transfer {
+            transaction_id,
+            from,
+            to,
+            amount,
+            currency,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Dispute {
     pub client_id: ClientId,
     pub target_transaction_id: TransactionId,
+    pub amount: Option<Amount>,
+    // checked against the target's currency if given
+    pub currency: Option<Currency>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Resolve {
     pub client_id: ClientId,
     pub target_transaction_id: TransactionId,
+    pub amount: Option<Amount>,
+    // checked against the target's currency if given
+    pub currency: Option<Currency>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Chargeback {
     pub client_id: ClientId,
     pub target_transaction_id: TransactionId,
+    pub amount: Option<Amount>,
+    // checked against the target's currency if given
+    pub currency: Option<Currency>,
+}
+
+// admin operation lifting the lock put on a client by a chargeback, the
+// operator has to give a reason which is kept in the client's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Unlock {
+    pub transaction_id: TransactionId,
+    pub client_id: ClientId,
+    pub reason: String,
+}
+
+// admin operation setting the credit limit of a client in a currency
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CreditLimit {
+    pub transaction_id: TransactionId,
+    pub client_id: ClientId,
+    pub credit_limit: Amount,
+    pub currency: Currency,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rust_decimal_macros::dec;
     use test_case::test_case;
 
     #[test_case(Amount(Decimal::ONE_HUNDRED); "deposit amount is one hundred")]
-    #[test_case(Amount(MIN_DEPOSIT); "minimum deposit amount")]
-    #[test_case(Amount(MAX_DEPOSIT); "maximum deposit amount")]
-    //#[test_case(Amount(Decimal::NEGATIVE_ONE); "amount is less than deposit minimum")] TODO
-    //#[test_case(Amount(Decimal::MAX); "amount is more than deposit maximum")] TODO
+    #[test_case(EngineConfig::default().min_deposit; "minimum deposit amount")]
+    #[test_case(EngineConfig::default().max_deposit; "maximum deposit amount")]
     fn deposit_to_non_existing_client_id(amount: Amount) {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
-            amount: amount,
-            dispute_status: DisputeStatus::NotDisputed,
+            amount,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -338,15 +2133,27 @@ mod tests {
 
         let mut fake_client = Client {
             client_id: ClientId(1),
-            available: amount,
-            held: Amount(Decimal::ZERO),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: amount,
+                    held: Amount(Decimal::ZERO),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             locked: false,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
             transaction_list: HashMap::new(),
+            dispute_history: HashMap::new(),
         };
 
-        fake_client
-            .transaction_list
-            .insert(deposit.transaction_id, Transaction::Deposit(deposit));
+        fake_client.transaction_list.insert(
+            deposit.transaction_id,
+            TransactionRecord::Deposit(Disputable::new(deposit.amount, deposit.currency)),
+        );
 
         assert_eq!(client, &fake_client);
     }
@@ -354,15 +2161,13 @@ mod tests {
     // TODO more test cases
     #[test_case(Amount(Decimal::ONE_HUNDRED), Amount(Decimal::ONE_HUNDRED); "both amounts are 100")]
     fn deposit_to_existing_client_id(first_amount: Amount, second_amount: Amount) {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let first_deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: first_amount,
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -373,7 +2178,7 @@ mod tests {
             transaction_id: TransactionId(2),
             client_id: ClientId(1),
             amount: second_amount,
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -389,20 +2194,37 @@ mod tests {
 
         fake_transaction_list.insert(
             first_deposit.transaction_id,
-            Transaction::Deposit(first_deposit),
+            TransactionRecord::Deposit(Disputable::new(
+                first_deposit.amount,
+                first_deposit.currency,
+            )),
         );
 
         fake_transaction_list.insert(
             second_deposit.transaction_id,
-            Transaction::Deposit(second_deposit),
+            TransactionRecord::Deposit(Disputable::new(
+                second_deposit.amount,
+                second_deposit.currency,
+            )),
         );
 
         let fake_client_after_second_deposit = Client {
             client_id: ClientId(1),
-            available: first_amount.checked_add(second_amount),
-            held: Amount(Decimal::ZERO),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: first_amount.checked_add(second_amount).unwrap(),
+                    held: Amount(Decimal::ZERO),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             locked: false,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
             transaction_list: fake_transaction_list,
+            dispute_history: HashMap::new(),
         };
 
         assert_eq!(
@@ -413,15 +2235,13 @@ mod tests {
     //TODO more test cases
     #[test_case(Amount(Decimal::ONE_HUNDRED), Amount(Decimal::ONE_HUNDRED); "normal withdraw")]
     fn withdraw_from_client_id(deposit_amount: Amount, withdraw_amount: Amount) {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: deposit_amount,
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -432,6 +2252,7 @@ mod tests {
             transaction_id: TransactionId(2),
             client_id: ClientId(1),
             amount: withdraw_amount,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -444,15 +2265,29 @@ mod tests {
             .expect("client id doesn't exist...");
 
         let mut fake_transaction_list = HashMap::new();
-        fake_transaction_list.insert(deposit.transaction_id, Transaction::Deposit(deposit));
-        fake_transaction_list.insert(withdraw.transaction_id, Transaction::Withdraw(withdraw));
+        fake_transaction_list.insert(
+            deposit.transaction_id,
+            TransactionRecord::Deposit(Disputable::new(deposit.amount, deposit.currency)),
+        );
+        // withdrawals can't be disputed by default, so they aren't kept
 
         let fake_client_after_withdraw = Client {
             client_id: ClientId(1),
-            available: deposit_amount.checked_subtract(withdraw_amount),
-            held: Amount(Decimal::ZERO),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: deposit_amount.checked_subtract(withdraw_amount).unwrap(),
+                    held: Amount(Decimal::ZERO),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             locked: false,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
             transaction_list: fake_transaction_list,
+            dispute_history: HashMap::new(),
         };
 
         assert_eq!(client_after_withdraw, &fake_client_after_withdraw);
@@ -461,15 +2296,13 @@ mod tests {
     #[test]
     #[should_panic]
     fn withdraw_insufficient_amount_from_client_id() {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE),
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -480,6 +2313,7 @@ mod tests {
             transaction_id: TransactionId(2),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
+            currency: Currency::XXX,
         };
 
         payments_engine
@@ -489,20 +2323,20 @@ mod tests {
 
     #[test]
     fn dispute_a_deposit() {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         let dispute = Dispute {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
         };
 
         payments_engine
@@ -519,22 +2353,48 @@ mod tests {
 
         let mut fake_client = Client {
             client_id: ClientId(1),
-            available: Amount(Decimal::ZERO),
-            held: Amount(Decimal::ONE_HUNDRED),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: Amount(Decimal::ZERO),
+                    held: Amount(Decimal::ONE_HUNDRED),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             transaction_list: HashMap::new(),
             locked: false,
-        };
-
-        let fake_deposit = Deposit {
-            transaction_id: TransactionId(1),
-            client_id: ClientId(1),
-            amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::Disputed,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
+            dispute_history: HashMap::new(),
         };
 
         fake_client.transaction_list.insert(
-            fake_deposit.transaction_id,
-            Transaction::Deposit(fake_deposit),
+            TransactionId(1),
+            TransactionRecord::Deposit(Disputable::with_amounts(
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+                DisputeStatus::Disputed,
+                Amount(Decimal::ONE_HUNDRED),
+                Amount::ZERO,
+                Amount::ZERO,
+            )),
+        );
+
+        fake_client.record_dispute_event(
+            TransactionId(1),
+            DisputeEvent {
+                sequence: 2,
+                from: DisputeStatus::NotDisputed,
+                to: DisputeStatus::Disputed,
+                amount: Amount(Decimal::ONE_HUNDRED),
+                parts: DisputeParts {
+                    disputed: Amount(Decimal::ONE_HUNDRED),
+                    ..DisputeParts::default()
+                },
+                transaction: Transaction::Dispute(dispute),
+            },
         );
 
         assert_eq!(client, &fake_client);
@@ -543,26 +2403,27 @@ mod tests {
     #[test]
     #[should_panic]
     fn dispute_a_non_deposit() {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         let withdraw = Withdraw {
             transaction_id: TransactionId(2),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
+            currency: Currency::XXX,
         };
 
         let dispute = Dispute {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
         };
 
         payments_engine
@@ -580,25 +2441,27 @@ mod tests {
 
     #[test]
     fn resolve_a_dispute() {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         let dispute = Dispute {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
         };
 
         let resolve = Resolve {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
         };
 
         payments_engine
@@ -620,22 +2483,63 @@ mod tests {
 
         let mut fake_client = Client {
             client_id: ClientId(1),
-            available: Amount(Decimal::ONE_HUNDRED),
-            held: Amount(Decimal::ZERO),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: Amount(Decimal::ONE_HUNDRED),
+                    held: Amount(Decimal::ZERO),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             transaction_list: HashMap::new(),
             locked: false,
-        };
-
-        let fake_deposit = Deposit {
-            transaction_id: TransactionId(1),
-            client_id: ClientId(1),
-            amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::Resolved,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
+            dispute_history: HashMap::new(),
         };
 
         fake_client.transaction_list.insert(
-            fake_deposit.transaction_id,
-            Transaction::Deposit(fake_deposit),
+            TransactionId(1),
+            TransactionRecord::Deposit(Disputable::with_amounts(
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+                DisputeStatus::Resolved,
+                Amount::ZERO,
+                Amount::ZERO,
+                Amount(Decimal::ONE_HUNDRED),
+            )),
+        );
+
+        fake_client.record_dispute_event(
+            TransactionId(1),
+            DisputeEvent {
+                sequence: 2,
+                from: DisputeStatus::NotDisputed,
+                to: DisputeStatus::Disputed,
+                amount: Amount(Decimal::ONE_HUNDRED),
+                parts: DisputeParts {
+                    disputed: Amount(Decimal::ONE_HUNDRED),
+                    ..DisputeParts::default()
+                },
+                transaction: Transaction::Dispute(dispute),
+            },
+        );
+
+        fake_client.record_dispute_event(
+            TransactionId(1),
+            DisputeEvent {
+                sequence: 3,
+                from: DisputeStatus::Disputed,
+                to: DisputeStatus::Resolved,
+                amount: Amount(Decimal::ONE_HUNDRED),
+                parts: DisputeParts {
+                    resolved: Amount(Decimal::ONE_HUNDRED),
+                    ..DisputeParts::default()
+                },
+                transaction: Transaction::Resolve(resolve),
+            },
         );
 
         assert_eq!(client, &fake_client);
@@ -643,25 +2547,27 @@ mod tests {
 
     #[test]
     fn chargeback_a_dispute() {
-        let mut payments_engine = PaymentsEngine {
-            client_list: HashMap::new(),
-        };
+        let mut payments_engine = PaymentsEngine::new();
 
         let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::NotDisputed,
+            currency: Currency::XXX,
         };
 
         let dispute = Dispute {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
         };
 
         let chargeback = Chargeback {
             client_id: ClientId(1),
             target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
         };
 
         payments_engine
@@ -683,24 +2589,2777 @@ mod tests {
 
         let mut fake_client = Client {
             client_id: ClientId(1),
-            available: Amount(Decimal::ZERO),
-            held: Amount(Decimal::ZERO),
+            balances: BTreeMap::from([(
+                Currency::XXX,
+                Balance {
+                    available: Amount(Decimal::ZERO),
+                    held: Amount(Decimal::ZERO),
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            )]),
             transaction_list: HashMap::new(),
             locked: true,
+            credit_limits: BTreeMap::new(),
+            fees: vec![],
+            rounding_residue: BTreeMap::new(),
+            dispute_history: HashMap::new(),
         };
 
-        let fake_deposit = Deposit {
+        fake_client.transaction_list.insert(
+            TransactionId(1),
+            TransactionRecord::Deposit(Disputable::with_amounts(
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+                DisputeStatus::Chargebacked,
+                Amount::ZERO,
+                Amount(Decimal::ONE_HUNDRED),
+                Amount::ZERO,
+            )),
+        );
+
+        fake_client.record_dispute_event(
+            TransactionId(1),
+            DisputeEvent {
+                sequence: 2,
+                from: DisputeStatus::NotDisputed,
+                to: DisputeStatus::Disputed,
+                amount: Amount(Decimal::ONE_HUNDRED),
+                parts: DisputeParts {
+                    disputed: Amount(Decimal::ONE_HUNDRED),
+                    ..DisputeParts::default()
+                },
+                transaction: Transaction::Dispute(dispute),
+            },
+        );
+
+        fake_client.record_dispute_event(
+            TransactionId(1),
+            DisputeEvent {
+                sequence: 3,
+                from: DisputeStatus::Disputed,
+                to: DisputeStatus::Chargebacked,
+                amount: Amount(Decimal::ONE_HUNDRED),
+                parts: DisputeParts {
+                    charged_back: Amount(Decimal::ONE_HUNDRED),
+                    ..DisputeParts::default()
+                },
+                transaction: Transaction::Chargeback(chargeback),
+            },
+        );
+
+        assert_eq!(client, &fake_client);
+    }
+
+    #[test_case(Transaction::Withdraw(Withdraw {
+        transaction_id: TransactionId(2),
+        client_id: ClientId(2),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "withdraw")]
+    #[test_case(Transaction::Dispute(Dispute {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }); "dispute")]
+    #[test_case(Transaction::Resolve(Resolve {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }); "resolve")]
+    #[test_case(Transaction::Chargeback(Chargeback {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }); "chargeback")]
+    fn transaction_for_non_existing_client_id(transaction: Transaction) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
             transaction_id: TransactionId(1),
             client_id: ClientId(1),
             amount: Amount(Decimal::ONE_HUNDRED),
-            dispute_status: DisputeStatus::Chargebacked,
+            currency: Currency::XXX,
         };
 
-        fake_client.transaction_list.insert(
-            fake_deposit.transaction_id,
-            Transaction::Deposit(fake_deposit),
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(transaction),
+            Err(Error::NonExistingClient)
+        );
+        assert!(!payments_engine.client_list.contains_key(&ClientId(2)));
+    }
+
+    #[test_case(Amount(Decimal::NEGATIVE_ONE), Error::DepositLessThanMin; "amount is less than deposit minimum")]
+    #[test_case(Amount(Decimal::ZERO), Error::DepositLessThanMin; "amount is zero")]
+    #[test_case(Amount(Decimal::MAX), Error::DepositMoreThanMax; "amount is more than deposit maximum")]
+    fn deposit_out_of_bounds(amount: Amount, error: Error) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount,
+            currency: Currency::XXX,
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(deposit)),
+            Err(error)
+        );
+        assert!(payments_engine.client_list.is_empty());
+    }
+
+    #[test_case(Amount(Decimal::NEGATIVE_ONE), Error::WithdrawLessThanMin; "amount is less than withdraw minimum")]
+    #[test_case(Amount(Decimal::MAX), Error::WithdrawMoreThanMax; "amount is more than withdraw maximum")]
+    #[test_case(Amount(Decimal::TWO), Error::WithdrawMoreThanAvailable; "amount is more than available")]
+    fn withdraw_rejected(amount: Amount, error: Error) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE),
+            currency: Currency::XXX,
+        };
+
+        let withdraw = Withdraw {
+            transaction_id: TransactionId(2),
+            client_id: ClientId(1),
+            amount,
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Withdraw(withdraw)),
+            Err(error)
+        );
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)]
+                .balance(Currency::XXX)
+                .available,
+            Amount(Decimal::ONE)
+        );
+    }
+
+    #[test]
+    fn deposit_overflow() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine.client_list.insert(
+            ClientId(1),
+            Client {
+                client_id: ClientId(1),
+                balances: BTreeMap::from([(
+                    Currency::XXX,
+                    Balance {
+                        available: Amount(Decimal::MAX),
+                        held: Amount(Decimal::ZERO),
+                        reserved: Amount::ZERO,
+                        receivable: Amount::ZERO,
+                    },
+                )]),
+                locked: false,
+                credit_limits: BTreeMap::new(),
+                fees: vec![],
+                rounding_residue: BTreeMap::new(),
+                transaction_list: HashMap::new(),
+                dispute_history: HashMap::new(),
+            },
+        );
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE),
+            currency: Currency::XXX,
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(deposit)),
+            Err(Error::AmountOverflow)
+        );
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::MAX)
+        );
+        assert!(client.transaction_list.is_empty());
+    }
+
+    #[test]
+    fn dispute_overflow() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE),
+            currency: Currency::XXX,
+        };
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        let client = payments_engine
+            .client_list
+            .get_mut(&ClientId(1))
+            .expect("client id doesn't exist...");
+        client.balances.get_mut(&Currency::XXX).unwrap().held = Amount(Decimal::MAX);
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Dispute(dispute)),
+            Err(Error::AmountOverflow)
+        );
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::ONE)
+        );
+        assert_eq!(client.balance(Currency::XXX).held, Amount(Decimal::MAX));
+    }
+
+    #[test_case(Transaction::Dispute(Dispute {
+        client_id: ClientId(1),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }), Error::DisputeError; "dispute")]
+    #[test_case(Transaction::Resolve(Resolve {
+        client_id: ClientId(1),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }), Error::ResolveError; "resolve")]
+    #[test_case(Transaction::Chargeback(Chargeback {
+        client_id: ClientId(1),
+        target_transaction_id: TransactionId(9),
+        amount: None,
+        currency: None,
+    }), Error::ChargebackError; "chargeback")]
+    fn dispute_flow_for_non_existing_transaction(transaction: Transaction, error: Error) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE_HUNDRED),
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        assert_eq!(payments_engine.recv_tx(transaction), Err(error));
+    }
+
+    // client 1 with deposits 1 and 2 of 100 each, deposit 1 charged back
+    fn engine_with_locked_client() -> PaymentsEngine {
+        let mut payments_engine = PaymentsEngine::new();
+
+        for transaction_id in [TransactionId(1), TransactionId(2)] {
+            let deposit = Deposit {
+                transaction_id,
+                client_id: ClientId(1),
+                amount: Amount(Decimal::ONE_HUNDRED),
+                currency: Currency::XXX,
+            };
+            payments_engine
+                .recv_tx(Transaction::Deposit(deposit))
+                .expect("deposit amount error");
+        }
+
+        payments_engine
+            .recv_tx(Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: None,
+                currency: None,
+            }))
+            .expect("dispute error");
+
+        payments_engine
+            .recv_tx(Transaction::Chargeback(Chargeback {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: None,
+                currency: None,
+            }))
+            .expect("chargeback error");
+
+        payments_engine
+    }
+
+    #[test_case(Transaction::Deposit(Deposit {
+        transaction_id: TransactionId(3),
+        client_id: ClientId(1),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "deposit")]
+    #[test_case(Transaction::Withdraw(Withdraw {
+        transaction_id: TransactionId(3),
+        client_id: ClientId(1),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "withdraw")]
+    fn locked_client_rejects_transaction(transaction: Transaction) {
+        let mut payments_engine = engine_with_locked_client();
+
+        assert_eq!(
+            payments_engine.recv_tx(transaction),
+            Err(Error::ClientLocked)
+        );
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::ONE_HUNDRED)
+        );
+        assert!(!client.transaction_list.contains_key(&TransactionId(3)));
+    }
+
+    #[test_case(true; "disputes allowed")]
+    #[test_case(false; "disputes rejected")]
+    fn dispute_on_locked_client(dispute_locked_clients: bool) {
+        let mut payments_engine = engine_with_locked_client();
+        payments_engine.config.dispute_locked_clients = dispute_locked_clients;
+
+        let result = payments_engine.recv_tx(Transaction::Dispute(Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
+        }));
+
+        if dispute_locked_clients {
+            assert_eq!(result, Ok(()));
+        } else {
+            assert_eq!(result, Err(Error::ClientLocked));
+        }
+    }
+
+    #[test]
+    fn unlock_a_locked_client() {
+        let mut payments_engine = engine_with_locked_client();
+
+        let unlock = Unlock {
+            transaction_id: TransactionId(3),
+            client_id: ClientId(1),
+            reason: String::from("chargeback investigated, client cleared"),
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Unlock(unlock.clone()))
+            .expect("unlock error");
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(4),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE),
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert!(!client.locked);
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(101))
+        );
+        assert_eq!(
+            client.transaction_list.get(&TransactionId(3)),
+            Some(&TransactionRecord::Unlock {
+                reason: unlock.reason
+            })
+        );
+    }
+
+    #[test]
+    fn unlock_a_non_locked_client() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE),
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        let unlock = Unlock {
+            transaction_id: TransactionId(2),
+            client_id: ClientId(1),
+            reason: String::from("no reason"),
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Unlock(unlock)),
+            Err(Error::ClientNotLocked)
+        );
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case(" \t "; "whitespace only")]
+    fn unlock_without_reason(reason: &str) {
+        let mut payments_engine = engine_with_locked_client();
+
+        let unlock = Unlock {
+            transaction_id: TransactionId(3),
+            client_id: ClientId(1),
+            reason: String::from(reason),
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Unlock(unlock)),
+            Err(Error::MissingUnlockReason)
+        );
+        assert!(payments_engine.client_list[&ClientId(1)].locked);
+    }
+
+    #[test_case(Transaction::Deposit(Deposit {
+        transaction_id: TransactionId(1),
+        client_id: ClientId(1),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "replayed deposit")]
+    #[test_case(Transaction::Deposit(Deposit {
+        transaction_id: TransactionId(1),
+        client_id: ClientId(2),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "deposit reusing another client's id")]
+    #[test_case(Transaction::Withdraw(Withdraw {
+        transaction_id: TransactionId(1),
+        client_id: ClientId(1),
+        amount: Amount(Decimal::ONE),
+        currency: Currency::XXX,
+    }); "withdraw reusing a deposit id")]
+    fn duplicate_transaction_id(transaction: Transaction) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE_HUNDRED),
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(transaction),
+            Err(Error::DuplicateTransaction)
+        );
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::ONE_HUNDRED)
+        );
+        assert_eq!(
+            client.transaction_list.get(&TransactionId(1)),
+            Some(&TransactionRecord::Deposit(Disputable::new(
+                deposit.amount,
+                deposit.currency
+            )))
+        );
+        assert!(!payments_engine.client_list.contains_key(&ClientId(2)));
+    }
+
+    #[test]
+    fn rejected_transaction_id_can_be_reused() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::MAX),
+            currency: Currency::XXX,
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(deposit)),
+            Err(Error::DepositMoreThanMax)
+        );
+
+        let deposit = Deposit {
+            amount: Amount(Decimal::ONE),
+            ..deposit
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(deposit)),
+            Ok(())
+        );
+    }
+
+    #[test_case(Transaction::Dispute(Dispute {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(1),
+        amount: None,
+        currency: None,
+    }); "dispute")]
+    #[test_case(Transaction::Resolve(Resolve {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(1),
+        amount: None,
+        currency: None,
+    }); "resolve")]
+    #[test_case(Transaction::Chargeback(Chargeback {
+        client_id: ClientId(2),
+        target_transaction_id: TransactionId(1),
+        amount: None,
+        currency: None,
+    }); "chargeback")]
+    fn dispute_flow_for_another_clients_transaction(transaction: Transaction) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        for (transaction_id, client_id) in [(1, 1), (2, 2)] {
+            let deposit = Deposit {
+                transaction_id: TransactionId(transaction_id),
+                client_id: ClientId(client_id),
+                amount: Amount(Decimal::ONE_HUNDRED),
+                currency: Currency::XXX,
+            };
+            payments_engine
+                .recv_tx(Transaction::Deposit(deposit))
+                .expect("deposit amount error");
+        }
+
+        assert_eq!(
+            payments_engine.recv_tx(transaction),
+            Err(Error::TransactionClientMismatch)
+        );
+    }
+
+    #[test]
+    fn engine_applies_config_limits_and_precision() {
+        let config = EngineConfig {
+            min_deposit: Amount(Decimal::ONE),
+            max_deposit: Amount(Decimal::ONE_HUNDRED),
+            decimal_points: 2,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::new(12345, 3)),
+            currency: Currency::XXX,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        for (amount, error) in [
+            (Decimal::new(5, 1), Error::DepositLessThanMin),
+            (Decimal::from(101), Error::DepositMoreThanMax),
+        ] {
+            let deposit = Deposit {
+                transaction_id: TransactionId(2),
+                amount: Amount(amount),
+                ..deposit
+            };
+            assert_eq!(
+                payments_engine.recv_tx(Transaction::Deposit(deposit)),
+                Err(error)
+            );
+        }
+
+        // the default currency's limits don't apply to other currencies
+        let deposit = Deposit {
+            transaction_id: TransactionId(2),
+            amount: Amount(Decimal::new(5, 1)),
+            currency: Currency::EUR,
+            ..deposit
+        };
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)]
+                .balance(Currency::XXX)
+                .available,
+            Amount(Decimal::new(1234, 2))
+        );
+    }
+
+    #[test]
+    fn engine_rejects_invalid_config() {
+        let config = EngineConfig {
+            decimal_points: 0,
+            ..EngineConfig::default()
+        };
+
+        assert_eq!(
+            PaymentsEngine::with_config(config).err(),
+            Some(ConfigError::InvalidDecimalPoints)
+        );
+    }
+
+    // client 1 deposited 100 and withdrew 40 in transaction 2, which is disputed
+    fn engine_with_disputed_withdraw() -> PaymentsEngine {
+        let config = EngineConfig {
+            withdrawal_disputes: WithdrawalDisputePolicy::Allow,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+
+        let deposit = Deposit {
+            transaction_id: TransactionId(1),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::ONE_HUNDRED),
+            currency: Currency::XXX,
+        };
+
+        let withdraw = Withdraw {
+            transaction_id: TransactionId(2),
+            client_id: ClientId(1),
+            amount: Amount(Decimal::from(40)),
+            currency: Currency::XXX,
+        };
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(deposit))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(Transaction::Withdraw(withdraw))
+            .expect("withdraw amount error");
+        payments_engine
+            .recv_tx(Transaction::Dispute(dispute))
+            .expect("dispute error");
+
+        payments_engine
+    }
+
+    #[test]
+    fn dispute_a_withdraw() {
+        let mut payments_engine = engine_with_disputed_withdraw();
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(60))
+        );
+        assert_eq!(
+            client.balance(Currency::XXX).held,
+            Amount(Decimal::from(40))
+        );
+        assert!(matches!(
+            client.transaction_list.get(&TransactionId(2)),
+            Some(TransactionRecord::Withdraw(Disputable {
+                status: DisputeStatus::Disputed,
+                ..
+            }))
+        ));
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
+        };
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Dispute(dispute)),
+            Err(Error::DisputeMoreThanAmount)
+        );
+    }
+
+    #[test]
+    fn resolve_a_withdraw_dispute() {
+        let mut payments_engine = engine_with_disputed_withdraw();
+
+        let resolve = Resolve {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Resolve(resolve))
+            .expect("resolve error");
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(60))
+        );
+        assert_eq!(client.balance(Currency::XXX).held, Amount(Decimal::ZERO));
+        assert!(matches!(
+            client.transaction_list.get(&TransactionId(2)),
+            Some(TransactionRecord::Withdraw(Disputable {
+                status: DisputeStatus::Resolved,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn chargeback_a_withdraw_dispute() {
+        let mut payments_engine = engine_with_disputed_withdraw();
+
+        let chargeback = Chargeback {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(2),
+            amount: None,
+            currency: None,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Chargeback(chargeback))
+            .expect("chargeback error");
+
+        let client = payments_engine
+            .client_list
+            .get(&ClientId(1))
+            .expect("client id doesn't exist...");
+
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::ONE_HUNDRED)
+        );
+        assert_eq!(client.balance(Currency::XXX).held, Amount(Decimal::ZERO));
+        assert!(!client.locked);
+        assert!(matches!(
+            client.transaction_list.get(&TransactionId(2)),
+            Some(TransactionRecord::Withdraw(Disputable {
+                status: DisputeStatus::Chargebacked,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn partial_disputes_up_to_the_deposit_amount() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        for amount in [30, 50] {
+            payments_engine
+                .recv_tx(Transaction::Dispute(Dispute {
+                    client_id: ClientId(1),
+                    target_transaction_id: TransactionId(1),
+                    amount: Some(Amount(Decimal::from(amount))),
+                    currency: None,
+                }))
+                .expect("dispute error");
+        }
+
+        for (amount, error) in [
+            (Some(Decimal::from(30)), Error::DisputeMoreThanAmount),
+            (Some(Decimal::ZERO), Error::InvalidDisputeAmount),
+        ] {
+            assert_eq!(
+                payments_engine.recv_tx(Transaction::Dispute(Dispute {
+                    client_id: ClientId(1),
+                    target_transaction_id: TransactionId(1),
+                    amount: amount.map(Amount),
+                    currency: None,
+                })),
+                Err(error)
+            );
+        }
+
+        let client = &payments_engine.client_list[&ClientId(1)];
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(20))
+        );
+        assert_eq!(
+            client.balance(Currency::XXX).held,
+            Amount(Decimal::from(80))
+        );
+    }
+
+    #[test]
+    fn partial_resolve_and_chargeback() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        payments_engine
+            .recv_tx(Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::from(80))),
+                currency: None,
+            }))
+            .expect("dispute error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Resolve(Resolve {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::from(90))),
+                currency: None,
+            })),
+            Err(Error::SettlementMoreThanDisputed)
+        );
+
+        payments_engine
+            .recv_tx(Transaction::Resolve(Resolve {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::from(20))),
+                currency: None,
+            }))
+            .expect("resolve error");
+
+        let client = &payments_engine.client_list[&ClientId(1)];
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(40))
+        );
+        assert_eq!(
+            client.balance(Currency::XXX).held,
+            Amount(Decimal::from(60))
+        );
+        assert!(matches!(
+            client.transaction_list[&TransactionId(1)],
+            TransactionRecord::Deposit(Disputable {
+                status: DisputeStatus::Disputed,
+                ..
+            })
+        ));
+
+        // without an amount the chargeback takes everything still disputed
+        payments_engine
+            .recv_tx(Transaction::Chargeback(Chargeback {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: None,
+                currency: None,
+            }))
+            .expect("chargeback error");
+
+        let client = &payments_engine.client_list[&ClientId(1)];
+        assert_eq!(
+            client.balance(Currency::XXX).available,
+            Amount(Decimal::from(40))
+        );
+        assert_eq!(client.balance(Currency::XXX).held, Amount::ZERO);
+        assert!(client.locked);
+        assert_eq!(
+            client.transaction_list[&TransactionId(1)],
+            TransactionRecord::Deposit(Disputable::with_amounts(
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+                DisputeStatus::Chargebacked,
+                Amount::ZERO,
+                Amount(Decimal::from(60)),
+                Amount(Decimal::from(20)),
+            ))
+        );
+    }
+
+    #[test]
+    fn dispute_what_a_settled_dispute_left() {
+        let mut payments_engine = PaymentsEngine::new();
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        let dispute = |amount: Option<u32>| {
+            Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: amount.map(|amount| Amount(Decimal::from(amount))),
+                currency: None,
+            })
+        };
+        let status = |payments_engine: &PaymentsEngine| match &payments_engine.client_list
+            [&ClientId(1)]
+            .transaction_list[&TransactionId(1)]
+        {
+            TransactionRecord::Deposit(funds) => funds.status,
+            _ => panic!("not a deposit"),
+        };
+
+        // part of it is charged back, which doesn't settle the rest
+        payments_engine
+            .recv_tx(dispute(Some(30)))
+            .expect("dispute error");
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Chargeback, 1, 1))
+            .expect("chargeback error");
+        assert_eq!(status(&payments_engine), DisputeStatus::Chargebacked);
+
+        payments_engine
+            .recv_tx(dispute(None))
+            .expect("dispute error");
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.held, Amount(Decimal::from(70)));
+        assert_eq!(status(&payments_engine), DisputeStatus::Disputed);
+
+        // the resolved part stays settled, the whole amount is now
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Resolve, 1, 1))
+            .expect("resolve error");
+        assert_eq!(
+            payments_engine.recv_tx(dispute(None)),
+            Err(Error::InvalidDisputeTransition {
+                status: DisputeStatus::Resolved,
+                action: DisputeAction::Dispute,
+            })
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+
+        // which part of the amount every step left where
+        let parts = |disputed: u32, charged_back: u32, resolved: u32| DisputeParts {
+            disputed: Amount(Decimal::from(disputed)),
+            charged_back: Amount(Decimal::from(charged_back)),
+            resolved: Amount(Decimal::from(resolved)),
+        };
+        let history = payments_engine.client_list[&ClientId(1)].dispute_history[&TransactionId(1)]
+            .iter()
+            .map(|event| (event.to, event.amount, event.parts))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            vec![
+                (
+                    DisputeStatus::Disputed,
+                    Amount(Decimal::from(30)),
+                    parts(30, 0, 0)
+                ),
+                (
+                    DisputeStatus::Chargebacked,
+                    Amount(Decimal::from(30)),
+                    parts(0, 30, 0)
+                ),
+                (
+                    DisputeStatus::Disputed,
+                    Amount(Decimal::from(70)),
+                    parts(70, 30, 0)
+                ),
+                (
+                    DisputeStatus::Resolved,
+                    Amount(Decimal::from(70)),
+                    parts(0, 30, 70)
+                ),
+            ]
+        );
+    }
+
+    #[test_case(false, 60 ; "only what was never disputed")]
+    #[test_case(true, 100 ; "resolved part as well")]
+    fn dispute_after_a_partial_resolve(redispute_resolved: bool, held: u32) {
+        let config = EngineConfig {
+            redispute_resolved,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::from(40))),
+                currency: None,
+            }))
+            .expect("dispute error");
+        for action in [DisputeAction::Resolve, DisputeAction::Dispute] {
+            payments_engine
+                .recv_tx(dispute_flow(action, 1, 1))
+                .expect("dispute flow error");
+        }
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.held, Amount(Decimal::from(held)));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn resolve_a_non_disputed_deposit() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Resolve(Resolve {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: None,
+                currency: None,
+            })),
+            Err(Error::InvalidDisputeTransition {
+                status: DisputeStatus::NotDisputed,
+                action: DisputeAction::Resolve,
+            })
+        );
+        assert!(payments_engine.client_list[&ClientId(1)]
+            .dispute_history(TransactionId(1))
+            .is_empty());
+    }
+
+    #[test_case(false; "redispute rejected")]
+    #[test_case(true; "redispute allowed")]
+    fn dispute_a_resolved_deposit(redispute_resolved: bool) {
+        let config = EngineConfig {
+            redispute_resolved,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
+        };
+
+        let resolve = Resolve {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: None,
+            currency: None,
+        };
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(Transaction::Dispute(dispute))
+            .expect("dispute error");
+        payments_engine
+            .recv_tx(Transaction::Resolve(resolve))
+            .expect("resolve error");
+
+        let result = payments_engine.recv_tx(Transaction::Dispute(dispute));
+        let history = payments_engine.client_list[&ClientId(1)].dispute_history(TransactionId(1));
+
+        if redispute_resolved {
+            assert_eq!(result, Ok(()));
+            assert_eq!(history.len(), 3);
+            assert_eq!(history[2].from, DisputeStatus::Resolved);
+            assert_eq!(history[2].to, DisputeStatus::Disputed);
+            assert_eq!(history[2].sequence, 4);
+        } else {
+            assert_eq!(
+                result,
+                Err(Error::InvalidDisputeTransition {
+                    status: DisputeStatus::Resolved,
+                    action: DisputeAction::Dispute,
+                })
+            );
+            assert_eq!(history.len(), 2);
+        }
+    }
+
+    #[test]
+    fn partial_dispute_history() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: Some(Amount(Decimal::from(40))),
+            currency: None,
+        };
+
+        let resolve = Resolve {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: Some(Amount(Decimal::from(30))),
+            currency: None,
+        };
+
+        for transaction in [
+            Transaction::Dispute(dispute),
+            Transaction::Dispute(dispute),
+            Transaction::Resolve(resolve),
+        ] {
+            payments_engine.recv_tx(transaction).expect("dispute error");
+        }
+
+        let history = payments_engine.client_list[&ClientId(1)].dispute_history(TransactionId(1));
+        let steps: Vec<_> = history
+            .iter()
+            .map(|event| (event.from, event.to, event.amount))
+            .collect();
+
+        assert_eq!(
+            steps,
+            [
+                (
+                    DisputeStatus::NotDisputed,
+                    DisputeStatus::Disputed,
+                    Amount(Decimal::from(40))
+                ),
+                (
+                    DisputeStatus::Disputed,
+                    DisputeStatus::Disputed,
+                    Amount(Decimal::from(40))
+                ),
+                (
+                    DisputeStatus::Disputed,
+                    DisputeStatus::Disputed,
+                    Amount(Decimal::from(30))
+                ),
+            ]
+        );
+        assert_eq!(history[2].transaction, Transaction::Resolve(resolve));
+    }
+
+    #[test]
+    fn balances_are_kept_per_currency() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        for (transaction_id, currency) in [(1, Currency::EUR), (2, Currency::USD)] {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::ONE_HUNDRED),
+                    currency,
+                )))
+                .expect("deposit amount error");
+        }
+        payments_engine
+            .recv_tx(Transaction::Withdraw(Withdraw::new(
+                TransactionId(3),
+                ClientId(1),
+                Amount(Decimal::from(40)),
+                Currency::EUR,
+            )))
+            .expect("withdraw amount error");
+
+        let client = &payments_engine.client_list[&ClientId(1)];
+        assert_eq!(
+            client.balance(Currency::EUR).available,
+            Amount(Decimal::from(60))
+        );
+        assert_eq!(
+            client.balance(Currency::USD).available,
+            Amount(Decimal::ONE_HUNDRED)
+        );
+        assert_eq!(client.balances.len(), 2);
+    }
+
+    #[test_case(Amount(dec!(1.005)), Currency::EUR, Ok(Amount(dec!(1.00))); "cents")]
+    #[test_case(Amount(dec!(1.5)), Currency::JPY, Ok(Amount(dec!(2))); "no minor units")]
+    #[test_case(Amount(dec!(0.4)), Currency::JPY, Err(Error::DepositLessThanMin); "rounds to zero")]
+    #[test_case(Amount(dec!(1.5)), "CHF".parse().unwrap(), Err(Error::NoCurrencyLimits); "no limits")]
+    fn round_to_currency_minor_units(
+        amount: Amount,
+        currency: Currency,
+        expected: Result<Amount, Error>,
+    ) {
+        let mut config = EngineConfig::default();
+        config.limits.push(CurrencyLimits {
+            currency: Currency::JPY,
+            min_deposit: Amount(Decimal::ONE),
+            max_deposit: Amount(dec!(5000000)),
+            min_withdraw: Amount(Decimal::ONE),
+            max_withdraw: Amount(dec!(5000000)),
+        });
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+
+        let result = payments_engine.recv_tx(Transaction::Deposit(Deposit::new(
+            TransactionId(1),
+            ClientId(1),
+            amount,
+            currency,
+        )));
+
+        assert_eq!(
+            result.map(|_| payments_engine.client_list[&ClientId(1)]
+                .balance(currency)
+                .available),
+            expected
+        );
+    }
+
+    #[test]
+    fn withdraw_in_currency_not_held() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::EUR,
+            )))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Withdraw(Withdraw::new(
+                TransactionId(2),
+                ClientId(1),
+                Amount(Decimal::ONE),
+                Currency::USD,
+            ))),
+            Err(Error::CurrencyMismatch)
+        );
+    }
+
+    #[test_case(Some(Currency::USD), Err(Error::CurrencyMismatch); "other currency")]
+    #[test_case(Some(Currency::EUR), Ok(()); "same currency")]
+    #[test_case(None, Ok(()); "currency of the target")]
+    fn dispute_checks_target_currency(currency: Option<Currency>, expected: Result<(), Error>) {
+        let mut payments_engine = PaymentsEngine::new();
+
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::EUR,
+            )))
+            .expect("deposit amount error");
+
+        let result = payments_engine.recv_tx(Transaction::Dispute(Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(1),
+            amount: None,
+            currency,
+        }));
+
+        assert_eq!(result, expected);
+    }
+
+    fn engine_with_two_clients() -> PaymentsEngine {
+        let mut payments_engine = PaymentsEngine::new();
+
+        for (transaction_id, client_id, amount) in [(1, 1, 100), (2, 2, 10)] {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(client_id),
+                    Amount(Decimal::from(amount)),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+        }
+
+        payments_engine
+    }
+
+    fn available(payments_engine: &PaymentsEngine, client_id: u16) -> Amount {
+        payments_engine.client_list[&ClientId(client_id)]
+            .balance(Currency::XXX)
+            .available
+    }
+
+    #[test]
+    fn transfer_between_clients() {
+        let mut payments_engine = engine_with_two_clients();
+
+        let transfer = Transfer::new(
+            TransactionId(3),
+            ClientId(1),
+            ClientId(2),
+            Amount(Decimal::from(30)),
+            Currency::XXX,
+        );
+        payments_engine
+            .recv_tx(Transaction::Transfer(transfer))
+            .expect("transfer error");
+
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::from(70)));
+        assert_eq!(available(&payments_engine, 2), Amount(Decimal::from(40)));
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)]
+                .transaction_list
+                .get(&TransactionId(3)),
+            Some(&TransactionRecord::Transfer {
+                to: ClientId(2),
+                funds: Disputable::new(transfer.amount, transfer.currency),
+            })
+        );
+        assert_eq!(
+            payments_engine.client_list[&ClientId(2)]
+                .transaction_list
+                .get(&TransactionId(3)),
+            Some(&TransactionRecord::ReceivedTransfer {
+                from: ClientId(1),
+                amount: transfer.amount,
+                currency: transfer.currency,
+            })
+        );
+        // only the sender can dispute it
+        assert_eq!(
+            payments_engine.recv_tx(dispute_flow(DisputeAction::Dispute, 2, 3)),
+            Err(Error::TransactionClientMismatch)
+        );
+    }
+
+    #[test_case(1, 1, Amount(Decimal::ONE), Error::TransferToSelf; "to the same client")]
+    #[test_case(1, 3, Amount(Decimal::ONE), Error::NonExistingClient; "to a non existing client")]
+    #[test_case(3, 1, Amount(Decimal::ONE), Error::NonExistingClient; "from a non existing client")]
+    #[test_case(2, 1, Amount(Decimal::from(11)), Error::WithdrawMoreThanAvailable; "more than available")]
+    #[test_case(1, 2, Amount(Decimal::from(50001)), Error::WithdrawMoreThanMax; "more than maximum")]
+    #[test_case(1, 2, Amount(Decimal::ZERO), Error::WithdrawLessThanMin; "less than minimum")]
+    fn reject_transfer(from: u16, to: u16, amount: Amount, error: Error) {
+        let mut payments_engine = engine_with_two_clients();
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(from),
+                ClientId(to),
+                amount,
+                Currency::XXX,
+            ))),
+            Err(error)
+        );
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::ONE_HUNDRED));
+        assert_eq!(available(&payments_engine, 2), Amount(Decimal::TEN));
+    }
+
+    #[test_case(ClientId(1), ClientId(2); "from a locked client")]
+    #[test_case(ClientId(2), ClientId(1); "to a locked client")]
+    fn reject_transfer_with_locked_client(from: ClientId, to: ClientId) {
+        let mut payments_engine = engine_with_locked_client();
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(4),
+                ClientId(2),
+                Amount(Decimal::TEN),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(5),
+                from,
+                to,
+                Amount(Decimal::ONE),
+                Currency::XXX,
+            ))),
+            Err(Error::ClientLocked)
+        );
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::ONE_HUNDRED));
+        assert_eq!(available(&payments_engine, 2), Amount(Decimal::TEN));
+    }
+
+    #[test]
+    fn transfer_dispute_holds_recipient_funds() {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::from(30)),
+                Currency::XXX,
+            )))
+            .expect("transfer error");
+
+        let dispute = Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(3),
+            amount: None,
+            currency: None,
+        };
+        payments_engine
+            .recv_tx(Transaction::Dispute(dispute))
+            .expect("dispute error");
+
+        let recipient = payments_engine.client_list[&ClientId(2)].balance(Currency::XXX);
+        assert_eq!(recipient.available, Amount(Decimal::TEN));
+        assert_eq!(recipient.held, Amount(Decimal::from(30)));
+
+        payments_engine
+            .recv_tx(Transaction::Resolve(Resolve {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(3),
+                amount: None,
+                currency: None,
+            }))
+            .expect("resolve error");
+
+        let recipient = payments_engine.client_list[&ClientId(2)].balance(Currency::XXX);
+        assert_eq!(recipient.available, Amount(Decimal::from(40)));
+        assert_eq!(recipient.held, Amount::ZERO);
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)].dispute_history(TransactionId(3))[0]
+                .transaction,
+            Transaction::Dispute(dispute)
+        );
+    }
+
+    #[test]
+    fn transfer_chargeback_returns_funds_to_sender() {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::from(30)),
+                Currency::XXX,
+            )))
+            .expect("transfer error");
+
+        for transaction in [
+            Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(3),
+                amount: Some(Amount(Decimal::from(20))),
+                currency: None,
+            }),
+            Transaction::Chargeback(Chargeback {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(3),
+                amount: None,
+                currency: None,
+            }),
+        ] {
+            payments_engine.recv_tx(transaction).expect("dispute error");
+        }
+
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::from(90)));
+        assert_eq!(available(&payments_engine, 2), Amount(Decimal::from(20)));
+        let recipient = &payments_engine.client_list[&ClientId(2)];
+        assert_eq!(recipient.balance(Currency::XXX).held, Amount::ZERO);
+        assert!(!recipient.locked);
+        assert!(matches!(
+            payments_engine.client_list[&ClientId(1)]
+                .transaction_list
+                .get(&TransactionId(3)),
+            Some(TransactionRecord::Transfer {
+                funds: Disputable {
+                    status: DisputeStatus::Chargebacked,
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn only_sender_can_dispute_transfer() {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::from(30)),
+                Currency::XXX,
+            )))
+            .expect("transfer error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Dispute(Dispute {
+                client_id: ClientId(2),
+                target_transaction_id: TransactionId(3),
+                amount: None,
+                currency: None,
+            })),
+            Err(Error::TransactionClientMismatch)
+        );
+    }
+
+    fn dispute_flow(action: DisputeAction, client_id: u16, target: u32) -> Transaction {
+        let (client_id, target_transaction_id) = (ClientId(client_id), TransactionId(target));
+        match action {
+            DisputeAction::Dispute => Transaction::Dispute(Dispute {
+                client_id,
+                target_transaction_id,
+                amount: None,
+                currency: None,
+            }),
+            DisputeAction::Resolve => Transaction::Resolve(Resolve {
+                client_id,
+                target_transaction_id,
+                amount: None,
+                currency: None,
+            }),
+            DisputeAction::Chargeback => Transaction::Chargeback(Chargeback {
+                client_id,
+                target_transaction_id,
+                amount: None,
+                currency: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn ledger_stays_balanced() {
+        let mut payments_engine = engine_with_disputed_withdraw();
+
+        for transaction in [
+            dispute_flow(DisputeAction::Chargeback, 1, 2),
+            Transaction::Deposit(Deposit::new(
+                TransactionId(3),
+                ClientId(2),
+                Amount(Decimal::from(50)),
+                Currency::EUR,
+            )),
+            Transaction::Deposit(Deposit::new(
+                TransactionId(4),
+                ClientId(1),
+                Amount(Decimal::from(20)),
+                Currency::EUR,
+            )),
+            Transaction::Transfer(Transfer::new(
+                TransactionId(5),
+                ClientId(2),
+                ClientId(1),
+                Amount(Decimal::from(15)),
+                Currency::EUR,
+            )),
+            dispute_flow(DisputeAction::Dispute, 2, 5),
+            dispute_flow(DisputeAction::Chargeback, 2, 5),
+            dispute_flow(DisputeAction::Dispute, 1, 4),
+            dispute_flow(DisputeAction::Resolve, 1, 4),
+            dispute_flow(DisputeAction::Dispute, 2, 3),
+            dispute_flow(DisputeAction::Chargeback, 2, 3),
+        ] {
+            payments_engine
+                .recv_tx(transaction)
+                .expect("transaction error");
+        }
+
+        let trial_balance = payments_engine.trial_balance().expect("overflow");
+        assert_eq!(trial_balance.total(Currency::XXX), Ok(Amount::ZERO));
+        assert_eq!(trial_balance.total(Currency::EUR), Ok(Amount::ZERO));
+        assert_eq!(
+            trial_balance.balance(Account::ChargebackLoss, Currency::XXX),
+            Amount(Decimal::from(-40))
+        );
+        assert_eq!(
+            trial_balance.balance(Account::Settlement, Currency::EUR),
+            Amount(Decimal::from(-20))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn rejected_transactions_post_nothing() {
+        let mut payments_engine = engine_with_two_clients();
+        let entries = payments_engine.ledger().entries().len();
+
+        assert!(payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(2),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .is_err());
+
+        assert_eq!(payments_engine.ledger().entries().len(), entries);
+        assert_eq!(payments_engine.ledger().latest_entries(), &[]);
+    }
+
+    #[test]
+    fn reconcile_detects_balance_changed_outside_the_ledger() {
+        let mut payments_engine = engine_with_two_clients();
+
+        payments_engine
+            .client_list
+            .get_mut(&ClientId(2))
+            .expect("client id doesn't exist...")
+            .balances
+            .insert(
+                Currency::XXX,
+                Balance {
+                    available: Amount(Decimal::ONE_HUNDRED),
+                    held: Amount::ZERO,
+                    reserved: Amount::ZERO,
+                    receivable: Amount::ZERO,
+                },
+            );
+
+        assert_eq!(
+            payments_engine.reconcile(),
+            Err(Error::LedgerMismatch(ClientId(2)))
+        );
+    }
+
+    fn authorize(transaction_id: u32, amount: u32) -> Transaction {
+        Transaction::Authorize(Authorize::new(
+            TransactionId(transaction_id),
+            ClientId(1),
+            Amount(Decimal::from(amount)),
+            Currency::XXX,
+        ))
+    }
+
+    fn capture(target: u32, amount: Option<u32>) -> Transaction {
+        Transaction::Capture(Capture {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(target),
+            amount: amount.map(|amount| Amount(Decimal::from(amount))),
+            currency: None,
+        })
+    }
+
+    fn authorization(payments_engine: &PaymentsEngine, transaction_id: u32) -> Authorization {
+        match payments_engine.client_list[&ClientId(1)]
+            .transaction_list
+            .get(&TransactionId(transaction_id))
+        {
+            Some(TransactionRecord::Authorize(authorization)) => **authorization,
+            transaction => panic!("not an authorization: {:?}", transaction),
+        }
+    }
+
+    #[test]
+    fn authorize_reserves_funds() {
+        let mut payments_engine = engine_with_two_clients();
+
+        payments_engine
+            .recv_tx(authorize(3, 30))
+            .expect("authorization error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(Decimal::from(70)));
+        assert_eq!(balance.reserved, Amount(Decimal::from(30)));
+        assert_eq!(balance.total(), Ok(Amount(Decimal::ONE_HUNDRED)));
+        assert_eq!(
+            payments_engine.recv_tx(authorize(4, 80)),
+            Err(Error::WithdrawMoreThanAvailable)
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test_case(None, 30, 70 ; "full capture")]
+    #[test_case(Some(10), 10, 90 ; "partial capture releases the rest")]
+    fn capture_authorization(amount: Option<u32>, captured: u32, available: u32) {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(authorize(3, 30))
+            .expect("authorization error");
+
+        payments_engine
+            .recv_tx(capture(3, amount))
+            .expect("capture error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(Decimal::from(available)));
+        assert_eq!(balance.reserved, Amount::ZERO);
+        let authorize = authorization(&payments_engine, 3);
+        assert_eq!(authorize.status, AuthorizationStatus::Captured);
+        assert_eq!(authorize.captured, Amount(Decimal::from(captured)));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn void_releases_funds() {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(authorize(3, 30))
+            .expect("authorization error");
+
+        payments_engine
+            .recv_tx(Transaction::Void(Void {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(3),
+            }))
+            .expect("void error");
+
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::ONE_HUNDRED));
+        assert_eq!(
+            authorization(&payments_engine, 3).status,
+            AuthorizationStatus::Voided
+        );
+        assert_eq!(
+            payments_engine.recv_tx(capture(3, None)),
+            Err(Error::AuthorizationClosed(AuthorizationStatus::Voided))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test_case(capture(3, Some(31)), Error::CaptureMoreThanAuthorized ; "more than authorized")]
+    #[test_case(capture(3, Some(0)), Error::InvalidCaptureAmount ; "zero amount")]
+    #[test_case(capture(1, None), Error::AuthorizationError ; "not an authorization")]
+    fn reject_capture(transaction: Transaction, error: Error) {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(authorize(3, 30))
+            .expect("authorization error");
+
+        assert_eq!(payments_engine.recv_tx(transaction), Err(error));
+        assert_eq!(
+            authorization(&payments_engine, 3).status,
+            AuthorizationStatus::Open
+        );
+    }
+
+    #[test]
+    fn authorization_expires() {
+        let config = EngineConfig {
+            authorization_lifetime: 2,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        for transaction_id in 1..=2 {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::TEN),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+        }
+        payments_engine
+            .recv_tx(authorize(3, 5))
+            .expect("authorization error");
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(4),
+                ClientId(1),
+                Amount(Decimal::TEN),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        assert_eq!(
+            authorization(&payments_engine, 3).status,
+            AuthorizationStatus::Open
+        );
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(5),
+                ClientId(1),
+                Amount(Decimal::TEN),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        // expired after two more accepted transactions
+        assert_eq!(
+            payments_engine.recv_tx(capture(3, None)),
+            Err(Error::AuthorizationClosed(AuthorizationStatus::Expired))
+        );
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::from(40)));
+        // posted at the sequence the rejected capture would have taken
+        let entries = payments_engine.ledger().latest_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sequence, 6);
+        assert_eq!(entries[0].from, Account::Reserved(ClientId(1)));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn authorization_expires_once_funds_fit() {
+        let config = EngineConfig {
+            max_deposit: Amount(Decimal::MAX),
+            authorization_lifetime: 1,
+            credit_lines: vec![CreditLine {
+                client: ClientId(2),
+                currency: None,
+                credit_limit: Amount(Decimal::TEN),
+            }],
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        for (transaction_id, client_id, amount) in
+            [(1, 1, Decimal::MAX - Decimal::ONE), (2, 2, Decimal::ONE)]
+        {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(client_id),
+                    Amount(amount),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+        }
+        payments_engine
+            .recv_tx(authorize(3, 10))
+            .expect("authorization error");
+        // brings available up to the largest amount there is, releasing the
+        // authorization on top of it would overflow
+        payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(4),
+                ClientId(2),
+                ClientId(1),
+                Amount(Decimal::from(11)),
+                Currency::XXX,
+            )))
+            .expect("transfer error");
+        payments_engine
+            .recv_tx(withdraw(5, 10))
+            .expect("withdraw amount error");
+        assert_eq!(
+            authorization(&payments_engine, 3).status,
+            AuthorizationStatus::Open
+        );
+
+        payments_engine
+            .recv_tx(withdraw(6, 1))
+            .expect("withdraw amount error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(Decimal::MAX - Decimal::ONE));
+        assert_eq!(balance.reserved, Amount::ZERO);
+        assert_eq!(
+            authorization(&payments_engine, 3).status,
+            AuthorizationStatus::Expired
+        );
+    }
+
+    fn withdraw(transaction_id: u32, amount: u32) -> Transaction {
+        Transaction::Withdraw(Withdraw::new(
+            TransactionId(transaction_id),
+            ClientId(1),
+            Amount(Decimal::from(amount)),
+            Currency::XXX,
+        ))
+    }
+
+    fn set_credit_limit(transaction_id: u32, credit_limit: Decimal) -> Transaction {
+        Transaction::CreditLimit(CreditLimit {
+            transaction_id: TransactionId(transaction_id),
+            client_id: ClientId(1),
+            credit_limit: Amount(credit_limit),
+            currency: Currency::XXX,
+        })
+    }
+
+    #[test]
+    fn withdraw_within_credit_line() {
+        let config = EngineConfig {
+            credit_lines: vec![CreditLine {
+                client: ClientId(1),
+                currency: None,
+                credit_limit: Amount(Decimal::from(50)),
+            }],
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+
+        payments_engine
+            .recv_tx(withdraw(2, 130))
+            .expect("withdraw error");
+        assert_eq!(
+            payments_engine.recv_tx(withdraw(3, 21)),
+            Err(Error::WithdrawMoreThanAvailable)
         );
 
-        assert_eq!(client, &fake_client);
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(Decimal::from(-30)));
+        assert_eq!(
+            balance.headroom(payments_engine.credit_limit(ClientId(1), Currency::XXX)),
+            Ok(Amount(Decimal::from(20)))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn credit_limit_set_by_admin() {
+        let mut payments_engine = engine_with_two_clients();
+
+        payments_engine
+            .recv_tx(set_credit_limit(3, Decimal::from(40)))
+            .expect("credit limit error");
+        payments_engine
+            .recv_tx(withdraw(4, 120))
+            .expect("withdraw error");
+
+        assert_eq!(available(&payments_engine, 1), Amount(Decimal::from(-20)));
+        assert_eq!(
+            payments_engine.credit_limit(ClientId(1), Currency::XXX),
+            Amount(Decimal::from(40))
+        );
+        assert_eq!(
+            payments_engine.credit_limit(ClientId(1), Currency::EUR),
+            Amount::ZERO
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test_case(dec!(-1), Error::NegativeCreditLimit ; "negative limit")]
+    #[test_case(dec!(19), Error::OverCreditLimit ; "below the overdraft")]
+    fn reject_credit_limit(credit_limit: Decimal, error: Error) {
+        let mut payments_engine = engine_with_two_clients();
+        payments_engine
+            .recv_tx(set_credit_limit(3, Decimal::from(40)))
+            .expect("credit limit error");
+        payments_engine
+            .recv_tx(withdraw(4, 120))
+            .expect("withdraw error");
+
+        assert_eq!(
+            payments_engine.recv_tx(set_credit_limit(5, credit_limit)),
+            Err(error)
+        );
+        assert_eq!(
+            payments_engine.credit_limit(ClientId(1), Currency::XXX),
+            Amount(Decimal::from(40))
+        );
+    }
+
+    fn engine_with_spent_deposit(dispute_shortfall: ShortfallPolicy) -> PaymentsEngine {
+        let config = EngineConfig {
+            dispute_shortfall,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        for transaction in [
+            Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )),
+            withdraw(2, 70),
+        ] {
+            payments_engine
+                .recv_tx(transaction)
+                .expect("transaction error");
+        }
+        payments_engine
+    }
+
+    #[test_case(ShortfallPolicy::AllowNegative, dec!(-70), dec!(100), dec!(0) ; "allow negative")]
+    #[test_case(ShortfallPolicy::HoldAvailable, dec!(0), dec!(100), dec!(70) ; "hold available")]
+    fn dispute_spent_deposit(
+        dispute_shortfall: ShortfallPolicy,
+        available: Decimal,
+        held: Decimal,
+        receivable: Decimal,
+    ) {
+        let mut payments_engine = engine_with_spent_deposit(dispute_shortfall);
+
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1))
+            .expect("dispute error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(available));
+        assert_eq!(balance.held, Amount(held));
+        assert_eq!(balance.receivable, Amount(receivable));
+        assert_eq!(balance.total(), Ok(Amount(dec!(30))));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn reject_dispute_of_spent_deposit() {
+        let mut payments_engine = engine_with_spent_deposit(ShortfallPolicy::Reject);
+
+        assert_eq!(
+            payments_engine.recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1)),
+            Err(Error::DisputeMoreThanAvailable)
+        );
+        assert_eq!(available(&payments_engine, 1), Amount(dec!(30)));
+    }
+
+    #[test]
+    fn resolve_cancels_receivable() {
+        let mut payments_engine = engine_with_spent_deposit(ShortfallPolicy::HoldAvailable);
+
+        for action in [DisputeAction::Dispute, DisputeAction::Resolve] {
+            payments_engine
+                .recv_tx(dispute_flow(action, 1, 1))
+                .expect("dispute flow error");
+        }
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(dec!(30)));
+        assert_eq!(balance.receivable, Amount::ZERO);
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn deposits_recover_receivable() {
+        let mut payments_engine = engine_with_spent_deposit(ShortfallPolicy::HoldAvailable);
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1))
+            .expect("dispute error");
+
+        for (transaction_id, amount, available, receivable) in
+            [(3, 50, dec!(0), dec!(20)), (4, 30, dec!(10), dec!(0))]
+        {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::from(amount)),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+
+            let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+            assert_eq!(balance.available, Amount(available));
+            assert_eq!(balance.receivable, Amount(receivable));
+        }
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Chargeback, 1, 1))
+            .expect("chargeback error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.total(), Ok(Amount(dec!(10))));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn locked_client_pays_back_receivable() {
+        let mut payments_engine = engine_with_spent_deposit(ShortfallPolicy::HoldAvailable);
+        for action in [DisputeAction::Dispute, DisputeAction::Chargeback] {
+            payments_engine
+                .recv_tx(dispute_flow(action, 1, 1))
+                .expect("dispute flow error");
+        }
+        assert!(payments_engine.client_list[&ClientId(1)].locked);
+
+        for (transaction_id, amount, available, receivable) in
+            [(3, 50, dec!(0), dec!(20)), (4, 30, dec!(10), dec!(0))]
+        {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::from(amount)),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+
+            let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+            assert_eq!(balance.available, Amount(available));
+            assert_eq!(balance.receivable, Amount(receivable));
+        }
+        // nothing is owed any more
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(5),
+                ClientId(1),
+                Amount(Decimal::ONE),
+                Currency::XXX,
+            ))),
+            Err(Error::ClientLocked)
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    fn engine_with_fees(
+        dispute_shortfall: ShortfallPolicy,
+        credit_lines: Vec<CreditLine>,
+    ) -> PaymentsEngine {
+        let config = EngineConfig {
+            fees: FeeSchedule {
+                withdrawal: Fee {
+                    flat: Amount(dec!(1)),
+                    ..Fee::default()
+                },
+                dispute: Fee {
+                    percentage: dec!(10),
+                    ..Fee::default()
+                },
+                chargeback: Fee {
+                    flat: Amount(dec!(2)),
+                    max: Some(Amount(dec!(1.5))),
+                    ..Fee::default()
+                },
+            },
+            dispute_shortfall,
+            credit_lines,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        payments_engine
+    }
+
+    #[test]
+    fn withdrawal_fee() {
+        let mut payments_engine = engine_with_fees(ShortfallPolicy::AllowNegative, vec![]);
+
+        payments_engine
+            .recv_tx(withdraw(2, 50))
+            .expect("withdraw error");
+        assert_eq!(
+            payments_engine.fee_lines(),
+            &[FeeLine {
+                sequence: 2,
+                client_id: ClientId(1),
+                kind: FeeKind::Withdrawal,
+                transaction_id: TransactionId(2),
+                currency: Currency::XXX,
+                amount: Amount(dec!(1)),
+            }]
+        );
+        // 49 would fit but not with its fee
+        assert_eq!(
+            payments_engine.recv_tx(withdraw(3, 49)),
+            Err(Error::WithdrawMoreThanAvailable)
+        );
+
+        assert_eq!(available(&payments_engine, 1), Amount(dec!(49)));
+        assert_eq!(
+            payments_engine.house().get(&Currency::XXX),
+            Some(&Amount(dec!(1)))
+        );
+        assert_eq!(payments_engine.fee_lines(), &[]);
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn every_fee_kept_with_its_client() {
+        let mut payments_engine = engine_with_fees(ShortfallPolicy::AllowNegative, vec![]);
+
+        for transaction_id in [2, 3] {
+            payments_engine
+                .recv_tx(withdraw(transaction_id, 10))
+                .expect("withdraw error");
+        }
+
+        let fee = |sequence, transaction_id| FeeLine {
+            sequence,
+            client_id: ClientId(1),
+            kind: FeeKind::Withdrawal,
+            transaction_id: TransactionId(transaction_id),
+            currency: Currency::XXX,
+            amount: Amount(dec!(1)),
+        };
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)].fees(),
+            &[fee(2, 2), fee(3, 3)]
+        );
+    }
+
+    #[test_case(ShortfallPolicy::AllowNegative, dec!(-11.5), dec!(0) ; "allow negative")]
+    #[test_case(ShortfallPolicy::HoldAvailable, dec!(0), dec!(11.5) ; "hold available")]
+    fn dispute_and_chargeback_fees(
+        dispute_shortfall: ShortfallPolicy,
+        available: Decimal,
+        receivable: Decimal,
+    ) {
+        let mut payments_engine = engine_with_fees(dispute_shortfall, vec![]);
+
+        for action in [DisputeAction::Dispute, DisputeAction::Chargeback] {
+            payments_engine
+                .recv_tx(dispute_flow(action, 1, 1))
+                .expect("dispute flow error");
+        }
+
+        let fees = payments_engine.client_list[&ClientId(1)]
+            .fees()
+            .iter()
+            .map(|line| (line.kind, line.amount))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            fees,
+            vec![
+                (FeeKind::Dispute, Amount(dec!(10))),
+                (FeeKind::Chargeback, Amount(dec!(1.5))),
+            ]
+        );
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(available));
+        assert_eq!(balance.receivable, Amount(receivable));
+        assert_eq!(
+            payments_engine.house().get(&Currency::XXX),
+            Some(&Amount(dec!(11.5)))
+        );
+        let trial_balance = payments_engine.trial_balance().expect("overflow");
+        assert_eq!(trial_balance.total(Currency::XXX), Ok(Amount::ZERO));
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn reject_fees_beyond_the_credit_limit() {
+        let mut payments_engine = engine_with_fees(
+            ShortfallPolicy::Reject,
+            vec![CreditLine {
+                client: ClientId(1),
+                currency: Some(Currency::XXX),
+                credit_limit: Amount(dec!(10)),
+            }],
+        );
+
+        // the dispute fee takes up the whole credit line
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1))
+            .expect("dispute error");
+        assert_eq!(
+            payments_engine.recv_tx(dispute_flow(DisputeAction::Chargeback, 1, 1)),
+            Err(Error::FeeMoreThanAvailable)
+        );
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(dec!(-10)));
+        assert_eq!(balance.held, Amount(dec!(100)));
+        assert_eq!(
+            payments_engine.house().get(&Currency::XXX),
+            Some(&Amount(dec!(10)))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn track_rounding_residue() {
+        let mut payments_engine = PaymentsEngine::new();
+
+        for (transaction_id, client_id, amount) in [(1, 1, dec!(1.23456)), (2, 2, dec!(10.00005))] {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(client_id),
+                    Amount(amount),
+                    Currency::XXX,
+                )))
+                .expect("deposit amount error");
+        }
+        payments_engine
+            .recv_tx(Transaction::Withdraw(Withdraw::new(
+                TransactionId(3),
+                ClientId(1),
+                Amount(dec!(0.00015)),
+                Currency::XXX,
+            )))
+            .expect("withdraw error");
+
+        // 1.23456 credited as 1.2346, 0.00015 debited as 0.0002
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)].rounding_residue(Currency::XXX),
+            Amount(dec!(0.00001))
+        );
+        // 10.00005 credited as 10.0000
+        assert_eq!(
+            payments_engine.client_list[&ClientId(2)].rounding_residue(Currency::XXX),
+            Amount(dec!(0.00005))
+        );
+        assert_eq!(
+            payments_engine.rounding_residue(Currency::XXX),
+            Ok(Amount(dec!(0.00006)))
+        );
+        let trial_balance = payments_engine.trial_balance().expect("overflow");
+        assert_eq!(
+            trial_balance.balance(Account::Settlement, Currency::XXX),
+            Amount(dec!(-11.23446))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn track_rounding_residue_of_every_rounded_amount() {
+        let mut payments_engine = engine_with_two_clients();
+        let dispute = |amount| {
+            Transaction::Dispute(Dispute {
+                client_id: ClientId(2),
+                target_transaction_id: TransactionId(2),
+                amount: Some(Amount(amount)),
+                currency: None,
+            })
+        };
+        for transaction in [
+            // 1.00015 sent as 1.0002
+            Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(dec!(1.00015)),
+                Currency::XXX,
+            )),
+            // 2.00015 reserved as 2.0002, 1.00005 captured as 1.0000
+            Transaction::Authorize(Authorize::new(
+                TransactionId(4),
+                ClientId(1),
+                Amount(dec!(2.00015)),
+                Currency::XXX,
+            )),
+            Transaction::Capture(Capture {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(4),
+                amount: Some(Amount(dec!(1.00005))),
+                currency: None,
+            }),
+            // 0.00015 held as 0.0002 and released as 0.0002
+            dispute(dec!(0.00015)),
+            Transaction::Resolve(Resolve {
+                client_id: ClientId(2),
+                target_transaction_id: TransactionId(2),
+                amount: Some(Amount(dec!(0.00015))),
+                currency: None,
+            }),
+        ] {
+            payments_engine
+                .recv_tx(transaction)
+                .expect("transaction error");
+        }
+
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)].rounding_residue(Currency::XXX),
+            Amount(dec!(0.00005))
+        );
+        assert_eq!(
+            payments_engine.client_list[&ClientId(2)].rounding_residue(Currency::XXX),
+            Amount(dec!(-0.00005))
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+        let trial_balance = payments_engine.trial_balance().expect("overflow");
+        assert_eq!(trial_balance.total(Currency::XXX), Ok(Amount::ZERO));
+    }
+
+    // client 1 holds 100 yen, client 2 holds 10 yen and a residue about to
+    // overflow
+    fn engine_with_yen(residue: Decimal) -> PaymentsEngine {
+        let mut config = EngineConfig::default();
+        config.limits.push(CurrencyLimits {
+            currency: Currency::JPY,
+            min_deposit: Amount(Decimal::ONE),
+            max_deposit: Amount(dec!(5000000)),
+            min_withdraw: Amount(Decimal::ONE),
+            max_withdraw: Amount(dec!(5000000)),
+        });
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+        for (transaction_id, client_id, amount) in [(1, 1, 100), (2, 2, 10)] {
+            payments_engine
+                .recv_tx(Transaction::Deposit(Deposit::new(
+                    TransactionId(transaction_id),
+                    ClientId(client_id),
+                    Amount(Decimal::from(amount)),
+                    Currency::JPY,
+                )))
+                .expect("deposit amount error");
+        }
+        payments_engine
+            .client_list
+            .get_mut(&ClientId(2))
+            .expect("no client")
+            .rounding_residue
+            .insert(Currency::JPY, Amount(residue));
+        payments_engine
+    }
+
+    #[test]
+    fn transfer_overflowing_the_recipient_residue() {
+        let mut payments_engine = engine_with_yen(Decimal::MIN);
+
+        // 1.5 sent as 2, the recipient's residue goes down by 0.5
+        let result = payments_engine.recv_tx(Transaction::Transfer(Transfer::new(
+            TransactionId(3),
+            ClientId(1),
+            ClientId(2),
+            Amount(dec!(1.5)),
+            Currency::JPY,
+        )));
+
+        assert_eq!(result, Err(Error::AmountOverflow));
+        let sender = &payments_engine.client_list[&ClientId(1)];
+        assert_eq!(sender.balance(Currency::JPY).available, Amount(dec!(100)));
+        assert_eq!(sender.rounding_residue(Currency::JPY), Amount::ZERO);
+        assert!(!sender.transaction_list.contains_key(&TransactionId(3)));
+        let recipient = &payments_engine.client_list[&ClientId(2)];
+        assert_eq!(recipient.balance(Currency::JPY).available, Amount(dec!(10)));
+        assert!(!recipient.transaction_list.contains_key(&TransactionId(3)));
+        assert!(payments_engine.ledger().latest_entries().is_empty());
+    }
+
+    #[test]
+    fn dispute_overflowing_the_holder_residue() {
+        let mut payments_engine = engine_with_yen(Decimal::MAX);
+
+        // 1.5 held as 2, the holder's residue goes up by 0.5
+        let result = payments_engine.recv_tx(Transaction::Dispute(Dispute {
+            client_id: ClientId(2),
+            target_transaction_id: TransactionId(2),
+            amount: Some(Amount(dec!(1.5))),
+            currency: None,
+        }));
+
+        assert_eq!(result, Err(Error::AmountOverflow));
+        let client = &payments_engine.client_list[&ClientId(2)];
+        let balance = client.balance(Currency::JPY);
+        assert_eq!(balance.available, Amount(dec!(10)));
+        assert_eq!(balance.held, Amount::ZERO);
+        assert_eq!(client.rounding_residue(Currency::JPY), Amount(Decimal::MAX));
+        assert!(client.dispute_history(TransactionId(2)).is_empty());
+        assert!(payments_engine.ledger().latest_entries().is_empty());
+    }
+
+    #[test]
+    fn amount_from_json_number() {
+        assert_eq!(
+            serde_json::from_str::<Amount>("10.5").ok(),
+            Some(Amount(dec!(10.5)))
+        );
+        assert_eq!(
+            serde_json::from_str::<Amount>("\"0.1234567890123456789\"").ok(),
+            Some(Amount(dec!(0.1234567890123456789)))
+        );
+        let transaction: Transaction = serde_json::from_str(
+            r#"{"type": "deposit", "transaction_id": 1, "client_id": 1, "amount": 10.5, "currency": "EUR"}"#,
+        )
+        .expect("json error");
+        assert_eq!(
+            transaction,
+            Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(dec!(10.5)),
+                Currency::EUR,
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_excess_precision() {
+        let config = EngineConfig {
+            reject_excess_precision: true,
+            ..EngineConfig::default()
+        };
+        let mut payments_engine = PaymentsEngine::with_config(config).expect("config error");
+
+        assert_eq!(
+            payments_engine.recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(dec!(1.23456)),
+                Currency::XXX,
+            ))),
+            Err(Error::ExcessPrecision)
+        );
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(dec!(1.2345)),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        assert_eq!(
+            payments_engine.rounding_residue(Currency::XXX),
+            Ok(Amount::ZERO)
+        );
+    }
+
+    fn deposit(transaction_id: u32, amount: u32) -> Transaction {
+        Transaction::Deposit(Deposit::new(
+            TransactionId(transaction_id),
+            ClientId(1),
+            Amount(Decimal::from(amount)),
+            Currency::XXX,
+        ))
+    }
+
+    fn engine_with_history(
+        dispute_window: Option<u64>,
+        spill_after: Option<u64>,
+    ) -> PaymentsEngine {
+        let config = EngineConfig {
+            dispute_window,
+            spill_after,
+            ..EngineConfig::default()
+        };
+        PaymentsEngine::with_config(config).expect("config error")
+    }
+
+    #[test]
+    fn evict_transactions_past_dispute_window() {
+        let mut payments_engine = engine_with_history(Some(2), None);
+        for transaction_id in 1..=3 {
+            payments_engine
+                .recv_tx(deposit(transaction_id, 10))
+                .expect("deposit amount error");
+        }
+
+        assert_eq!(
+            payments_engine.recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1)),
+            Err(Error::DisputeWindowClosed)
+        );
+        assert!(!payments_engine.client_list[&ClientId(1)]
+            .transaction_list
+            .contains_key(&TransactionId(1)));
+        // the id is still taken
+        assert_eq!(
+            payments_engine.recv_tx(deposit(1, 10)),
+            Err(Error::DuplicateTransaction)
+        );
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 3))
+            .expect("dispute error");
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn evict_transfer_from_both_clients() {
+        let mut payments_engine = engine_with_history(Some(2), None);
+        payments_engine
+            .recv_tx(deposit(1, 10))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(2),
+                ClientId(2),
+                Amount(Decimal::TEN),
+                Currency::XXX,
+            )))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::ONE),
+                Currency::XXX,
+            )))
+            .expect("transfer error");
+        for transaction_id in 4..=6 {
+            payments_engine
+                .recv_tx(deposit(transaction_id, 10))
+                .expect("deposit amount error");
+        }
+
+        for client_id in [ClientId(1), ClientId(2)] {
+            assert!(!payments_engine.client_list[&client_id]
+                .transaction_list
+                .contains_key(&TransactionId(3)));
+        }
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn keep_disputed_transaction_past_dispute_window() {
+        let mut payments_engine = engine_with_history(Some(1), None);
+        payments_engine
+            .recv_tx(deposit(1, 10))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1))
+            .expect("dispute error");
+        for transaction_id in 2..=4 {
+            payments_engine
+                .recv_tx(deposit(transaction_id, 10))
+                .expect("deposit amount error");
+        }
+
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Resolve, 1, 1))
+            .expect("resolve error");
+        assert_eq!(
+            payments_engine.client_list[&ClientId(1)]
+                .balance(Currency::XXX)
+                .available,
+            Amount(Decimal::from(40))
+        );
+    }
+
+    #[test]
+    fn dispute_spilled_transaction() {
+        let path = std::env::temp_dir().join(format!(
+            "payments_engine_{}_dispute.spill",
+            std::process::id()
+        ));
+        let mut payments_engine = engine_with_history(Some(100), Some(1));
+        payments_engine.spill_history(SpillFile::create(&path).expect("spill error"));
+        for transaction_id in 1..=3 {
+            payments_engine
+                .recv_tx(deposit(transaction_id, 10))
+                .expect("deposit amount error");
+        }
+        assert!(!payments_engine.client_list[&ClientId(1)]
+            .transaction_list
+            .contains_key(&TransactionId(1)));
+
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Dispute, 1, 1))
+            .expect("dispute error");
+        payments_engine
+            .recv_tx(deposit(4, 10))
+            .expect("deposit amount error");
+        payments_engine
+            .recv_tx(dispute_flow(DisputeAction::Chargeback, 1, 1))
+            .expect("chargeback error");
+
+        let balance = payments_engine.client_list[&ClientId(1)].balance(Currency::XXX);
+        assert_eq!(balance.available, Amount(Decimal::from(30)));
+        assert_eq!(balance.held, Amount::ZERO);
+        assert_eq!(
+            payments_engine.recv_tx(dispute_flow(DisputeAction::Dispute, 2, 2)),
+            Err(Error::TransactionClientMismatch)
+        );
+        assert_eq!(payments_engine.reconcile(), Ok(()));
+        std::fs::remove_file(&path).expect("cleanup error");
+    }
+
+    // fails every commit once told to
+    #[derive(Default)]
+    struct FailingStore {
+        clients: MemoryStore,
+        fail: bool,
+    }
+
+    impl ClientStore for FailingStore {
+        fn get(&self, client_id: &ClientId) -> Option<&Client> {
+            self.clients.get(client_id)
+        }
+
+        fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Client> {
+            self.clients.get_mut(client_id)
+        }
+
+        fn insert(&mut self, client_id: ClientId, client: Client) -> Option<Client> {
+            self.clients.insert(client_id, client)
+        }
+
+        fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+            self.clients.values()
+        }
+
+        fn commit(&mut self, _commit: Commit) -> Result<(), StoreError> {
+            if self.fail {
+                return Err(StoreError::Malformed(String::from("disk full")));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stop_after_a_failed_commit() {
+        let mut payments_engine =
+            PaymentsEngine::with_store(EngineConfig::default(), FailingStore::default())
+                .expect("config error");
+        payments_engine
+            .recv_tx(deposit(1, 10))
+            .expect("deposit amount error");
+
+        payments_engine.client_list.fail = true;
+        assert!(matches!(
+            payments_engine.recv_tx(deposit(2, 10)),
+            Err(Error::Storage(_))
+        ));
+        payments_engine.client_list.fail = false;
+        // the store never got deposit 2, so nothing may build on it
+        assert!(matches!(
+            payments_engine.recv_tx(deposit(2, 10)),
+            Err(Error::Storage(_))
+        ));
+        assert!(matches!(
+            payments_engine.recv_tx(deposit(3, 10)),
+            Err(Error::Storage(_))
+        ));
     }
 }
@@ -0,0 +1,326 @@
+use crate::{Balance, Client, ClientId, ClientStore, Currency, Error, PaymentsEngine, Transaction};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+type Submission = (Transaction, oneshot::Sender<Result<(), Error>>);
+
+// balances of every client as of the last transaction the engine applied
+type Published = Arc<RwLock<HashMap<ClientId, ClientBalances>>>;
+
+// what a balance query sees of a client, copied out of the engine
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientBalances {
+    pub client_id: ClientId,
+    pub balances: BTreeMap<Currency, Balance>,
+    pub locked: bool,
+}
+
+impl From<&Client> for ClientBalances {
+    fn from(client: &Client) -> ClientBalances {
+        ClientBalances {
+            client_id: client.client_id,
+            balances: client.balances.clone(),
+            locked: client.locked,
+        }
+    }
+}
+
+// Feeds an engine started with PaymentsEngine::spawn and reads the balances
+// it published. Every clone feeds the same engine, which stops once the last
+// one is dropped.
+#[derive(Clone)]
+pub struct EngineHandle {
+    transactions: mpsc::Sender<Submission>,
+    balances: Published,
+}
+
+// outcome of a queued transaction, resolves once the engine applied or
+// rejected it
+#[derive(Debug)]
+pub struct Reply(oneshot::Receiver<Result<(), Error>>);
+
+impl Future for Reply {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|outcome| outcome.unwrap_or(Err(Error::EngineStopped)))
+    }
+}
+
+impl<S: ClientStore + Send + 'static> PaymentsEngine<S> {
+    // Moves the engine to a blocking thread of the current tokio runtime,
+    // since applying a transaction may wait on the store. At most capacity
+    // transactions, which must be positive, are queued, sending more waits
+    // for room. Every client is loaded first, and after each transaction the
+    // balances of the clients it touched are published before it's answered,
+    // so balance queries never wait on the engine. The join handle gives the
+    // engine back once every handle is dropped.
+    pub fn spawn(
+        mut self,
+        capacity: usize,
+    ) -> Result<(EngineHandle, JoinHandle<PaymentsEngine<S>>), Error> {
+        self.load_clients()?;
+        let balances: Published = Arc::new(RwLock::new(
+            self.clients()
+                .map(|client| (client.client_id, ClientBalances::from(client)))
+                .collect(),
+        ));
+        let (transactions, mut pending) = mpsc::channel::<Submission>(capacity);
+
+        let published = Arc::clone(&balances);
+        let engine = task::spawn_blocking(move || {
+            while let Some((transaction, reply)) = pending.blocking_recv() {
+                let client_id = transaction.client_id();
+                let outcome = self.recv_tx(transaction);
+                self.publish(client_id, &published);
+                // the sender may have stopped waiting for it
+                let _ = reply.send(outcome);
+            }
+            self
+        });
+
+        Ok((
+            EngineHandle {
+                transactions,
+                balances,
+            },
+            engine,
+        ))
+    }
+
+    // Copies out the client of the latest transaction, which may have been
+    // locked or unlocked, and every client it posted to, like the recipient
+    // of a transfer or the owners of expired authorizations.
+    fn publish(&self, client_id: ClientId, published: &Published) {
+        let touched: BTreeSet<ClientId> = self
+            .ledger
+            .latest_entries()
+            .iter()
+            .flat_map(|entry| [entry.from, entry.to])
+            .filter_map(|account| account.client_id())
+            .chain([client_id])
+            .collect();
+        let mut published = published.write().unwrap_or_else(PoisonError::into_inner);
+        for client_id in touched {
+            if let Some(client) = self.client(client_id) {
+                published.insert(client_id, ClientBalances::from(client));
+            }
+        }
+    }
+}
+
+impl EngineHandle {
+    // queues the transaction, waiting while the queue is full
+    pub async fn send(&self, transaction: Transaction) -> Result<Reply, Error> {
+        let (reply, outcome) = oneshot::channel();
+        self.transactions
+            .send((transaction, reply))
+            .await
+            .map_err(|_| Error::EngineStopped)?;
+        Ok(Reply(outcome))
+    }
+
+    // queues the transaction and waits for the engine to apply it
+    pub async fn recv_tx(&self, transaction: Transaction) -> Result<(), Error> {
+        self.send(transaction).await?.await
+    }
+
+    // as of the last transaction the engine answered, queued ones aren't
+    // applied yet
+    pub fn balances(&self, client_id: ClientId) -> Option<ClientBalances> {
+        self.balances
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&client_id)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Amount, Commit, Deposit, EngineConfig, MemoryStore, StoreError, TransactionId, Transfer,
+        Withdraw,
+    };
+    use rust_decimal::Decimal;
+    use std::future;
+    use std::sync::mpsc as std_mpsc;
+
+    fn deposit(tx: u32, client: u16, amount: Decimal) -> Transaction {
+        Transaction::Deposit(Deposit::new(
+            TransactionId(tx),
+            ClientId(client),
+            Amount(amount),
+            Currency::XXX,
+        ))
+    }
+
+    fn available(balances: Option<ClientBalances>) -> Option<Amount> {
+        balances.map(|client| client.balances[&Currency::XXX].available)
+    }
+
+    // holds every commit until the engine is released, all of them once the
+    // sender is dropped
+    struct BlockingStore {
+        clients: MemoryStore,
+        released: std_mpsc::Receiver<()>,
+    }
+
+    impl ClientStore for BlockingStore {
+        fn get(&self, client_id: &ClientId) -> Option<&Client> {
+            self.clients.get(client_id)
+        }
+
+        fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Client> {
+            self.clients.get_mut(client_id)
+        }
+
+        fn insert(&mut self, client_id: ClientId, client: Client) -> Option<Client> {
+            self.clients.insert(client_id, client)
+        }
+
+        fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+            self.clients.values()
+        }
+
+        fn commit(&mut self, _commit: Commit) -> Result<(), StoreError> {
+            let _ = self.released.recv();
+            Ok(())
+        }
+    }
+
+    fn blocked_engine() -> (PaymentsEngine<BlockingStore>, std_mpsc::Sender<()>) {
+        let (release, released) = std_mpsc::channel();
+        let store = BlockingStore {
+            clients: MemoryStore::default(),
+            released,
+        };
+        let engine =
+            PaymentsEngine::with_store(EngineConfig::default(), store).expect("config error");
+        (engine, release)
+    }
+
+    #[tokio::test]
+    async fn reply_per_transaction() {
+        let (handle, engine) = PaymentsEngine::new().spawn(4).expect("store error");
+
+        assert_eq!(handle.recv_tx(deposit(1, 1, Decimal::TEN)).await, Ok(()));
+        assert_eq!(
+            handle
+                .recv_tx(Transaction::Withdraw(Withdraw::new(
+                    TransactionId(2),
+                    ClientId(1),
+                    Amount(Decimal::ONE_HUNDRED),
+                    Currency::XXX,
+                )))
+                .await,
+            Err(Error::WithdrawMoreThanAvailable)
+        );
+        assert_eq!(
+            handle.recv_tx(deposit(1, 1, Decimal::ONE)).await,
+            Err(Error::DuplicateTransaction)
+        );
+        assert_eq!(
+            available(handle.balances(ClientId(1))),
+            Some(Amount(Decimal::TEN))
+        );
+        assert_eq!(handle.balances(ClientId(2)), None);
+
+        drop(handle);
+        let engine = engine.await.expect("engine panicked");
+        assert!(engine.client(ClientId(1)).is_some());
+    }
+
+    #[tokio::test]
+    async fn publish_every_client_posted_to() {
+        let mut engine = PaymentsEngine::new();
+        engine
+            .recv_tx(deposit(1, 2, Decimal::ONE))
+            .expect("deposit amount error");
+        let (handle, _engine) = engine.spawn(4).expect("store error");
+        assert_eq!(
+            available(handle.balances(ClientId(2))),
+            Some(Amount(Decimal::ONE))
+        );
+
+        handle
+            .recv_tx(deposit(2, 1, Decimal::TEN))
+            .await
+            .expect("deposit amount error");
+        handle
+            .recv_tx(Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::TWO),
+                Currency::XXX,
+            )))
+            .await
+            .expect("transfer error");
+
+        assert_eq!(
+            available(handle.balances(ClientId(1))),
+            Some(Amount(Decimal::from(8)))
+        );
+        assert_eq!(
+            available(handle.balances(ClientId(2))),
+            Some(Amount(Decimal::from(3)))
+        );
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_room() {
+        let (engine, release) = blocked_engine();
+        let (handle, _engine) = engine.spawn(1).expect("store error");
+
+        // taken off the queue by the engine, which then blocks on the store
+        let first = handle
+            .send(deposit(1, 1, Decimal::ONE))
+            .await
+            .expect("engine stopped");
+        let second = handle
+            .send(deposit(2, 1, Decimal::ONE))
+            .await
+            .expect("engine stopped");
+        let third = handle.send(deposit(3, 1, Decimal::ONE));
+        tokio::pin!(third);
+        tokio::select! {
+            biased;
+            _ = &mut third => panic!("queue past its capacity"),
+            _ = future::ready(()) => {}
+        }
+
+        drop(release);
+        let third = third.await.expect("engine stopped");
+        assert_eq!(first.await, Ok(()));
+        assert_eq!(second.await, Ok(()));
+        assert_eq!(third.await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn queries_dont_wait_for_the_engine() {
+        let (engine, release) = blocked_engine();
+        let (handle, _engine) = engine.spawn(4).expect("store error");
+
+        let reply = handle
+            .send(deposit(1, 1, Decimal::ONE))
+            .await
+            .expect("engine stopped");
+        assert_eq!(handle.balances(ClientId(1)), None);
+
+        release.send(()).expect("engine stopped");
+        assert_eq!(reply.await, Ok(()));
+        assert_eq!(
+            available(handle.balances(ClientId(1))),
+            Some(Amount(Decimal::ONE))
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use crate::{
+    Amount, ClientId, ConfigError, Currency, Disputable, DisputeStatus, EngineConfig, JournalEntry,
+    Ledger, MemoryStore, PaymentsEngine, TransactionId, TransactionIds, TransactionRecord,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+// A snapshot file starts with MAGIC and the format version as a little endian
+// u32, followed by the engine state as JSON. Whenever the serialized shape of
+// the state changes, bump SNAPSHOT_VERSION and add a step to migrate() that
+// upgrades the JSON of the previous version, so older files keep loading.
+pub const SNAPSHOT_VERSION: u32 = 2;
+const MAGIC: &[u8; 8] = b"PESNAP\0\0";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("snapshot i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("malformed snapshot: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error("not a snapshot file")]
+    BadMagic,
+
+    #[error("snapshot version {0} isn't supported")]
+    UnsupportedVersion(u32),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("snapshot was taken with a different config")]
+    ConfigMismatch,
+
+    #[error("history spilled to disk can't be saved in a snapshot")]
+    SpilledHistory,
+}
+
+// The config is given again on load and only kept to be checked against, as
+// a snapshot applied with another config would round or reject differently.
+#[derive(Serialize)]
+struct StateRef<'a> {
+    config: &'a EngineConfig,
+    client_list: &'a MemoryStore,
+    transaction_ids: &'a TransactionIds,
+    transaction_index: &'a HashMap<TransactionId, ClientId>,
+    evicted: &'a TransactionIds,
+    history: &'a BTreeSet<(u64, TransactionId)>,
+    sequence: u64,
+    ledger: &'a Ledger,
+    authorization_expiry: &'a BTreeSet<(u64, TransactionId)>,
+    house: &'a BTreeMap<Currency, Amount>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    // missing from version 1 snapshots, which load unchecked
+    #[serde(default)]
+    config: Option<EngineConfig>,
+    client_list: MemoryStore,
+    transaction_ids: TransactionIds,
+    transaction_index: HashMap<TransactionId, ClientId>,
+    evicted: TransactionIds,
+    history: BTreeSet<(u64, TransactionId)>,
+    sequence: u64,
+    ledger: Ledger,
+    authorization_expiry: BTreeSet<(u64, TransactionId)>,
+    house: BTreeMap<Currency, Amount>,
+}
+
+// Upgrades the state of an older snapshot one version at a time, each step
+// looks like `1 => migrate(2, v1_to_v2(state)?)`.
+fn migrate(version: u32, state: Value) -> Result<Value, SnapshotError> {
+    match version {
+        1 => migrate(2, v1_to_v2(state)?),
+        SNAPSHOT_VERSION => Ok(state),
+        _ => Err(SnapshotError::UnsupportedVersion(version)),
+    }
+}
+
+// how version 1 kept the transactions of a client, as they came in
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum V1Transaction {
+    Deposit(V1Funds),
+    Withdraw(V1Funds),
+    Transfer {
+        to: ClientId,
+        #[serde(flatten)]
+        funds: V1Funds,
+    },
+    Unlock {
+        reason: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct V1Funds {
+    amount: Amount,
+    currency: Currency,
+    dispute_status: DisputeStatus,
+    disputed: Amount,
+    charged_back: Amount,
+}
+
+// Version 1 didn't keep the resolved part, a resolved transaction stays
+// resolved for all it didn't charge back. Amounts are never negative, so the
+// differences can't overflow.
+impl From<V1Funds> for Disputable {
+    fn from(funds: V1Funds) -> Disputable {
+        let resolved = match funds.dispute_status {
+            DisputeStatus::Resolved => {
+                Amount(funds.amount.0 - funds.disputed.0 - funds.charged_back.0)
+            }
+            _ => Amount::ZERO,
+        };
+        Disputable::with_amounts(
+            funds.amount,
+            funds.currency,
+            funds.dispute_status,
+            funds.disputed,
+            funds.charged_back,
+            resolved,
+        )
+    }
+}
+
+// Version 1 had balances of available and held funds only, kept the
+// transactions as they came in and a transfer only in the sender's history,
+// and its ledger was the journal alone. Everything added since starts empty.
+fn v1_to_v2(mut state: Value) -> Result<Value, SnapshotError> {
+    let mut received = vec![];
+    if let Some(clients) = state["client_list"].as_object_mut() {
+        for client in clients.values_mut() {
+            if let Some(balances) = client["balances"].as_object_mut() {
+                for balance in balances.values_mut() {
+                    balance["reserved"] = Value::from("0");
+                    balance["receivable"] = Value::from("0");
+                }
+            }
+            client["credit_limits"] = Value::Object(Default::default());
+            client["fees"] = Value::Array(vec![]);
+            client["rounding_residue"] = Value::Object(Default::default());
+
+            let client_id: ClientId = serde_json::from_value(client["client_id"].clone())?;
+            let transactions: HashMap<TransactionId, V1Transaction> =
+                serde_json::from_value(client["transaction_list"].take())?;
+            let records: HashMap<TransactionId, TransactionRecord> = transactions
+                .into_iter()
+                .map(|(transaction_id, transaction)| {
+                    let record = match transaction {
+                        V1Transaction::Deposit(funds) => TransactionRecord::Deposit(funds.into()),
+                        V1Transaction::Withdraw(funds) => TransactionRecord::Withdraw(funds.into()),
+                        V1Transaction::Transfer { to, funds } => {
+                            received.push((
+                                to,
+                                transaction_id,
+                                TransactionRecord::ReceivedTransfer {
+                                    from: client_id,
+                                    amount: funds.amount,
+                                    currency: funds.currency,
+                                },
+                            ));
+                            TransactionRecord::Transfer {
+                                to,
+                                funds: funds.into(),
+                            }
+                        }
+                        V1Transaction::Unlock { reason } => TransactionRecord::Unlock { reason },
+                    };
+                    (transaction_id, record)
+                })
+                .collect();
+            client["transaction_list"] = serde_json::to_value(records)?;
+        }
+    }
+    for (recipient, transaction_id, record) in received {
+        let transactions = &mut state["client_list"][recipient.0.to_string()]["transaction_list"];
+        if let Some(transactions) = transactions.as_object_mut() {
+            transactions.insert(transaction_id.0.to_string(), serde_json::to_value(record)?);
+        }
+    }
+
+    let index: HashMap<TransactionId, ClientId> =
+        serde_json::from_value(state["transaction_index"].clone())?;
+    let mut transaction_ids = TransactionIds::default();
+    for transaction_id in index.keys() {
+        transaction_ids.insert(*transaction_id);
+    }
+    state["transaction_ids"] = serde_json::to_value(transaction_ids)?;
+    state["evicted"] = serde_json::to_value(TransactionIds::default())?;
+    state["history"] = Value::Array(vec![]);
+
+    let entries: Vec<JournalEntry> = serde_json::from_value(state["ledger"]["entries"].take())?;
+    state["ledger"] = serde_json::to_value(Ledger::from_entries(entries))?;
+    state["authorization_expiry"] = Value::Array(vec![]);
+    state["house"] = Value::Object(Default::default());
+    Ok(state)
+}
+
+impl PaymentsEngine {
+    pub fn save_snapshot<W: Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        if self.spill.is_some() {
+            return Err(SnapshotError::SpilledHistory);
+        }
+        writer.write_all(MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        serde_json::to_writer(
+            &mut writer,
+            &StateRef {
+                config: &self.config,
+                client_list: &self.client_list,
+                transaction_ids: &self.transaction_ids,
+                transaction_index: &self.transaction_index,
+                evicted: &self.evicted,
+                history: &self.history,
+                sequence: self.sequence,
+                ledger: &self.ledger,
+                authorization_expiry: &self.authorization_expiry,
+                house: &self.house,
+            },
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // the config has to be the one the snapshot was taken with
+    pub fn load_snapshot<R: Read>(
+        config: EngineConfig,
+        mut reader: R,
+    ) -> Result<PaymentsEngine, SnapshotError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+
+        let state = migrate(version, serde_json::from_reader(reader)?)?;
+        let state: State = serde_json::from_value(state)?;
+        if state.config.is_some_and(|taken| taken != config) {
+            return Err(SnapshotError::ConfigMismatch);
+        }
+
+        let mut engine = PaymentsEngine {
+            client_list: state.client_list,
+            transaction_ids: state.transaction_ids,
+            transaction_index: state.transaction_index,
+            evicted: state.evicted,
+            history: state.history,
+            sequence: state.sequence,
+            ledger: state.ledger,
+            authorization_expiry: state.authorization_expiry,
+            house: state.house,
+            ..PaymentsEngine::with_config(config)?
+        };
+        engine.track_history();
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Account, Amount, Client, Currency, Deposit, Dispute, Error, Fee, FeeLine, FeeSchedule,
+        Transaction, Transfer, Withdraw,
+    };
+    use rust_decimal::Decimal;
+
+    fn engine() -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        for transaction in [
+            Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::ONE_HUNDRED),
+                Currency::EUR,
+            )),
+            Transaction::Deposit(Deposit::new(
+                TransactionId(2),
+                ClientId(2),
+                Amount(Decimal::new(1005, 2)),
+                Currency::GBP,
+            )),
+            Transaction::Transfer(Transfer::new(
+                TransactionId(3),
+                ClientId(1),
+                ClientId(2),
+                Amount(Decimal::TEN),
+                Currency::EUR,
+            )),
+            Transaction::Dispute(Dispute {
+                client_id: ClientId(1),
+                target_transaction_id: TransactionId(1),
+                amount: Some(Amount(Decimal::ONE)),
+                currency: None,
+            }),
+        ] {
+            engine.recv_tx(transaction).expect("transaction error");
+        }
+        engine
+    }
+
+    fn save(engine: &PaymentsEngine) -> Vec<u8> {
+        let mut snapshot = vec![];
+        engine.save_snapshot(&mut snapshot).expect("snapshot error");
+        snapshot
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let engine = engine();
+
+        let loaded = PaymentsEngine::load_snapshot(EngineConfig::default(), &save(&engine)[..])
+            .expect("snapshot error");
+
+        assert_eq!(loaded.client_list, engine.client_list);
+        assert_eq!(loaded.transaction_index, engine.transaction_index);
+        assert_eq!(loaded.sequence, engine.sequence);
+        assert_eq!(loaded.trial_balance(), engine.trial_balance());
+        assert!(matches!(
+            loaded.client_list[&ClientId(1)]
+                .transaction_list
+                .get(&TransactionId(1)),
+            Some(TransactionRecord::Deposit(Disputable {
+                status: DisputeStatus::Disputed,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn fees_survive_a_snapshot() {
+        let config = EngineConfig {
+            fees: FeeSchedule {
+                withdrawal: Fee {
+                    flat: Amount(Decimal::ONE),
+                    ..Fee::default()
+                },
+                ..FeeSchedule::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = PaymentsEngine::with_config(config.clone()).expect("config error");
+        engine
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(1),
+                ClientId(1),
+                Amount(Decimal::TEN),
+                Currency::EUR,
+            )))
+            .expect("deposit amount error");
+        for transaction_id in [2, 3] {
+            engine
+                .recv_tx(Transaction::Withdraw(Withdraw::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::TWO),
+                    Currency::EUR,
+                )))
+                .expect("withdraw amount error");
+        }
+
+        let loaded =
+            PaymentsEngine::load_snapshot(config, &save(&engine)[..]).expect("snapshot error");
+
+        let fees = loaded.client(ClientId(1)).map(Client::fees);
+        assert_eq!(fees.map(<[FeeLine]>::len), Some(2));
+        assert_eq!(fees, engine.client(ClientId(1)).map(Client::fees));
+    }
+
+    #[test]
+    fn loaded_engine_keeps_processing() {
+        let mut loaded =
+            PaymentsEngine::load_snapshot(EngineConfig::default(), &save(&engine())[..])
+                .expect("snapshot error");
+
+        assert_eq!(
+            loaded.recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(3),
+                ClientId(1),
+                Amount(Decimal::ONE),
+                Currency::EUR,
+            ))),
+            Err(Error::DuplicateTransaction)
+        );
+        loaded
+            .recv_tx(Transaction::Deposit(Deposit::new(
+                TransactionId(4),
+                ClientId(1),
+                Amount(Decimal::ONE),
+                Currency::EUR,
+            )))
+            .expect("deposit amount error");
+        assert_eq!(loaded.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn migrate_v1_snapshot() {
+        let v1 = r#"{
+            "client_list": {"1": {
+                "client_id": 1,
+                "balances": {"EUR": {"available": "10", "held": "0"}},
+                "locked": false,
+                "transaction_list": {"1": {
+                    "type": "deposit",
+                    "transaction_id": 1,
+                    "client_id": 1,
+                    "amount": "10",
+                    "currency": "EUR",
+                    "dispute_status": "NotDisputed",
+                    "disputed": "0",
+                    "charged_back": "0"
+                }},
+                "dispute_history": {}
+            }},
+            "transaction_index": {"1": 1},
+            "sequence": 1,
+            "ledger": {"entries": [{
+                "sequence": 1,
+                "currency": "EUR",
+                "from": "Settlement",
+                "to": {"Available": 1},
+                "amount": "10"
+            }]}
+        }"#;
+        let mut snapshot = MAGIC.to_vec();
+        snapshot.extend_from_slice(&1u32.to_le_bytes());
+        snapshot.extend_from_slice(v1.as_bytes());
+
+        let engine = PaymentsEngine::load_snapshot(EngineConfig::default(), &snapshot[..])
+            .expect("snapshot error");
+
+        let balance = engine.client_list[&ClientId(1)].balance(Currency::EUR);
+        assert_eq!(balance.available, Amount(Decimal::TEN));
+        assert_eq!(balance.reserved, Amount::ZERO);
+        assert_eq!(
+            engine.client_list[&ClientId(1)]
+                .transaction_list
+                .get(&TransactionId(1)),
+            Some(&TransactionRecord::Deposit(Disputable::new(
+                Amount(Decimal::TEN),
+                Currency::EUR
+            )))
+        );
+        assert_eq!(
+            engine
+                .trial_balance()
+                .map(|trial_balance| trial_balance.balance(Account::Settlement, Currency::EUR)),
+            Ok(Amount(-Decimal::TEN))
+        );
+        assert_eq!(engine.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn reject_other_config() {
+        let config = EngineConfig {
+            dispute_locked_clients: false,
+            ..EngineConfig::default()
+        };
+
+        assert!(matches!(
+            PaymentsEngine::load_snapshot(config, &save(&engine())[..]),
+            Err(SnapshotError::ConfigMismatch)
+        ));
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let mut snapshot = save(&engine());
+        snapshot[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+
+        assert!(matches!(
+            PaymentsEngine::load_snapshot(EngineConfig::default(), &snapshot[..]),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == SNAPSHOT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(matches!(
+            PaymentsEngine::load_snapshot(EngineConfig::default(), &b"type,client,tx,amount"[..]),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+}
@@ -0,0 +1,803 @@
+use crate::store::{ClientStore, Commit, MemoryStore, StoreError, StoredTransaction};
+use crate::{
+    Account, Amount, Balance, Client, ClientId, FeeKind, FeeLine, JournalEntry, TransactionId,
+    TransactionIds,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+// Every client is a row of `clients`, with a row of `balances` per currency
+// and a row of `transactions` per record in its history, holding the record
+// and its dispute history as JSON. Only the rows a transaction changed are
+// written. The journal and fees are only appended to, the journal is read
+// back whole when the store is opened and the fees of a client along with
+// it. `accounts` keeps the balance of every ledger account for ad hoc
+// queries. Amounts are exact decimal strings.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS engine (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        sequence INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO engine (id, sequence) VALUES (0, 0);
+    CREATE TABLE IF NOT EXISTS clients (
+        client_id INTEGER PRIMARY KEY,
+        locked INTEGER NOT NULL,
+        credit_limits TEXT NOT NULL,
+        rounding_residue TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        client_id INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        reserved TEXT NOT NULL,
+        receivable TEXT NOT NULL,
+        PRIMARY KEY (client_id, currency)
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        client_id INTEGER NOT NULL,
+        transaction_id INTEGER NOT NULL,
+        owned INTEGER NOT NULL,
+        leaves_memory INTEGER NOT NULL,
+        expires_at INTEGER,
+        record TEXT NOT NULL,
+        dispute_history TEXT,
+        PRIMARY KEY (client_id, transaction_id)
+    );
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY,
+        sequence INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        from_account TEXT NOT NULL,
+        from_client INTEGER,
+        to_account TEXT NOT NULL,
+        to_client INTEGER,
+        amount TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS accounts (
+        account TEXT NOT NULL,
+        client_id INTEGER,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS accounts_by_name ON accounts (account, client_id, currency);
+    CREATE TABLE IF NOT EXISTS fees (
+        id INTEGER PRIMARY KEY,
+        sequence INTEGER NOT NULL,
+        client_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        transaction_id INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS fees_by_client ON fees (client_id);
+    CREATE TABLE IF NOT EXISTS transaction_ids (
+        transaction_id INTEGER PRIMARY KEY
+    );
+";
+
+// Clients live in an embedded SQLite database and are read into memory the
+// first time the engine needs them. Clients handed out by get_mut or insert
+// are written back on commit, together with the records the transaction
+// changed, the new journal entries, the accounts they moved and the fees
+// charged, in a single database transaction.
+pub struct SqliteStore {
+    connection: Connection,
+    // the clients loaded so far
+    clients: MemoryStore,
+    dirty: BTreeSet<ClientId>,
+    sequence: u64,
+    journal: Vec<JournalEntry>,
+    transaction_ids: TransactionIds,
+    transactions: Vec<StoredTransaction>,
+}
+
+impl SqliteStore {
+    // creates the database if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<SqliteStore, StoreError> {
+        SqliteStore::load(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore, StoreError> {
+        SqliteStore::load(Connection::open_in_memory()?)
+    }
+
+    fn load(connection: Connection) -> Result<SqliteStore, StoreError> {
+        // in-memory databases stay in their own journal mode
+        connection
+            .query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .optional()?;
+        connection.execute_batch(SCHEMA)?;
+
+        let sequence = connection.query_row("SELECT sequence FROM engine", [], |row| {
+            row.get::<_, i64>(0)
+        })?;
+
+        let mut journal = vec![];
+        let mut statement = connection.prepare(
+            "SELECT sequence, currency, from_account, from_client, to_account, to_client, amount
+             FROM journal ORDER BY id",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            journal.push(JournalEntry {
+                sequence: row.get::<_, i64>(0)? as u64,
+                currency: parse(&row.get::<_, String>(1)?)?,
+                from: account(&row.get::<_, String>(2)?, row.get(3)?)?,
+                to: account(&row.get::<_, String>(4)?, row.get(5)?)?,
+                amount: Amount(parse(&row.get::<_, String>(6)?)?),
+            });
+        }
+        drop(rows);
+        drop(statement);
+
+        let mut transaction_ids = TransactionIds::default();
+        let mut statement = connection.prepare("SELECT transaction_id FROM transaction_ids")?;
+        for transaction_id in statement.query_map([], |row| row.get::<_, u32>(0))? {
+            transaction_ids.insert(TransactionId(transaction_id?));
+        }
+        drop(statement);
+
+        let mut transactions = vec![];
+        let mut statement = connection.prepare(
+            "SELECT client_id, transaction_id, owned, leaves_memory, expires_at
+             FROM transactions",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            transactions.push(StoredTransaction {
+                client_id: ClientId(row.get(0)?),
+                transaction_id: TransactionId(row.get(1)?),
+                owned: row.get(2)?,
+                leaves_memory: row.get(3)?,
+                expires_at: row
+                    .get::<_, Option<i64>>(4)?
+                    .map(|sequence| sequence as u64),
+            });
+        }
+        drop(rows);
+        drop(statement);
+
+        Ok(SqliteStore {
+            connection,
+            clients: MemoryStore::default(),
+            dirty: BTreeSet::new(),
+            sequence: sequence as u64,
+            journal,
+            transaction_ids,
+            transactions,
+        })
+    }
+
+    // for ad hoc queries on the stored balances, journal and fees
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl ClientStore for SqliteStore {
+    fn load(&mut self, client_id: &ClientId) -> Result<(), StoreError> {
+        if self.clients.contains_key(client_id) {
+            return Ok(());
+        }
+        if let Some(client) = read_client(&self.connection, *client_id)? {
+            self.clients.insert(*client_id, client);
+        }
+        Ok(())
+    }
+
+    fn load_all(&mut self) -> Result<(), StoreError> {
+        let mut statement = self.connection.prepare("SELECT client_id FROM clients")?;
+        let client_ids = statement
+            .query_map([], |row| row.get::<_, u16>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(statement);
+        for client_id in client_ids {
+            self.load(&ClientId(client_id))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, client_id: &ClientId) -> Option<&Client> {
+        self.clients.get(client_id)
+    }
+
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Client> {
+        let client = self.clients.get_mut(client_id)?;
+        self.dirty.insert(*client_id);
+        Some(client)
+    }
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Option<Client> {
+        self.dirty.insert(client_id);
+        self.clients.insert(client_id, client)
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+        Box::new(self.clients.values())
+    }
+
+    fn commit(&mut self, commit: Commit) -> Result<(), StoreError> {
+        let entries = commit.ledger.latest_entries();
+        // a rejected transaction usually changes nothing
+        if self.dirty.is_empty()
+            && commit.transactions.is_empty()
+            && entries.is_empty()
+            && commit.fee_lines.is_empty()
+            && commit.sequence == self.sequence
+        {
+            return Ok(());
+        }
+
+        let transaction = self.connection.transaction()?;
+        // kept apart from the clients, whose history may drop transactions
+        if let Some(transaction_id) = commit.transaction_id {
+            transaction.execute(
+                "INSERT OR IGNORE INTO transaction_ids (transaction_id) VALUES (?1)",
+                [transaction_id.0],
+            )?;
+        }
+        for client in self.dirty.iter().filter_map(|id| self.clients.get(id)) {
+            write_client(&transaction, client)?;
+        }
+        for (client_id, transaction_id) in commit.transactions {
+            if let Some(client) = self.clients.get(client_id) {
+                write_record(&transaction, client, *transaction_id)?;
+            }
+        }
+        for entry in entries {
+            let (from_account, from_client) = columns(entry.from);
+            let (to_account, to_client) = columns(entry.to);
+            transaction.execute(
+                "INSERT INTO journal
+                 (sequence, currency, from_account, from_client, to_account, to_client, amount)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    entry.sequence as i64,
+                    entry.currency.code(),
+                    from_account,
+                    from_client,
+                    to_account,
+                    to_client,
+                    entry.amount.0.to_string(),
+                ],
+            )?;
+        }
+        let touched: BTreeSet<_> = entries
+            .iter()
+            .flat_map(|entry| [(entry.from, entry.currency), (entry.to, entry.currency)])
+            .collect();
+        for (account, currency) in touched {
+            let (name, client_id) = columns(account);
+            let amount = commit.ledger.balance(account, currency).0.to_string();
+            let updated = transaction.execute(
+                "UPDATE accounts SET amount = ?4
+                 WHERE account = ?1 AND client_id IS ?2 AND currency = ?3",
+                params![name, client_id, currency.code(), amount],
+            )?;
+            if updated == 0 {
+                transaction.execute(
+                    "INSERT INTO accounts (account, client_id, currency, amount)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![name, client_id, currency.code(), amount],
+                )?;
+            }
+        }
+        for line in commit.fee_lines {
+            transaction.execute(
+                "INSERT INTO fees (sequence, client_id, kind, transaction_id, currency, amount)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    line.sequence as i64,
+                    line.client_id.0,
+                    kind(line.kind),
+                    line.transaction_id.0,
+                    line.currency.code(),
+                    line.amount.0.to_string(),
+                ],
+            )?;
+        }
+        transaction.execute("UPDATE engine SET sequence = ?1", [commit.sequence as i64])?;
+        transaction.commit()?;
+
+        self.dirty.clear();
+        self.sequence = commit.sequence;
+        Ok(())
+    }
+
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn take_journal(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.journal)
+    }
+
+    fn take_transaction_ids(&mut self) -> TransactionIds {
+        std::mem::take(&mut self.transaction_ids)
+    }
+
+    fn take_transactions(&mut self) -> Vec<StoredTransaction> {
+        std::mem::take(&mut self.transactions)
+    }
+}
+
+// everything of a client but its history
+fn write_client(connection: &Connection, client: &Client) -> Result<(), StoreError> {
+    let client_id = client.client_id.0;
+    connection.execute(
+        "INSERT INTO clients (client_id, locked, credit_limits, rounding_residue)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (client_id) DO UPDATE SET
+         locked = ?2, credit_limits = ?3, rounding_residue = ?4",
+        params![
+            client_id,
+            client.locked,
+            to_json(&client.credit_limits)?,
+            to_json(&client.rounding_residue)?,
+        ],
+    )?;
+    for (currency, balance) in &client.balances {
+        connection.execute(
+            "INSERT INTO balances
+             (client_id, currency, available, held, reserved, receivable)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (client_id, currency) DO UPDATE SET
+             available = ?3, held = ?4, reserved = ?5, receivable = ?6",
+            params![
+                client_id,
+                currency.code(),
+                balance.available.0.to_string(),
+                balance.held.0.to_string(),
+                balance.reserved.0.to_string(),
+                balance.receivable.0.to_string(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+// a record of the client's history as it is now, gone if the history dropped
+// it
+fn write_record(
+    connection: &Connection,
+    client: &Client,
+    transaction_id: TransactionId,
+) -> Result<(), StoreError> {
+    let record = match client.transaction_list.get(&transaction_id) {
+        Some(record) => record,
+        None => {
+            connection.execute(
+                "DELETE FROM transactions WHERE client_id = ?1 AND transaction_id = ?2",
+                params![client.client_id.0, transaction_id.0],
+            )?;
+            return Ok(());
+        }
+    };
+    let stored = StoredTransaction::new(client.client_id, transaction_id, record);
+    let dispute_history = client
+        .dispute_history
+        .get(&transaction_id)
+        .map(to_json)
+        .transpose()?;
+    connection.execute(
+        "INSERT INTO transactions
+         (client_id, transaction_id, owned, leaves_memory, expires_at, record, dispute_history)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (client_id, transaction_id) DO UPDATE SET
+         owned = ?3, leaves_memory = ?4, expires_at = ?5, record = ?6, dispute_history = ?7",
+        params![
+            client.client_id.0,
+            transaction_id.0,
+            stored.owned,
+            stored.leaves_memory,
+            stored.expires_at.map(|sequence| sequence as i64),
+            to_json(record)?,
+            dispute_history,
+        ],
+    )?;
+    Ok(())
+}
+
+fn read_client(connection: &Connection, client_id: ClientId) -> Result<Option<Client>, StoreError> {
+    let row = connection
+        .query_row(
+            "SELECT locked, credit_limits, rounding_residue FROM clients WHERE client_id = ?1",
+            [client_id.0],
+            |row| {
+                Ok((
+                    row.get::<_, bool>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+    let (locked, credit_limits, rounding_residue) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let mut balances = BTreeMap::new();
+    let mut statement = connection.prepare(
+        "SELECT currency, available, held, reserved, receivable
+         FROM balances WHERE client_id = ?1",
+    )?;
+    let mut rows = statement.query([client_id.0])?;
+    while let Some(row) = rows.next()? {
+        balances.insert(
+            parse(&row.get::<_, String>(0)?)?,
+            Balance {
+                available: Amount(parse(&row.get::<_, String>(1)?)?),
+                held: Amount(parse(&row.get::<_, String>(2)?)?),
+                reserved: Amount(parse(&row.get::<_, String>(3)?)?),
+                receivable: Amount(parse(&row.get::<_, String>(4)?)?),
+            },
+        );
+    }
+    drop(rows);
+    drop(statement);
+
+    let mut transaction_list = HashMap::new();
+    let mut dispute_history = HashMap::new();
+    let mut statement = connection.prepare(
+        "SELECT transaction_id, record, dispute_history
+         FROM transactions WHERE client_id = ?1",
+    )?;
+    let mut rows = statement.query([client_id.0])?;
+    while let Some(row) = rows.next()? {
+        let transaction_id = TransactionId(row.get(0)?);
+        transaction_list.insert(transaction_id, from_json(&row.get::<_, String>(1)?)?);
+        if let Some(events) = row.get::<_, Option<String>>(2)? {
+            dispute_history.insert(transaction_id, from_json(&events)?);
+        }
+    }
+    drop(rows);
+    drop(statement);
+
+    let mut fees = vec![];
+    let mut statement = connection.prepare(
+        "SELECT sequence, kind, transaction_id, currency, amount
+         FROM fees WHERE client_id = ?1 ORDER BY id",
+    )?;
+    let mut rows = statement.query([client_id.0])?;
+    while let Some(row) = rows.next()? {
+        fees.push(FeeLine {
+            sequence: row.get::<_, i64>(0)? as u64,
+            client_id,
+            kind: fee_kind(&row.get::<_, String>(1)?)?,
+            transaction_id: TransactionId(row.get(2)?),
+            currency: parse(&row.get::<_, String>(3)?)?,
+            amount: Amount(parse(&row.get::<_, String>(4)?)?),
+        });
+    }
+
+    Ok(Some(Client {
+        client_id,
+        balances,
+        locked,
+        credit_limits: from_json(&credit_limits)?,
+        fees,
+        rounding_residue: from_json(&rounding_residue)?,
+        transaction_list,
+        dispute_history,
+    }))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, StoreError> {
+    serde_json::to_string(value).map_err(|err| StoreError::Malformed(err.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(value: &str) -> Result<T, StoreError> {
+    serde_json::from_str(value).map_err(|err| StoreError::Malformed(err.to_string()))
+}
+
+fn columns(account: Account) -> (&'static str, Option<u16>) {
+    match account {
+        Account::Available(client_id) => ("available", Some(client_id.0)),
+        Account::Held(client_id) => ("held", Some(client_id.0)),
+        Account::Reserved(client_id) => ("reserved", Some(client_id.0)),
+        Account::Receivable(client_id) => ("receivable", Some(client_id.0)),
+        Account::Rounding(client_id) => ("rounding", Some(client_id.0)),
+        Account::Settlement => ("settlement", None),
+        Account::ChargebackLoss => ("chargeback_loss", None),
+        Account::House => ("house", None),
+    }
+}
+
+fn account(name: &str, client_id: Option<u16>) -> Result<Account, StoreError> {
+    match (name, client_id.map(ClientId)) {
+        ("available", Some(client_id)) => Ok(Account::Available(client_id)),
+        ("held", Some(client_id)) => Ok(Account::Held(client_id)),
+        ("reserved", Some(client_id)) => Ok(Account::Reserved(client_id)),
+        ("receivable", Some(client_id)) => Ok(Account::Receivable(client_id)),
+        ("rounding", Some(client_id)) => Ok(Account::Rounding(client_id)),
+        ("settlement", None) => Ok(Account::Settlement),
+        ("chargeback_loss", None) => Ok(Account::ChargebackLoss),
+        ("house", None) => Ok(Account::House),
+        _ => Err(StoreError::Malformed(format!("unknown account {}", name))),
+    }
+}
+
+fn kind(kind: FeeKind) -> &'static str {
+    match kind {
+        FeeKind::Withdrawal => "withdrawal",
+        FeeKind::Dispute => "dispute",
+        FeeKind::Chargeback => "chargeback",
+    }
+}
+
+fn fee_kind(name: &str) -> Result<FeeKind, StoreError> {
+    match name {
+        "withdrawal" => Ok(FeeKind::Withdrawal),
+        "dispute" => Ok(FeeKind::Dispute),
+        "chargeback" => Ok(FeeKind::Chargeback),
+        _ => Err(StoreError::Malformed(format!("unknown fee kind {}", name))),
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, StoreError> {
+    value
+        .parse()
+        .map_err(|_| StoreError::Malformed(format!("can't parse {:?}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Currency, Deposit, Dispute, DisputeStatus, EngineConfig, Fee, FeeSchedule, PaymentsEngine,
+        Transaction, TransactionId, TransactionRecord, Withdraw,
+    };
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "payments_engine_{}_{}.db",
+            std::process::id(),
+            name
+        ));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = fs::remove_file(file);
+        }
+        path
+    }
+
+    fn deposit(transaction_id: u32, client_id: u16, amount: Decimal) -> Transaction {
+        Transaction::Deposit(Deposit::new(
+            TransactionId(transaction_id),
+            ClientId(client_id),
+            Amount(amount),
+            Currency::EUR,
+        ))
+    }
+
+    fn engine(store: SqliteStore) -> PaymentsEngine<SqliteStore> {
+        PaymentsEngine::with_store(Default::default(), store).expect("config error")
+    }
+
+    #[test]
+    fn balances_survive_reopening() {
+        let path = temp_path("reopen");
+        let mut first = engine(SqliteStore::open(&path).expect("store error"));
+        first
+            .recv_tx(deposit(1, 1, Decimal::TEN))
+            .expect("deposit amount error");
+        first
+            .recv_tx(deposit(2, 2, Decimal::ONE))
+            .expect("deposit amount error");
+        let journal = first.ledger().entries().to_vec();
+        drop(first);
+
+        let mut reopened = engine(SqliteStore::open(&path).expect("store error"));
+        assert_eq!(reopened.ledger().entries(), &journal[..]);
+        assert_eq!(reopened.reconcile(), Ok(()));
+        assert_eq!(
+            reopened.recv_tx(deposit(1, 1, Decimal::ONE)),
+            Err(crate::Error::DuplicateTransaction)
+        );
+        reopened
+            .recv_tx(Transaction::Withdraw(Withdraw::new(
+                TransactionId(3),
+                ClientId(1),
+                Amount(Decimal::TWO),
+                Currency::EUR,
+            )))
+            .expect("withdraw amount error");
+        assert_eq!(
+            reopened
+                .client(ClientId(1))
+                .map(|client| client.balance(Currency::EUR).available),
+            Some(Amount(Decimal::from(8)))
+        );
+        assert_eq!(reopened.reconcile(), Ok(()));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn fees_survive_reopening() {
+        let path = temp_path("fees");
+        let config = EngineConfig {
+            fees: FeeSchedule {
+                withdrawal: Fee {
+                    flat: Amount(Decimal::ONE),
+                    ..Fee::default()
+                },
+                ..FeeSchedule::default()
+            },
+            ..EngineConfig::default()
+        };
+        let open = || {
+            PaymentsEngine::with_store(
+                config.clone(),
+                SqliteStore::open(&path).expect("store error"),
+            )
+            .expect("config error")
+        };
+        let mut first = open();
+        first
+            .recv_tx(deposit(1, 1, Decimal::TEN))
+            .expect("deposit amount error");
+        for transaction_id in [2, 3] {
+            first
+                .recv_tx(Transaction::Withdraw(Withdraw::new(
+                    TransactionId(transaction_id),
+                    ClientId(1),
+                    Amount(Decimal::TWO),
+                    Currency::EUR,
+                )))
+                .expect("withdraw amount error");
+        }
+        let fees = first
+            .client(ClientId(1))
+            .map(|client| client.fees().to_vec());
+        drop(first);
+
+        let mut reopened = open();
+        reopened.load_clients().expect("store error");
+        let reloaded = reopened
+            .client(ClientId(1))
+            .map(|client| client.fees().to_vec());
+        assert_eq!(reloaded.as_ref().map(Vec::len), Some(2));
+        assert_eq!(reloaded, fees);
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn balances_can_be_queried() {
+        let mut engine = engine(SqliteStore::open_in_memory().expect("store error"));
+        engine
+            .recv_tx(deposit(1, 1, Decimal::new(1050, 2)))
+            .expect("deposit amount error");
+        engine
+            .recv_tx(deposit(2, 2, Decimal::ONE))
+            .expect("deposit amount error");
+
+        let available: String = engine
+            .store()
+            .connection()
+            .query_row(
+                "SELECT available FROM balances WHERE client_id = 1 AND currency = 'EUR'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("query error");
+        assert_eq!(available, "10.50");
+        let entries: i64 = engine
+            .store()
+            .connection()
+            .query_row("SELECT COUNT(*) FROM journal", [], |row| row.get(0))
+            .expect("query error");
+        assert_eq!(entries, 2);
+        let settlement: String = engine
+            .store()
+            .connection()
+            .query_row(
+                "SELECT amount FROM accounts WHERE account = 'settlement' AND currency = 'EUR'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("query error");
+        assert_eq!(settlement, "-11.50");
+    }
+
+    #[test]
+    fn rejected_transaction_writes_nothing() {
+        let mut engine = engine(SqliteStore::open_in_memory().expect("store error"));
+        engine
+            .recv_tx(deposit(1, 1, Decimal::ONE))
+            .expect("deposit amount error");
+        assert!(engine.recv_tx(deposit(1, 1, Decimal::ONE)).is_err());
+
+        let sequence: i64 = engine
+            .store()
+            .connection()
+            .query_row("SELECT sequence FROM engine", [], |row| row.get(0))
+            .expect("query error");
+        assert_eq!(sequence, 1);
+    }
+
+    fn dispute(transaction_id: u32, client_id: u16) -> Transaction {
+        Transaction::Dispute(Dispute {
+            client_id: ClientId(client_id),
+            target_transaction_id: TransactionId(transaction_id),
+            amount: None,
+            currency: None,
+        })
+    }
+
+    #[test]
+    fn clients_load_on_demand() {
+        let path = temp_path("on_demand");
+        let mut first = engine(SqliteStore::open(&path).expect("store error"));
+        for (transaction_id, client_id) in [(1, 1), (2, 2)] {
+            first
+                .recv_tx(deposit(transaction_id, client_id, Decimal::TEN))
+                .expect("deposit amount error");
+        }
+        first.recv_tx(dispute(1, 1)).expect("dispute error");
+        drop(first);
+
+        let mut reopened = engine(SqliteStore::open(&path).expect("store error"));
+        assert_eq!(reopened.clients().count(), 0);
+        // the transaction index doesn't need the owner in memory
+        assert_eq!(
+            reopened.recv_tx(dispute(1, 2)),
+            Err(crate::Error::TransactionClientMismatch)
+        );
+        assert!(reopened.client(ClientId(1)).is_none());
+        reopened
+            .recv_tx(deposit(3, 1, Decimal::ONE))
+            .expect("deposit amount error");
+        let client = reopened.client(ClientId(1)).expect("client not loaded");
+        assert_eq!(client.balance(Currency::EUR).held, Amount(Decimal::TEN));
+        assert!(matches!(
+            client.transaction_list.get(&TransactionId(1)),
+            Some(TransactionRecord::Deposit(funds)) if funds.status == DisputeStatus::Disputed
+        ));
+        assert_eq!(client.dispute_history[&TransactionId(1)].len(), 1);
+
+        reopened.load_clients().expect("load error");
+        assert_eq!(reopened.clients().count(), 2);
+        assert_eq!(reopened.reconcile(), Ok(()));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn only_changed_records_are_written() {
+        let mut engine = engine(SqliteStore::open_in_memory().expect("store error"));
+        for transaction_id in 1..=2 {
+            engine
+                .recv_tx(deposit(transaction_id, 1, Decimal::ONE))
+                .expect("deposit amount error");
+        }
+        // marks the rows so a rewrite would show
+        engine
+            .store()
+            .connection()
+            .execute("UPDATE transactions SET dispute_history = 'untouched'", [])
+            .expect("update error");
+        engine.recv_tx(dispute(2, 1)).expect("dispute error");
+
+        let histories: Vec<(u32, Option<String>)> = engine
+            .store()
+            .connection()
+            .prepare("SELECT transaction_id, dispute_history FROM transactions ORDER BY 1")
+            .expect("query error")
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("query error")
+            .collect::<Result<_, _>>()
+            .expect("query error");
+        assert_eq!(histories[0], (1, Some(String::from("untouched"))));
+        assert_eq!(histories[1].0, 2);
+        assert_ne!(histories[1].1, Some(String::from("untouched")));
+    }
+}
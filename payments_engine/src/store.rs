@@ -0,0 +1,263 @@
+use crate::{
+    Client, ClientId, FeeLine, JournalEntry, Ledger, TransactionId, TransactionIds,
+    TransactionRecord,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeSet, HashMap};
+use std::iter;
+use std::ops::Index;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[cfg(feature = "sqlite")]
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("malformed stored state: {0}")]
+    Malformed(String),
+}
+
+// what a call to recv_tx changed, accepted or not
+pub struct Commit<'a> {
+    pub sequence: u64,
+    // holds the journal entries posted
+    pub ledger: &'a Ledger,
+    pub fee_lines: &'a [FeeLine],
+    // records added to, changed in or dropped from client histories
+    pub transactions: &'a BTreeSet<(ClientId, TransactionId)>,
+    // the transaction, if it was accepted
+    pub transaction_id: Option<TransactionId>,
+}
+
+// what the engine indexes of a transaction in a client's history, so a store
+// doesn't need to load its client up front
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StoredTransaction {
+    pub client_id: ClientId,
+    pub transaction_id: TransactionId,
+    // false for a transfer in the recipient's history
+    pub owned: bool,
+    pub leaves_memory: bool,
+    // for an open authorization
+    pub expires_at: Option<u64>,
+}
+
+impl StoredTransaction {
+    pub(crate) fn new(
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        record: &TransactionRecord,
+    ) -> StoredTransaction {
+        StoredTransaction {
+            client_id,
+            transaction_id,
+            owned: !matches!(record, TransactionRecord::ReceivedTransfer { .. }),
+            leaves_memory: record.leaves_memory(),
+            expires_at: record.expires_at(),
+        }
+    }
+}
+
+// Where the engine keeps its clients. A store may keep some of them out of
+// memory, the engine loads the clients a transaction needs before looking
+// them up. Changes made through get_mut and insert are only guaranteed to
+// last once commit returns, which the engine calls at the end of every
+// recv_tx.
+pub trait ClientStore {
+    // brings the client into memory if the store has it
+    fn load(&mut self, _client_id: &ClientId) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    // brings every client into memory, for reports over all of them
+    fn load_all(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn get(&self, client_id: &ClientId) -> Option<&Client>;
+
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Client>;
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Option<Client>;
+
+    // the clients in memory, by client id
+    fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_>;
+
+    fn commit(&mut self, _commit: Commit) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    // engine sequence of the last commit, for an engine opened on a store
+    // that outlived the previous one
+    fn sequence(&self) -> u64 {
+        0
+    }
+
+    // journal entries committed before the store was opened, handed over once
+    fn take_journal(&mut self) -> Vec<JournalEntry> {
+        vec![]
+    }
+
+    // ids of the transactions committed before the store was opened, the ones
+    // still in the clients' histories may be left out
+    fn take_transaction_ids(&mut self) -> TransactionIds {
+        TransactionIds::default()
+    }
+
+    // the transactions in the clients' histories when the store was opened,
+    // handed over once
+    fn take_transactions(&mut self) -> Vec<StoredTransaction> {
+        self.values()
+            .flat_map(|client| {
+                client
+                    .transaction_list
+                    .iter()
+                    .map(|(transaction_id, record)| {
+                        StoredTransaction::new(client.client_id, *transaction_id, record)
+                    })
+            })
+            .collect()
+    }
+}
+
+// one slot for every possible client id
+const CLIENT_SLOTS: usize = u16::MAX as usize + 1;
+
+// The default store, nothing survives the process. Clients sit in a table
+// with a slot for every possible id, allocated up front, so finding one is an
+// index and iterating goes by id.
+#[derive(Debug)]
+pub struct MemoryStore {
+    slots: Vec<Option<Box<Client>>>,
+    len: usize,
+}
+
+impl MemoryStore {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_key(&self, client_id: &ClientId) -> bool {
+        self.slots[usize::from(client_id.0)].is_some()
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            slots: iter::repeat_with(|| None).take(CLIENT_SLOTS).collect(),
+            len: 0,
+        }
+    }
+}
+
+impl PartialEq for MemoryStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.values().eq(other.values())
+    }
+}
+
+impl Index<&ClientId> for MemoryStore {
+    type Output = Client;
+
+    fn index(&self, client_id: &ClientId) -> &Client {
+        self.get(client_id).expect("no client with that id")
+    }
+}
+
+// same shape as a map from id to client
+impl Serialize for MemoryStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.values().map(|client| (client.client_id, client)))
+    }
+}
+
+impl<'de> Deserialize<'de> for MemoryStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut store = MemoryStore::default();
+        for (client_id, client) in HashMap::<ClientId, Client>::deserialize(deserializer)? {
+            store.insert(client_id, client);
+        }
+        Ok(store)
+    }
+}
+
+impl ClientStore for MemoryStore {
+    fn get(&self, client_id: &ClientId) -> Option<&Client> {
+        self.slots[usize::from(client_id.0)].as_deref()
+    }
+
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Client> {
+        self.slots[usize::from(client_id.0)].as_deref_mut()
+    }
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Option<Client> {
+        let previous = self.slots[usize::from(client_id.0)].replace(Box::new(client));
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous.map(|client| *client)
+    }
+
+    // by client id
+    fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+        Box::new(self.slots.iter().filter_map(|slot| slot.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, Currency, Deposit, TransactionId};
+    use rust_decimal::Decimal;
+
+    fn client(client_id: u16) -> Client {
+        Client::new_with_deposit(Deposit::new(
+            TransactionId(u32::from(client_id)),
+            ClientId(client_id),
+            Amount(Decimal::ONE),
+            Currency::EUR,
+        ))
+    }
+
+    #[test]
+    fn iterate_by_client_id() {
+        let mut store = MemoryStore::default();
+        for client_id in [u16::MAX, 7, 0, 300] {
+            assert!(store
+                .insert(ClientId(client_id), client(client_id))
+                .is_none());
+        }
+        assert!(store.insert(ClientId(7), client(7)).is_some());
+
+        assert_eq!(store.len(), 4);
+        assert_eq!(
+            store
+                .values()
+                .map(|client| client.client_id.0)
+                .collect::<Vec<_>>(),
+            vec![0, 7, 300, u16::MAX]
+        );
+    }
+
+    #[test]
+    fn serialize_as_map() {
+        let mut store = MemoryStore::default();
+        store.insert(ClientId(2), client(2));
+        store.insert(ClientId(1), client(1));
+
+        let json = serde_json::to_value(&store).expect("serialize error");
+        assert_eq!(
+            json.as_object()
+                .map(|clients| clients.keys().cloned().collect::<Vec<_>>()),
+            Some(vec![String::from("1"), String::from("2")])
+        );
+        let loaded: MemoryStore = serde_json::from_value(json).expect("deserialize error");
+        assert_eq!(loaded, store);
+    }
+}
@@ -0,0 +1,473 @@
+use crate::{PaymentsEngine, Transaction};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+// Newline delimited JSON like the event log: a header, then every parsed input
+// row, written before the engine sees it. Rows the engine rejected are logged
+// too, replaying them rejects them again.
+pub const WAL_VERSION: u32 = 2;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    // sequence of the engine when the log was started, i.e. of the snapshot
+    // the logged transactions apply on top of
+    base_sequence: u64,
+    input: InputFile,
+}
+
+// The input a log was written for. Logged line numbers only mean something
+// for the very same contents, so the size and a hash are kept with the path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFile {
+    pub path: String,
+    pub len: u64,
+    // FNV-1a of the contents
+    pub hash: u64,
+}
+
+impl InputFile {
+    pub fn read(path: &Path) -> io::Result<InputFile> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut len = 0;
+        let mut hash = FNV_OFFSET;
+        loop {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            for byte in buffer {
+                hash = (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+            }
+            let read = buffer.len();
+            len += read as u64;
+            reader.consume(read);
+        }
+        Ok(InputFile {
+            path: path.to_string_lossy().into_owned(),
+            len,
+            hash,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Record<T> {
+    line: u64,
+    transaction: T,
+}
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("write-ahead log i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("malformed write-ahead log record on line {line}: {source}")]
+    Malformed {
+        line: u64,
+        source: serde_json::Error,
+    },
+
+    #[error("write-ahead log has no header")]
+    MissingHeader,
+
+    #[error("write-ahead log version {0} isn't supported")]
+    UnsupportedVersion(u32),
+
+    #[error("write-ahead log starts at sequence {log} but the engine is at {engine}, the snapshot it applies to is missing")]
+    MissingSnapshot { log: u64, engine: u64 },
+
+    #[error("invalid fsync policy {0:?}, expected always, never or a number of records")]
+    InvalidSyncPolicy(String),
+}
+
+// how often appended records are forced to disk
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // every record, nothing acknowledged is ever lost
+    #[default]
+    Always,
+    // every n records, a crash loses at most the last n - 1
+    Every(u32),
+    // left to the operating system
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = WalError;
+
+    fn from_str(policy: &str) -> Result<SyncPolicy, WalError> {
+        match policy {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            records => match records.parse() {
+                Ok(records) if records > 0 => Ok(SyncPolicy::Every(records)),
+                _ => Err(WalError::InvalidSyncPolicy(String::from(policy))),
+            },
+        }
+    }
+}
+
+// what a recovery found in the log
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recovery {
+    // input file the log was written for
+    pub input: InputFile,
+    // last input line handed to the engine, rows up to it must be skipped
+    pub last_line: Option<u64>,
+    // records applied on top of the engine, zero if the snapshot already
+    // contained them
+    pub replayed: u64,
+}
+
+pub struct WriteAheadLog {
+    file: File,
+    sync: SyncPolicy,
+    unsynced: u32,
+    // where the last appended record starts, until it's retracted
+    last_record: Option<u64>,
+}
+
+impl WriteAheadLog {
+    // starts a log for the given input on top of the engine's current state
+    pub fn create(
+        path: &Path,
+        engine: &PaymentsEngine,
+        input: &InputFile,
+        sync: SyncPolicy,
+    ) -> Result<WriteAheadLog, WalError> {
+        let mut wal = WriteAheadLog {
+            file: File::create(path)?,
+            sync,
+            unsynced: 0,
+            last_record: None,
+        };
+        let header = Header {
+            version: WAL_VERSION,
+            base_sequence: engine.sequence,
+            input: input.clone(),
+        };
+        wal.write_line(&serde_json::to_vec(&header).map_err(io::Error::from)?)?;
+        wal.file.sync_all()?;
+        Ok(wal)
+    }
+
+    // Replays an existing log onto the engine, which has to be in the state
+    // the log was started from, and reopens it for appending. A last record
+    // without its newline was torn by a crash while being written, so it
+    // never reached the engine and is dropped.
+    pub fn recover(
+        path: &Path,
+        engine: &mut PaymentsEngine,
+        sync: SyncPolicy,
+    ) -> Result<(WriteAheadLog, Recovery), WalError> {
+        let contents = fs::read_to_string(path)?;
+        let complete = contents.rfind('\n').map_or(0, |end| end + 1);
+        let mut lines = contents[..complete].lines().zip(1..);
+
+        let header: Header = match lines.next() {
+            Some((line, number)) => {
+                serde_json::from_str(line).map_err(|source| WalError::Malformed {
+                    line: number,
+                    source,
+                })?
+            }
+            None => return Err(WalError::MissingHeader),
+        };
+        if header.version != WAL_VERSION {
+            return Err(WalError::UnsupportedVersion(header.version));
+        }
+        if header.base_sequence > engine.sequence {
+            return Err(WalError::MissingSnapshot {
+                log: header.base_sequence,
+                engine: engine.sequence,
+            });
+        }
+
+        let records = lines
+            .map(|(line, number)| {
+                serde_json::from_str::<Record<Transaction>>(line).map_err(|source| {
+                    WalError::Malformed {
+                        line: number,
+                        source,
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut recovery = Recovery {
+            input: header.input,
+            last_line: records.last().map(|record| record.line),
+            replayed: 0,
+        };
+        // a snapshot taken after the log was started already has its records
+        if header.base_sequence == engine.sequence {
+            for record in records {
+                // rows rejected now were rejected the first time as well
+                let _ = engine.recv_tx(record.transaction);
+                recovery.replayed += 1;
+            }
+        }
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(complete as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok((
+            WriteAheadLog {
+                file,
+                sync,
+                unsynced: 0,
+                last_record: None,
+            },
+            recovery,
+        ))
+    }
+
+    // Must be called before the transaction is handed to the engine. The
+    // record isn't synced yet, the caller syncs once it has handled the row
+    // and sync_due says so, so whatever it wrote about the row can be made
+    // durable along with it.
+    pub fn append(&mut self, line: u64, transaction: &Transaction) -> Result<(), WalError> {
+        let record = Record { line, transaction };
+        self.last_record = Some(self.file.stream_position()?);
+        self.write_line(&serde_json::to_vec(&record).map_err(io::Error::from)?)?;
+        self.unsynced += 1;
+        Ok(())
+    }
+
+    // whether the fsync policy wants the appended records synced now
+    pub fn sync_due(&self) -> bool {
+        match self.sync {
+            SyncPolicy::Always => self.unsynced > 0,
+            SyncPolicy::Every(records) => self.unsynced >= records,
+            SyncPolicy::Never => false,
+        }
+    }
+
+    // Takes the last appended record back out, for a row the run stops at
+    // instead of getting past it. A recovery then resumes at that row rather
+    // than after it.
+    pub fn retract(&mut self) -> Result<(), WalError> {
+        if let Some(start) = self.last_record.take() {
+            self.file.set_len(start)?;
+            self.file.seek(SeekFrom::Start(start))?;
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), WalError> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // drops the log once the state it protects has been saved elsewhere
+    pub fn remove(self, path: &Path) -> Result<(), WalError> {
+        drop(self.file);
+        Ok(fs::remove_file(path)?)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<(), WalError> {
+        // a single write per record, so a crash tears at most the last one
+        let mut buffer = Vec::with_capacity(line.len() + 1);
+        buffer.extend_from_slice(line);
+        buffer.push(b'\n');
+        self.file.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, ClientId, Currency, Deposit, Dispute, TransactionId};
+    use rust_decimal::Decimal;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "payments_engine_{}_{}.wal",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn deposit(transaction_id: u32) -> Transaction {
+        Transaction::Deposit(Deposit::new(
+            TransactionId(transaction_id),
+            ClientId(1),
+            Amount(Decimal::TEN),
+            Currency::EUR,
+        ))
+    }
+
+    fn input() -> InputFile {
+        InputFile {
+            path: String::from("input.csv"),
+            len: 100,
+            hash: 1,
+        }
+    }
+
+    fn available(engine: &PaymentsEngine) -> Amount {
+        engine.client_list[&ClientId(1)]
+            .balance(Currency::EUR)
+            .available
+    }
+
+    // logs and applies the transactions like the app does, returning the
+    // engine that was "lost" in the crash
+    fn crashed_run(path: &Path, transactions: &[Transaction]) -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        let mut wal =
+            WriteAheadLog::create(path, &engine, &input(), SyncPolicy::Always).expect("wal error");
+        for (line, transaction) in (2..).zip(transactions) {
+            wal.append(line, transaction).expect("wal error");
+            let _ = engine.recv_tx(transaction.clone());
+        }
+        engine
+    }
+
+    #[test]
+    fn recover_replays_logged_transactions() {
+        let path = temp_path("replay");
+        let dispute = Transaction::Dispute(Dispute {
+            client_id: ClientId(1),
+            target_transaction_id: TransactionId(9),
+            amount: None,
+            currency: None,
+        });
+        let crashed = crashed_run(&path, &[deposit(1), dispute, deposit(2)]);
+
+        let mut engine = PaymentsEngine::new();
+        let (_, recovery) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Always).expect("wal error");
+
+        assert_eq!(
+            recovery,
+            Recovery {
+                input: input(),
+                last_line: Some(4),
+                replayed: 3,
+            }
+        );
+        assert_eq!(engine.client_list, crashed.client_list);
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn recover_drops_torn_record() {
+        let path = temp_path("torn");
+        crashed_run(&path, &[deposit(1)]);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("io error");
+        file.write_all(b"{\"line\":3,\"transac").expect("io error");
+
+        let mut engine = PaymentsEngine::new();
+        let (mut wal, recovery) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Never).expect("wal error");
+        assert_eq!(recovery.last_line, Some(2));
+        assert_eq!(available(&engine), Amount(Decimal::TEN));
+
+        // records appended after the recovery aren't glued to the torn one
+        wal.append(3, &deposit(2)).expect("wal error");
+        let mut engine = PaymentsEngine::new();
+        let (_, recovery) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Never).expect("wal error");
+        assert_eq!(recovery.last_line, Some(3));
+        assert_eq!(available(&engine), Amount(Decimal::from(20)));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn recover_after_retracted_record() {
+        let path = temp_path("retract");
+        crashed_run(&path, &[deposit(1)]);
+        let mut engine = PaymentsEngine::new();
+        let (mut wal, _) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Always).expect("wal error");
+        wal.append(3, &deposit(2)).expect("wal error");
+        wal.retract().expect("wal error");
+        wal.append(4, &deposit(3)).expect("wal error");
+
+        let mut engine = PaymentsEngine::new();
+        let (_, recovery) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Always).expect("wal error");
+
+        assert_eq!(recovery.last_line, Some(4));
+        assert_eq!(recovery.replayed, 2);
+        assert_eq!(available(&engine), Amount(Decimal::from(20)));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn input_file_fingerprint() {
+        let path = temp_path("input");
+        fs::write(&path, "type,client,tx,amount\n").expect("io error");
+        let input = InputFile::read(&path).expect("io error");
+        assert_eq!(input.len, 22);
+
+        fs::write(&path, "type,client,tx,amount\r").expect("io error");
+        let changed = InputFile::read(&path).expect("io error");
+        assert_eq!(changed.len, input.len);
+        assert_ne!(changed.hash, input.hash);
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn recover_skips_log_already_in_snapshot() {
+        let path = temp_path("stale");
+        let mut engine = crashed_run(&path, &[deposit(1), deposit(2)]);
+
+        let (_, recovery) =
+            WriteAheadLog::recover(&path, &mut engine, SyncPolicy::Always).expect("wal error");
+
+        assert_eq!(recovery.replayed, 0);
+        assert_eq!(recovery.last_line, Some(3));
+        assert_eq!(available(&engine), Amount(Decimal::from(20)));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn recover_needs_the_base_snapshot() {
+        let path = temp_path("missing");
+        let mut engine = PaymentsEngine::new();
+        engine.recv_tx(deposit(1)).expect("deposit amount error");
+        WriteAheadLog::create(&path, &engine, &input(), SyncPolicy::Always).expect("wal error");
+
+        let result = WriteAheadLog::recover(&path, &mut PaymentsEngine::new(), SyncPolicy::Always);
+
+        assert!(matches!(
+            result,
+            Err(WalError::MissingSnapshot { log: 1, engine: 0 })
+        ));
+        fs::remove_file(&path).expect("cleanup error");
+    }
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!(
+            "always".parse::<SyncPolicy>().ok(),
+            Some(SyncPolicy::Always)
+        );
+        assert_eq!("never".parse::<SyncPolicy>().ok(), Some(SyncPolicy::Never));
+        assert_eq!(
+            "100".parse::<SyncPolicy>().ok(),
+            Some(SyncPolicy::Every(100))
+        );
+        assert!("0".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+}